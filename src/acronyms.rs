@@ -0,0 +1,216 @@
+//! Acronyms declared in the data, written in full the first time they are used.
+//!
+//! ```toml
+//! [acronyms]
+//! DEM = "Digital Elevation Model"
+//! GNSS = {long = "Global Navigation Satellite System", short = "GNSS"}  # "short" is optional.
+//! ```
+//! `{{acr "DEM"}}` is written as "Digital Elevation Model (DEM)" the first time and "DEM" after
+//! that, and `{{acrlist}}` is replaced by a list of the acronyms that were used in the manuscript.
+use serde_json::Value as Json;
+use std::sync::{Arc, Mutex};
+
+/// The line that `{{acrlist}}` writes, which is replaced by the list when the whole manuscript is
+/// rendered. It is a comment, so it is harmless if it is left in place.
+const LIST_MARKER: &str = "%manus:acronym-list:";
+
+/// The acronyms that have been used so far in a manuscript, in order of their first use.
+///
+/// The uses of the line that is being rendered only count once the line is kept, since a line
+/// that fails to render is written as it is.
+#[derive(Clone, Default)]
+pub struct Usage(Arc<Mutex<Uses>>);
+
+/// The acronyms used in the kept lines and in the line that is being rendered.
+#[derive(Default)]
+struct Uses {
+    kept: Vec<String>,
+    line: Vec<String>,
+}
+
+impl Usage {
+    /// Register a use of an acronym in the line that is being rendered.
+    ///
+    /// # Returns
+    /// Whether it was the first use.
+    fn first_use(&self, id: &str) -> bool {
+        let mut uses = self.0.lock().unwrap();
+        match uses.kept.iter().chain(uses.line.iter()).any(|u| u == id) {
+            true => false,
+            false => {
+                uses.line.push(id.to_owned());
+                true
+            }
+        }
+    }
+
+    /// Keep the uses of the line that was rendered, or forget them if it was not.
+    ///
+    /// # Arguments
+    /// * `rendered`: Whether the line rendered and its output is used.
+    pub fn end_line(&self, rendered: bool) {
+        let mut uses = self.0.lock().unwrap();
+        let line = std::mem::take(&mut uses.line);
+        if rendered {
+            uses.kept.extend(line);
+        }
+    }
+
+    /// The acronyms that have been used in the kept lines, in order of their first use.
+    pub fn used(&self) -> Vec<String> {
+        self.0.lock().unwrap().kept.clone()
+    }
+}
+
+/// Find the long and short form of an acronym in the data.
+fn lookup(data: &Json, id: &str) -> Result<(String, String), String> {
+    match data.get("acronyms").and_then(|a| a.get(id)) {
+        Some(Json::String(long)) => Ok((long.to_owned(), id.to_owned())),
+        Some(Json::Object(obj)) => match obj.get("long").and_then(|l| l.as_str()) {
+            Some(long) => Ok((
+                long.to_owned(),
+                obj.get("short")
+                    .and_then(|s| s.as_str())
+                    .unwrap_or(id)
+                    .to_owned(),
+            )),
+            None => Err(format!("The acronym '{}' has no 'long' form.", id)),
+        },
+        Some(v) => Err(format!("Could not understand the acronym '{}': {}", id, v)),
+        None => Err(format!("'{}' not found in the 'acronyms' table.", id)),
+    }
+}
+
+/// Helper to write an acronym, in full the first time it is used in the manuscript.
+///
+/// Given the data:
+/// ```
+/// {
+///     "acronyms": {"DEM": "Digital Elevation Model"}
+/// }
+/// ```
+/// the helper "{{acr "DEM"}}" will render as "`Digital Elevation Model (DEM)`" the first time and
+/// "`DEM`" after that. With `full=true`, it is always written in full.
+pub struct AcronymHelper(pub Usage);
+
+impl handlebars::HelperDef for AcronymHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        _: &'reg handlebars::Handlebars<'reg>,
+        context: &'rc handlebars::Context,
+        _: &mut handlebars::RenderContext<'reg, 'rc>,
+        out: &mut dyn handlebars::Output,
+    ) -> handlebars::HelperResult {
+        let id = h.param(0).and_then(|p| p.value().as_str()).ok_or_else(|| {
+            handlebars::RenderError::new("acr: The acronym is required, e.g. {{acr \"DEM\"}}")
+        })?;
+        let (long, short) = lookup(context.data(), id)
+            .map_err(|e| handlebars::RenderError::new(format!("acr: {}", e)))?;
+
+        let full = h
+            .hash_get("full")
+            .and_then(|f| f.value().as_bool())
+            .unwrap_or(false);
+        match self.0.first_use(id) || full {
+            true => out.write(&format!("{} ({})", long, short))?,
+            false => out.write(&short)?,
+        };
+        Ok(())
+    }
+}
+
+/// Helper to write a list of the acronyms used in the manuscript.
+///
+/// "{{acrlist}}" is replaced by a section (titled "Abbreviations" unless `title=` is given) with
+/// the used acronyms in alphabetical order, also if it comes before they are used.
+pub fn acrlist_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let title = h
+        .hash_get("title")
+        .and_then(|t| t.value().as_str())
+        .unwrap_or("Abbreviations");
+    out.write(&format!("{}{}", LIST_MARKER, title))?;
+    Ok(())
+}
+
+/// Replace the `{{acrlist}}` markers of a rendered manuscript with the list of used acronyms.
+///
+/// # Arguments
+/// * `lines`: The rendered lines of the manuscript.
+/// * `data`: The data that the acronyms are declared in.
+/// * `usage`: The acronyms that were used while rendering.
+pub fn insert_lists(lines: &mut [String], data: &Json, usage: &Usage) -> Result<(), String> {
+    let mut used = usage.used();
+    used.sort_by_key(|id| id.to_lowercase());
+
+    for line in lines.iter_mut() {
+        let title = match line.trim().strip_prefix(LIST_MARKER) {
+            Some(t) => t.to_owned(),
+            None => continue,
+        };
+        let mut list = vec![
+            format!("\\section*{{{}}}", title),
+            "\\begin{description}".to_owned(),
+        ];
+        for id in &used {
+            let (long, short) = lookup(data, id)?;
+            list.push(format!("\\item[{}] {}", short, long));
+        }
+        list.push("\\end{description}".to_owned());
+        *line = list.join("\n");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::templates::fill_data;
+
+    #[test]
+    fn test_acronyms() {
+        let data = serde_json::json!({
+            "acronyms": {
+                "DEM": "Digital Elevation Model",
+                "GNSS": {"long": "Global Navigation Satellite System"},
+                "abc": {"long": "A Better Calculation", "short": "aBc"},
+                "UAV": "Uncrewed Aerial Vehicle",
+            },
+        });
+        let lines = fill_data(
+            &[
+                "{{acrlist title=\"List of abbreviations\"}}".into(),
+                "A {{acr \"DEM\"}} from {{acr \"GNSS\"}}.".into(),
+                "The {{acr \"DEM\"}} and {{acr \"abc\"}}, again: {{acr \"abc\" full=true}}.".into(),
+                "{{acr \"missing\"}}".into(),
+                // The line fails after the acronym, so it is kept as it is and not counted.
+                "A {{acr \"UAV\"}} {{missing}}".into(),
+                "The {{acr \"UAV\"}}.".into(),
+            ],
+            &data,
+        )
+        .unwrap();
+
+        assert_eq!(
+            lines[0],
+            "\\section*{List of abbreviations}\n\\begin{description}\n\\item[aBc] A Better Calculation\n\\item[DEM] Digital Elevation Model\n\\item[GNSS] Global Navigation Satellite System\n\\item[UAV] Uncrewed Aerial Vehicle\n\\end{description}"
+        );
+        assert_eq!(
+            lines[1],
+            "A Digital Elevation Model (DEM) from Global Navigation Satellite System (GNSS)."
+        );
+        assert_eq!(
+            lines[2],
+            "The DEM and A Better Calculation (aBc), again: A Better Calculation (aBc)."
+        );
+        assert_eq!(lines[3], "{{acr \"missing\"}}");
+        assert_eq!(lines[4], "A {{acr \"UAV\"}} {{missing}}");
+        assert_eq!(lines[5], "The Uncrewed Aerial Vehicle (UAV).");
+    }
+}
@@ -0,0 +1,309 @@
+//! Read columnar (Parquet) data files, either as whole columns or as aggregated values.
+//!
+//! Large files do not have to fit in the data, since the rows are read one at a time and only the
+//! requested columns and aggregates are kept:
+//! ```toml
+//! [sources.model]
+//! parquet = "output.parquet"
+//! columns = ["site"]  # Optional. Columns to read as arrays.
+//! aggregates = {n_cells = "count(dh)", mean_dh = "mean(dh)"}  # Optional.
+//! ```
+use crate::expressions::{self, Expression, PathSegment};
+use crate::templates::float_to_json;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::schema::types::Type;
+use serde_json::Value as Json;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The most rows that are read without picking columns or aggregates.
+///
+/// Larger files would mostly fill the data with values that are never used.
+const MAX_ROWS_IN_FULL: i64 = 10_000;
+
+/// The functions that values can be aggregated with.
+const AGGREGATES: [&str; 5] = ["count", "sum", "mean", "min", "max"];
+
+/// An aggregate of one column, e.g. "mean(dh)", which is updated one value at a time.
+struct Aggregate {
+    name: String,
+    function: String,
+    column: String,
+    count: usize,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Aggregate {
+    /// Parse an aggregate from its spec, e.g. "mean(dh)".
+    fn parse(name: &str, spec: &str) -> Result<Aggregate, String> {
+        let invalid = || {
+            format!(
+                "Invalid aggregate '{}' for '{}'. Expected e.g. 'mean(column)' with one of: {}",
+                spec,
+                name,
+                AGGREGATES.join(", ")
+            )
+        };
+        let (function, column) = match expressions::parse(spec).map_err(|_| invalid())? {
+            Expression::Call(function, args) => match args.as_slice() {
+                [Expression::Path(path)] => match path.as_slice() {
+                    [PathSegment::Key(column)] => (function, column.to_owned()),
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        if !AGGREGATES.contains(&function.as_str()) {
+            return Err(invalid());
+        }
+
+        Ok(Aggregate {
+            name: name.to_owned(),
+            function,
+            column,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        })
+    }
+
+    /// Add a value to the aggregate. Missing (null) values are skipped.
+    fn add(&mut self, value: &Json) -> Result<(), String> {
+        if value.is_null() {
+            return Ok(());
+        }
+        self.count += 1;
+        if self.function != "count" {
+            let number = value.as_f64().ok_or_else(|| {
+                format!(
+                    "Cannot calculate the {} of '{}': {} is not a number.",
+                    self.function, self.column, value
+                )
+            })?;
+            self.sum += number;
+            self.min = self.min.min(number);
+            self.max = self.max.max(number);
+        }
+        Ok(())
+    }
+
+    /// The final value of the aggregate.
+    fn result(&self) -> Result<Json, String> {
+        if self.count == 0 && self.function != "count" {
+            return Err(format!(
+                "Cannot calculate the {} of '{}': It has no values.",
+                self.function, self.column
+            ));
+        }
+        Ok(match self.function.as_str() {
+            "count" => Json::from(self.count),
+            "sum" => float_to_json(self.sum),
+            "mean" => float_to_json(self.sum / self.count as f64),
+            "min" => float_to_json(self.min),
+            _ => float_to_json(self.max),
+        })
+    }
+}
+
+/// Read columns and aggregates from a Parquet file.
+///
+/// # Arguments
+/// * `path`: The path to the Parquet file.
+/// * `columns`: The columns to read as arrays. If None and there are no aggregates, all columns
+///   are read, as long as the file has at most `MAX_ROWS_IN_FULL` rows.
+/// * `aggregates`: The name and spec (e.g. "mean(dh)") of each aggregate.
+///
+/// # Returns
+/// An object with each column as an array and each aggregate as a value.
+pub fn read_parquet(
+    path: &Path,
+    columns: Option<&[String]>,
+    aggregates: &serde_json::Map<String, Json>,
+) -> Result<Json, String> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+    let reader = SerializedFileReader::new(file)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let schema = reader.metadata().file_metadata().schema();
+    let n_rows = reader.metadata().file_metadata().num_rows();
+
+    let mut aggregates = aggregates
+        .iter()
+        .map(|(name, spec)| match spec.as_str() {
+            Some(s) => Aggregate::parse(name, s),
+            None => Err(format!("The aggregate '{}' is not a string.", name)),
+        })
+        .collect::<Result<Vec<Aggregate>, String>>()?;
+
+    let columns: Vec<String> = match columns {
+        Some(c) => c.to_vec(),
+        None if aggregates.is_empty() && n_rows > MAX_ROWS_IN_FULL => {
+            return Err(format!(
+                "{} has {} rows, which is too many to read in full (max {}). Pick columns or \
+                 aggregates in a [sources] table instead.",
+                path.display(),
+                n_rows,
+                MAX_ROWS_IN_FULL
+            ))
+        }
+        None if aggregates.is_empty() => schema
+            .get_fields()
+            .iter()
+            .map(|f| f.name().to_owned())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Only read the columns that are needed.
+    let mut fields: Vec<Arc<Type>> = Vec::new();
+    let needed = columns.iter().chain(aggregates.iter().map(|a| &a.column));
+    for name in needed {
+        if fields.iter().any(|f| f.name() == name) {
+            continue;
+        }
+        match schema.get_fields().iter().find(|f| f.name() == name) {
+            Some(f) => fields.push(f.clone()),
+            None => return Err(format!("Column '{}' not found in the Parquet file.", name)),
+        }
+    }
+    let projection = Type::group_type_builder(schema.name())
+        .with_fields(fields)
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut data = serde_json::Map::new();
+    for column in &columns {
+        data.insert(column.to_owned(), Json::Array(Vec::new()));
+    }
+
+    let rows = reader
+        .get_row_iter(Some(projection))
+        .map_err(|e| e.to_string())?;
+    for row in rows {
+        let row = row.map_err(|e| e.to_string())?;
+        for (name, field) in row.get_column_iter() {
+            let value = field.to_json_value();
+            for aggregate in aggregates.iter_mut().filter(|a| &a.column == name) {
+                aggregate.add(&value)?;
+            }
+            if let Some(Json::Array(column)) = data.get_mut(name) {
+                column.push(value);
+            }
+        }
+    }
+
+    for aggregate in &aggregates {
+        data.insert(aggregate.name.to_owned(), aggregate.result()?);
+    }
+    Ok(Json::Object(data))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType};
+    use parquet::file::writer::SerializedFileWriter;
+
+    #[test]
+    fn test_read_parquet() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("model.parquet");
+
+        // Write a small file with a text and a number column.
+        let schema = Arc::new(
+            parquet::schema::parser::parse_message_type(
+                "message model { REQUIRED BYTE_ARRAY site (UTF8); REQUIRED DOUBLE dh; }",
+            )
+            .unwrap(),
+        );
+        let file = std::fs::File::create(&path).unwrap();
+        let mut writer = SerializedFileWriter::new(file, schema, Default::default()).unwrap();
+        let mut row_group = writer.next_row_group().unwrap();
+        let sites: Vec<ByteArray> = vec!["A".into(), "B".into(), "C".into()];
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column
+            .typed::<ByteArrayType>()
+            .write_batch(&sites, None, None)
+            .unwrap();
+        column.close().unwrap();
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column
+            .typed::<DoubleType>()
+            .write_batch(&[1.0, 2.5, -0.5], None, None)
+            .unwrap();
+        column.close().unwrap();
+        row_group.close().unwrap();
+        writer.close().unwrap();
+
+        let all = read_parquet(&path, None, &serde_json::Map::new()).unwrap();
+        assert_eq!(
+            all,
+            serde_json::json!({"site": ["A", "B", "C"], "dh": [1.0, 2.5, -0.5]})
+        );
+
+        let aggregates = serde_json::json!({
+            "n": "count(dh)",
+            "mean_dh": "mean(dh)",
+            "lowest": "min(dh)",
+            "total": "sum(dh)",
+        });
+        let data = read_parquet(
+            &path,
+            Some(&["site".to_owned()]),
+            aggregates.as_object().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            data,
+            serde_json::json!({
+                "site": ["A", "B", "C"], "n": 3, "mean_dh": 1, "lowest": -0.5, "total": 3
+            })
+        );
+
+        for spec in &[
+            "median(dh)",
+            "mean(dh, 2)",
+            "dh",
+            "mean(missing)",
+            "sum(site)",
+        ] {
+            let aggregates = serde_json::json!({ "x": spec });
+            assert!(
+                read_parquet(&path, Some(&[]), aggregates.as_object().unwrap()).is_err(),
+                "{}",
+                spec
+            );
+        }
+
+        // Large files can only be read through columns or aggregates.
+        let large_path = temp_dir.path().join("large.parquet");
+        let schema = Arc::new(
+            parquet::schema::parser::parse_message_type("message large { REQUIRED DOUBLE dh; }")
+                .unwrap(),
+        );
+        let file = std::fs::File::create(&large_path).unwrap();
+        let mut writer = SerializedFileWriter::new(file, schema, Default::default()).unwrap();
+        let mut row_group = writer.next_row_group().unwrap();
+        let mut column = row_group.next_column().unwrap().unwrap();
+        column
+            .typed::<DoubleType>()
+            .write_batch(&vec![0.5; MAX_ROWS_IN_FULL as usize + 1], None, None)
+            .unwrap();
+        column.close().unwrap();
+        row_group.close().unwrap();
+        writer.close().unwrap();
+
+        let error = read_parquet(&large_path, None, &serde_json::Map::new()).unwrap_err();
+        assert!(error.contains("too many"), "{}", error);
+        let aggregates = serde_json::json!({"mean_dh": "mean(dh)"});
+        assert_eq!(
+            read_parquet(&large_path, None, aggregates.as_object().unwrap()).unwrap(),
+            serde_json::json!({"mean_dh": 0.5})
+        );
+    }
+}
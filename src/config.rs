@@ -0,0 +1,215 @@
+//! The project configuration in `manus.toml`, e.g. the word limits of a journal and profiles:
+//! ```toml
+//! [limits]
+//! abstract = 250
+//! main = 6000
+//!
+//! [profiles.submission]
+//! strip_comments = true
+//!
+//! [sources.stats]
+//! cmd = "python stats.py"
+//! ```
+use serde_json::Value as Json;
+use std::path::{Path, PathBuf};
+
+/// The name of the configuration file.
+pub const CONFIG_FILENAME: &str = "manus.toml";
+
+/// Find the configuration file of a manuscript.
+///
+/// # Arguments
+/// * `input_str`: The path to the main tex file, or "-" if it is read from stdin.
+///
+/// # Returns
+/// The path to the "manus.toml" next to the tex file (or in the working directory), if it exists.
+pub fn find_config(input_str: &str) -> Option<PathBuf> {
+    let directory = match input_str.trim() {
+        "-" => Path::new(""),
+        path => Path::new(path).parent().unwrap_or_else(|| Path::new("")),
+    };
+    let candidates = [
+        directory.join(CONFIG_FILENAME),
+        PathBuf::from(CONFIG_FILENAME),
+    ];
+
+    candidates.iter().find(|path| path.is_file()).cloned()
+}
+
+/// Read the configuration of a manuscript.
+///
+/// # Arguments
+/// * `config_path`: Optional. The path to the configuration file. If None, it is looked for with
+///   `find_config`.
+/// * `input_str`: The path to the main tex file, or "-" if it is read from stdin.
+///
+/// # Returns
+/// The configuration, or an empty table if there is no configuration file.
+pub fn read_config(config_path: Option<&str>, input_str: &str) -> Result<Json, String> {
+    let path = match config_path {
+        Some(p) => PathBuf::from(p),
+        None => match find_config(input_str) {
+            Some(p) => p,
+            None => return Ok(serde_json::json!({})),
+        },
+    };
+    crate::io::read_data(&path)
+        .map_err(|e| format!("Could not read the configuration {}: {}", path.display(), e))
+}
+
+/// Run the sources declared in the configuration, like those of a data file.
+///
+/// The commands are run in the directory of the configuration file, and their output is cached
+/// there.
+///
+/// # Arguments
+/// * `input_str`: The path to the main tex file, or "-" if it is read from stdin.
+///
+/// # Returns
+/// The output of each source under its name, or None if there are no sources to run.
+pub fn read_sources(input_str: &str) -> Result<Option<Json>, String> {
+    let path = match find_config(input_str) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let sources = match read_config(path.to_str(), input_str)?.get("sources") {
+        Some(s) => s.clone(),
+        None => return Ok(None),
+    };
+    let mut data = serde_json::json!({ "sources": sources });
+    crate::sources::resolve_sources(&mut data, path.parent().unwrap_or_else(|| Path::new("")))?;
+
+    Ok(Some(data))
+}
+
+/// Options that are set together with `--profile`.
+#[derive(Debug, PartialEq)]
+pub struct Profile {
+    /// Remove comments from the output.
+    pub strip_comments: bool,
+    /// Remove `\todo{}` notes from the output.
+    pub strip_todos: bool,
+    /// Whether the output may contain `\todo{}` notes.
+    pub allow_todos: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            strip_comments: false,
+            strip_todos: false,
+            allow_todos: true,
+        }
+    }
+}
+
+/// Read a profile from the "profiles" table of the configuration.
+///
+/// The "submission" profile is built in: It strips comments and refuses any remaining todos. Its
+/// options can be changed in the configuration like those of any other profile.
+///
+/// # Arguments
+/// * `name`: Optional. The name of the profile. If None, the default options are used.
+/// * `config`: The configuration, from `read_config`.
+///
+/// # Returns
+/// The options of the profile, or an error if the profile does not exist or is invalid.
+pub fn read_profile(name: Option<&str>, config: &Json) -> Result<Profile, String> {
+    let name = match name {
+        Some(n) => n,
+        None => return Ok(Profile::default()),
+    };
+    let mut profile = match name {
+        "submission" => Profile {
+            strip_comments: true,
+            strip_todos: false,
+            allow_todos: false,
+        },
+        _ => Profile::default(),
+    };
+
+    let options = match config.get("profiles").and_then(|p| p.get(name)) {
+        Some(Json::Object(o)) => o,
+        Some(o) => {
+            return Err(format!(
+                "Could not understand the profile '{}': {}",
+                name, o
+            ))
+        }
+        None if name == "submission" => return Ok(profile),
+        None => {
+            return Err(format!(
+                "Profile '{}' not found in {}",
+                name, CONFIG_FILENAME
+            ))
+        }
+    };
+    for (key, value) in options {
+        let value = value
+            .as_bool()
+            .ok_or_else(|| format!("Profile option '{}' has to be true or false.", key))?;
+        match key.as_str() {
+            "strip_comments" => profile.strip_comments = value,
+            "strip_todos" => profile.strip_todos = value,
+            "allow_todos" => profile.allow_todos = value,
+            _ => {
+                return Err(format!(
+                "Unknown profile option '{}'. Choices: [strip_comments, strip_todos, allow_todos]",
+                key
+            ))
+            }
+        };
+    }
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_read_config() {
+        let config = read_config(None, "tests/data/case7/main.tex").unwrap();
+        assert_eq!(config["limits"]["abstract"], 5);
+
+        let config = read_config(Some("tests/data/case7/journal.toml"), "-").unwrap();
+        assert_eq!(config["limits"]["abstract"], 250);
+
+        // Without a configuration file, the configuration is empty.
+        assert_eq!(
+            read_config(None, "tests/data/case1/main.tex").unwrap(),
+            serde_json::json!({})
+        );
+        assert!(read_config(Some("missing.toml"), "-").is_err());
+
+        let config = serde_json::json!({"profiles": {"review": {"strip_todos": true}}});
+        assert!(read_profile(None, &config).unwrap().allow_todos);
+        assert!(
+            !read_profile(Some("submission"), &config)
+                .unwrap()
+                .allow_todos
+        );
+        assert!(read_profile(Some("review"), &config).unwrap().strip_todos);
+        assert!(read_profile(Some("missing"), &config).is_err());
+    }
+
+    #[test]
+    fn test_read_sources() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("main.tex");
+        assert_eq!(read_sources(input.to_str().unwrap()), Ok(None));
+
+        // The commands are run next to the configuration.
+        std::fs::write(temp_dir.path().join("values.json"), r#"{"mean": 1.5}"#).unwrap();
+        std::fs::write(
+            temp_dir.path().join(CONFIG_FILENAME),
+            "[sources.stats]\ncmd = \"cat values.json\"",
+        )
+        .unwrap();
+        assert_eq!(
+            read_sources(input.to_str().unwrap()),
+            Ok(Some(serde_json::json!({"stats": {"mean": 1.5}})))
+        );
+    }
+}
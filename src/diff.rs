@@ -0,0 +1,503 @@
+//! Compare rendered manuscripts and data files.
+use crate::io::SourceLine;
+use crate::templates;
+use serde_json::Value as Json;
+use std::collections::HashMap;
+
+/// One step in a diff between two sequences.
+#[derive(Debug, PartialEq)]
+pub enum Change<T> {
+    /// The item exists in both sequences.
+    Equal(T),
+    /// The item only exists in the old sequence.
+    Delete(T),
+    /// The item only exists in the new sequence.
+    Insert(T),
+}
+
+/// Find the differences between two sequences using their longest common subsequence.
+///
+/// # Arguments
+/// * `old`: The old sequence.
+/// * `new`: The new sequence.
+///
+/// # Returns
+/// The changes needed to go from `old` to `new`, in order.
+///
+/// # Examples
+/// ```
+/// assert_eq!(
+///     diff(&["a", "b"], &["a", "c"]),
+///     vec![Change::Equal(&"a"), Change::Delete(&"b"), Change::Insert(&"c")]
+/// );
+/// ```
+pub fn diff<'a, T: PartialEq>(old: &'a [T], new: &'a [T]) -> Vec<Change<&'a T>> {
+    // Common prefixes and suffixes are cheap to find, and are usually most of a revision.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..(old.len() - suffix)];
+    let b = &new[prefix..(new.len() - suffix)];
+
+    // Fill a table of the longest common subsequence lengths from each (i, j) to the end.
+    let width = b.len() + 1;
+    let mut table = vec![0_usize; (a.len() + 1) * width];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            table[i * width + j] = match a[i] == b[j] {
+                true => table[(i + 1) * width + j + 1] + 1,
+                false => table[(i + 1) * width + j].max(table[i * width + j + 1]),
+            };
+        }
+    }
+
+    let mut changes: Vec<Change<&T>> = old[..prefix].iter().map(Change::Equal).collect();
+
+    // Walk through the table and pick the longest path.
+    let (mut i, mut j) = (0, 0);
+    while (i < a.len()) & (j < b.len()) {
+        if a[i] == b[j] {
+            changes.push(Change::Equal(&a[i]));
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * width + j] >= table[i * width + j + 1] {
+            changes.push(Change::Delete(&a[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Insert(&b[j]));
+            j += 1;
+        }
+    }
+    changes.extend(a[i..].iter().map(Change::Delete));
+    changes.extend(b[j..].iter().map(Change::Insert));
+    changes.extend(old[(old.len() - suffix)..].iter().map(Change::Equal));
+
+    changes
+}
+
+/// Split a text into words and the whitespace between them.
+///
+/// Inline math ("$a + b$") is kept as one word so it is never split by markup.
+///
+/// # Examples
+/// ```
+/// assert_eq!(split_words("a  $b + c$"), vec!["a", "  ", "$b + c$"]);
+/// ```
+pub fn split_words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut in_math = false;
+    let mut previous = '\0';
+
+    for c in text.chars() {
+        // Start a new word when switching between whitespace and non-whitespace.
+        let is_boundary = match word.chars().last() {
+            Some(last) => !in_math & (c.is_whitespace() != last.is_whitespace()),
+            None => false,
+        };
+        if is_boundary {
+            words.push(std::mem::take(&mut word));
+        }
+        if (c == '$') & (previous != '\\') {
+            in_math = !in_math;
+        }
+        word.push(c);
+        previous = c;
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Mark up the word-level differences between two texts with `\DIFdel{}` and `\DIFadd{}`.
+///
+/// The markup follows the conventions of `latexdiff`, so the commands from `PREAMBLE` (or a
+/// `latexdiff` preamble) are needed to render it.
+///
+/// # Examples
+/// ```
+/// assert_eq!(markup_words("It is 2 m", "It is 3 m"), r"It is \DIFdel{2}\DIFadd{3} m");
+/// ```
+pub fn markup_words(old: &str, new: &str) -> String {
+    let old_words = split_words(old);
+    let new_words = split_words(new);
+
+    let mut output = String::new();
+    let mut deleted = String::new();
+    let mut added = String::new();
+
+    // Write the buffered deletions and additions (if any) to the output.
+    let flush = |output: &mut String, deleted: &mut String, added: &mut String| {
+        if !deleted.trim().is_empty() {
+            *output += &format!("\\DIFdel{{{}}}", deleted);
+        }
+        if !added.trim().is_empty() {
+            *output += &format!("\\DIFadd{{{}}}", added);
+        }
+        deleted.clear();
+        added.clear();
+    };
+
+    for change in diff(&old_words, &new_words) {
+        match change {
+            Change::Equal(word) => {
+                flush(&mut output, &mut deleted, &mut added);
+                output += word;
+            }
+            Change::Delete(word) => deleted += word,
+            Change::Insert(word) => added += word,
+        }
+    }
+    flush(&mut output, &mut deleted, &mut added);
+
+    output
+}
+
+/// Definitions of the `latexdiff` markup commands (underline style).
+pub const PREAMBLE: &str = r"\usepackage[normalem]{ulem}
+\usepackage{xcolor}
+\providecommand{\DIFadd}[1]{{\protect\color{blue}\uwave{#1}}}
+\providecommand{\DIFdel}[1]{{\protect\color{red}\sout{#1}}}";
+
+/// Insert the markup definitions before `\begin{document}`.
+///
+/// If no `\begin{document}` is found, the definitions are put at the top.
+pub fn add_preamble(lines: &mut Vec<String>) {
+    let index = lines
+        .iter()
+        .position(|line| line.contains(r"\begin{document}"))
+        .unwrap_or(0);
+
+    for (i, line) in PREAMBLE.lines().enumerate() {
+        lines.insert(index + i, line.to_owned());
+    }
+}
+
+/// Check if a line is structural (an environment delimiter or a sectioning command) rather than
+/// text, in which case it should not be wrapped in markup.
+fn is_structural(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty()
+        | trimmed.starts_with('%')
+        | trimmed.starts_with(r"\begin{")
+        | trimmed.starts_with(r"\end{")
+        | trimmed.starts_with(r"\section")
+        | trimmed.starts_with(r"\subsection")
+        | trimmed.starts_with(r"\label{")
+}
+
+/// Mark up the differences between two full tex documents.
+///
+/// Lines are compared first, and changed blocks of lines are then compared word by word.
+/// The preamble is taken from the new document, with the markup definitions added to it.
+///
+/// # Arguments
+/// * `old`: The lines of the old document.
+/// * `new`: The lines of the new document.
+///
+/// # Returns
+/// The lines of a document with `latexdiff`-style markup.
+pub fn markup_documents(old: &[String], new: &[String]) -> Vec<String> {
+    let body_start = |lines: &[String]| {
+        lines
+            .iter()
+            .position(|line| line.contains(r"\begin{document}"))
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    };
+    let old_start = body_start(old);
+    let new_start = body_start(new);
+
+    let mut output: Vec<String> = new[..new_start].to_vec();
+    add_preamble(&mut output);
+
+    let mut deleted: Vec<&String> = Vec::new();
+    let mut added: Vec<&String> = Vec::new();
+
+    // Write the buffered block of deleted and added lines (if any) to the output.
+    let flush = |output: &mut Vec<String>, deleted: &mut Vec<&String>, added: &mut Vec<&String>| {
+        // If the same number of text lines were changed, they are most likely edits of each other.
+        let paired = (deleted.len() == added.len())
+            & !deleted.iter().chain(added.iter()).any(|l| is_structural(l));
+        if paired {
+            for (old_line, new_line) in deleted.iter().zip(added.iter()) {
+                output.push(markup_words(old_line, new_line));
+            }
+        } else {
+            for line in deleted.iter().filter(|l| !is_structural(l)) {
+                output.push(format!("\\DIFdel{{{}}}", line));
+            }
+            for line in added.iter() {
+                match is_structural(line) {
+                    true => output.push(line.to_string()),
+                    false => output.push(format!("\\DIFadd{{{}}}", line)),
+                }
+            }
+        }
+        deleted.clear();
+        added.clear();
+    };
+
+    for change in diff(&old[old_start..], &new[new_start..]) {
+        match change {
+            Change::Equal(line) => {
+                flush(&mut output, &mut deleted, &mut added);
+                output.push(line.to_owned());
+            }
+            Change::Delete(line) => deleted.push(line),
+            Change::Insert(line) => added.push(line),
+        }
+    }
+    flush(&mut output, &mut deleted, &mut added);
+
+    output
+}
+
+/// Flatten a json into dotted key paths and their values.
+///
+/// Arrays are treated as values, as they are usually referred to as a whole.
+fn flatten(data: &Json, prefix: &str, output: &mut Vec<(String, Json)>) {
+    match data {
+        Json::Object(obj) => {
+            for (key, val) in obj {
+                let path = match prefix.is_empty() {
+                    true => key.to_owned(),
+                    false => format!("{}.{}", prefix, key),
+                };
+                flatten(val, &path, output);
+            }
+        }
+        v => output.push((prefix.to_owned(), v.to_owned())),
+    }
+}
+
+/// Find the keys that differ between two (evaluated) data files.
+///
+/// # Returns
+/// A vector of (key path, old value, new value) for every key that was changed, added or removed.
+pub fn changed_keys(old: &Json, new: &Json) -> Vec<(String, Option<Json>, Option<Json>)> {
+    let mut old_values: Vec<(String, Json)> = Vec::new();
+    let mut new_values: Vec<(String, Json)> = Vec::new();
+    flatten(old, "", &mut old_values);
+    flatten(new, "", &mut new_values);
+
+    let old_map: HashMap<&str, &Json> = old_values.iter().map(|(k, v)| (k.as_str(), v)).collect();
+    let new_map: HashMap<&str, &Json> = new_values.iter().map(|(k, v)| (k.as_str(), v)).collect();
+
+    let mut changes: Vec<(String, Option<Json>, Option<Json>)> = Vec::new();
+    for (key, value) in &old_values {
+        match new_map.get(key.as_str()) {
+            Some(v) if *v == value => (),
+            new_value => changes.push((
+                key.to_owned(),
+                Some(value.to_owned()),
+                new_value.map(|v| (*v).to_owned()),
+            )),
+        }
+    }
+    for (key, value) in &new_values {
+        if !old_map.contains_key(key.as_str()) {
+            changes.push((key.to_owned(), None, Some(value.to_owned())));
+        }
+    }
+
+    changes
+}
+
+/// Check if a line refers to a key path, without matching parts of longer keys.
+///
+/// # Examples
+/// ```
+/// assert!(refers_to_key("{{round age}}", "age"));
+/// assert!(!refers_to_key("{{round shrinkage}}", "age"));
+/// ```
+fn refers_to_key(line: &str, key: &str) -> bool {
+    let is_key_char = |c: char| c.is_alphanumeric() | (c == '_') | (c == '.');
+
+    line.match_indices(key).any(|(i, _)| {
+        let before = line[..i].chars().last();
+        let after = line[(i + key.len())..].chars().next();
+        !before.is_some_and(is_key_char) & !after.is_some_and(is_key_char)
+    })
+}
+
+/// Render a manuscript with two data files and show the lines that differ.
+///
+/// # Arguments
+/// * `lines`: The merged manuscript lines and their sources.
+/// * `old_data`: The old data.
+/// * `new_data`: The new data.
+/// * `format`: "text" for a line-by-line report, or "tex" for the full manuscript with
+///   `latexdiff`-style markup.
+///
+/// # Returns
+/// The report, or an error if rendering failed.
+pub fn diff_data(
+    lines: &[SourceLine],
+    old_data: &Json,
+    new_data: &Json,
+    format: &str,
+) -> Result<String, String> {
+    let texts: Vec<String> = lines.iter().map(|l| l.text.to_owned()).collect();
+
+    let old_lines = templates::fill_data(&texts, old_data)?;
+    let new_lines = templates::fill_data(&texts, new_data)?;
+
+    let changes = changed_keys(
+        &templates::evaluate_all_expressions(old_data)?,
+        &templates::evaluate_all_expressions(new_data)?,
+    );
+
+    // Find the changed keys that a template line refers to.
+    let keys_in_line = |line: &str| -> Vec<String> {
+        changes
+            .iter()
+            .filter(|(key, _, _)| refers_to_key(line, key))
+            .map(|(key, old, new)| {
+                let show = |v: &Option<Json>| match v {
+                    Some(v) => v.to_string(),
+                    None => "(none)".into(),
+                };
+                format!("{}: {} -> {}", key, show(old), show(new))
+            })
+            .collect()
+    };
+
+    match format {
+        "text" => {
+            let mut output = String::new();
+            for (i, line) in lines.iter().enumerate() {
+                if old_lines[i] == new_lines[i] {
+                    continue;
+                }
+                output += &format!("{}:{}\n", line.path.display(), line.line_no);
+                for key in keys_in_line(&line.text) {
+                    output += &format!("  # {}\n", key);
+                }
+                output += &format!("- {}\n+ {}\n", old_lines[i], new_lines[i]);
+            }
+            Ok(output)
+        }
+        "tex" => {
+            let mut output: Vec<String> = Vec::new();
+            for (i, line) in lines.iter().enumerate() {
+                if old_lines[i] == new_lines[i] {
+                    output.push(new_lines[i].to_owned());
+                    continue;
+                }
+                let mut marked = markup_words(&old_lines[i], &new_lines[i]);
+                let keys = keys_in_line(&line.text);
+                if !keys.is_empty() {
+                    marked += &format!(" % Changed: {}", keys.join(", "));
+                }
+                output.push(marked);
+            }
+            add_preamble(&mut output);
+            Ok(output.join("\n"))
+        }
+        s => Err(format!("Unknown diff format: {}. Choices: [text, tex]", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            diff(&["a", "b", "c", "d"], &["a", "c", "e", "d"]),
+            vec![
+                Change::Equal(&"a"),
+                Change::Delete(&"b"),
+                Change::Equal(&"c"),
+                Change::Insert(&"e"),
+                Change::Equal(&"d")
+            ]
+        );
+
+        assert_eq!(
+            split_words("The value is $1 + 2$ m."),
+            vec!["The", " ", "value", " ", "is", " ", "$1 + 2$", " ", "m."]
+        );
+        assert_eq!(
+            markup_words("It is 2 m and 5 s.", "It is 3 m and 5 s."),
+            r"It is \DIFdel{2}\DIFadd{3} m and 5 s."
+        );
+
+        let mut lines: Vec<String> = vec![r"\begin{document}".into(), "Hello".into()];
+        add_preamble(&mut lines);
+        assert!(lines[0].contains("ulem"));
+        assert_eq!(lines.last().unwrap(), "Hello");
+    }
+
+    #[test]
+    fn test_markup_documents() {
+        let to_lines = |text: &str| -> Vec<String> { text.lines().map(|l| l.to_owned()).collect() };
+
+        let old = to_lines(
+            "\\documentclass{article}\n\\begin{document}\nThe value is 1.\n\nRemoved line.\nAnother removed line.\n\\end{document}",
+        );
+        let new = to_lines(
+            "\\documentclass{article}\n\\usepackage{amsmath}\n\\begin{document}\nThe value is 2.\n\n\\section{New}\nNew line.\n\\end{document}",
+        );
+
+        let marked = markup_documents(&old, &new);
+
+        // The preamble is taken from the new document, and the markup commands are added.
+        assert_eq!(marked[1], "\\usepackage{amsmath}");
+        assert!(marked
+            .iter()
+            .any(|l| l.contains("\\providecommand{\\DIFadd}")));
+
+        assert!(marked.contains(&"The value is \\DIFdel{1.}\\DIFadd{2.}".to_string()));
+        assert!(marked.contains(&"\\DIFdel{Removed line.}".to_string()));
+        assert!(marked.contains(&"\\section{New}".to_string()));
+        assert!(marked.contains(&"\\DIFadd{New line.}".to_string()));
+        assert_eq!(marked.last().unwrap(), "\\end{document}");
+    }
+
+    #[test]
+    fn test_diff_data() {
+        let lines = SourceLine::numbered(
+            "main.tex",
+            &[
+                "The glacier is {{length}} km long.",
+                "It is {{age}} years old.",
+                "It has shrunk by {{round shrinkage}} km.",
+            ],
+        );
+
+        let old_data = serde_json::json!({"length": 10, "original_length": 12, "age": 1000, "shrinkage": "expr: original_length - length"});
+        let new_data = serde_json::json!({"length": 9, "original_length": 12, "age": 1000, "shrinkage": "expr: original_length - length"});
+
+        // The shrinkage expression depends on the length, so it changes as well.
+        let changes = changed_keys(
+            &templates::evaluate_all_expressions(&old_data).unwrap(),
+            &templates::evaluate_all_expressions(&new_data).unwrap(),
+        );
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].0, "length");
+        assert_eq!(changes[1].0, "shrinkage");
+
+        assert!(refers_to_key("{{round age}}", "age"));
+        assert!(!refers_to_key("{{round shrinkage}}", "age"));
+
+        let report = diff_data(&lines, &old_data, &new_data, "text").unwrap();
+        assert!(report.contains("main.tex:1\n  # length: 10 -> 9\n"));
+        assert!(report
+            .contains("  # shrinkage: 2 -> 3\n- It has shrunk by 2 km.\n+ It has shrunk by 3 km."));
+        assert!(!report.contains("years old"));
+
+        let tex = diff_data(&lines, &old_data, &new_data, "tex").unwrap();
+        assert!(tex.contains(r"The glacier is \DIFdel{10}\DIFadd{9} km long."));
+        assert!(tex.contains("It is 1000 years old."));
+    }
+}
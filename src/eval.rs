@@ -0,0 +1,152 @@
+//! Print the evaluated data of a data file, to debug "expr:" expressions without a manuscript.
+use crate::expressions::{get_by_path, refers_to};
+use crate::templates::{self, Explanation};
+use serde_json::Value as Json;
+
+/// Format a data tree (or a part of it) as JSON or TOML.
+///
+/// Strings, numbers and booleans are written as they are, so that single values are easy to use
+/// in scripts.
+///
+/// # Arguments
+/// * `value`: The value to format.
+/// * `key`: The dotted key path of the value. Used to name values that TOML cannot write alone.
+/// * `format`: The format to write. Choices: [json, toml].
+fn format_value(value: &Json, key: Option<&str>, format: &str) -> Result<String, String> {
+    match (value, format) {
+        (Json::String(s), _) => Ok(s.to_owned()),
+        (Json::Number(_), _) | (Json::Bool(_), _) => Ok(value.to_string()),
+        (_, "json") => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+        (_, "toml") => {
+            // Only tables can be written as a TOML document, so other values get a key.
+            let table = match value {
+                Json::Object(_) => value.clone(),
+                _ => serde_json::json!({ key.unwrap_or("value"): value }),
+            };
+            let toml_value = toml::Value::try_from(table)
+                .map_err(|e| format!("Could not convert the data to TOML: {}", e))?;
+            toml::to_string(&toml_value)
+                .map(|s| s.trim_end().to_owned())
+                .map_err(|e| format!("Could not convert the data to TOML: {}", e))
+        }
+        (_, f) => Err(format!("Unknown format: {}. Choices: [json, toml]", f)),
+    }
+}
+
+/// Format the explanations of how expressions were evaluated.
+///
+/// # Examples
+/// ```text
+/// volume = area * thickness
+///   area = "12.5 km^2"
+///   thickness = {"unit":"m","value":20}
+///   => "250000000 m^3"
+/// ```
+fn format_explanations(explanations: &[&Explanation]) -> String {
+    let mut blocks: Vec<String> = Vec::new();
+    for explanation in explanations {
+        let mut block = format!("{} = {}", explanation.key, explanation.expression);
+        if let Some(((_, value), steps)) = explanation.steps.split_last() {
+            for (part, part_value) in steps {
+                block += &format!("\n  {} = {}", part, part_value);
+            }
+            block += &format!("\n  => {}", value);
+        }
+        blocks.push(block);
+    }
+    blocks.join("\n\n")
+}
+
+/// Only keep the explanations that a key depends on, directly or through other expressions.
+fn explanations_for_key<'a>(explanations: &'a [Explanation], key: &str) -> Vec<&'a Explanation> {
+    let mut needed: Vec<String> = vec![key.to_owned()];
+    let mut kept: Vec<&Explanation> = Vec::new();
+
+    // Expressions are evaluated after their dependencies, so go through them backwards.
+    for explanation in explanations.iter().rev() {
+        if needed.iter().any(|path| refers_to(path, &explanation.key)) {
+            needed.extend(explanation.dependencies.iter().cloned());
+            kept.push(explanation);
+        }
+    }
+    kept.reverse();
+    kept
+}
+
+/// Evaluate all expressions in a data file and format the result.
+///
+/// # Arguments
+/// * `data`: The raw (unevaluated) data.
+/// * `key`: Optional. A dotted key path to only show one value of, e.g. "results.change".
+/// * `explain`: Show how each expression was evaluated instead of the values.
+/// * `format`: The format to write values in. Choices: [json, toml].
+///
+/// # Returns
+/// The formatted values or explanations, or an error if an expression could not be evaluated.
+pub fn evaluate_data(
+    data: &Json,
+    key: Option<&str>,
+    explain: bool,
+    format: &str,
+) -> Result<String, String> {
+    let (evaluated, explanations) = templates::explain_expressions(data)?;
+
+    let value = match key {
+        Some(k) => get_by_path(&evaluated, k).ok_or(format!("Key '{}' not found.", k))?,
+        None => &evaluated,
+    };
+
+    if explain {
+        let shown = match key {
+            Some(k) => explanations_for_key(&explanations, k),
+            None => explanations.iter().collect(),
+        };
+        return Ok(format_explanations(&shown));
+    }
+
+    format_value(value, key, format)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_evaluate_data() {
+        let data = serde_json::json!({
+            "a": 2,
+            "b": "expr: a * 3",
+            "c": "expr: b + 1",
+            "other": "expr: a - 1",
+            "name": "glacier",
+            "nested": {"values": [1, "expr: a"]},
+        });
+
+        assert_eq!(
+            evaluate_data(&data, Some("c"), false, "json"),
+            Ok("7".into())
+        );
+        assert_eq!(
+            evaluate_data(&data, Some("name"), false, "toml"),
+            Ok("glacier".into())
+        );
+        assert_eq!(
+            evaluate_data(&data, Some("nested.values"), false, "toml"),
+            Ok("\"nested.values\" = [1, 2]".into())
+        );
+        let json: Json =
+            serde_json::from_str(&evaluate_data(&data, None, false, "json").unwrap()).unwrap();
+        assert_eq!(json["nested"]["values"][1], 2);
+
+        // Only the chain of expressions that 'c' depends on is explained.
+        assert_eq!(
+            evaluate_data(&data, Some("c"), true, "json"),
+            Ok("b = a * 3\n  a = 2\n  => 6\n\nc = b + 1\n  b = 6\n  => 7".into())
+        );
+
+        assert!(evaluate_data(&data, Some("missing"), false, "json").is_err());
+        assert!(evaluate_data(&data, None, false, "yaml").is_err());
+        assert!(evaluate_data(&serde_json::json!({"x": "expr: y"}), None, false, "json").is_err());
+    }
+}
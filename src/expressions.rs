@@ -0,0 +1,914 @@
+//! Parse and evaluate the "expr:" expressions of data files.
+//!
+//! The grammar, from lowest to highest precedence:
+//! - Ternaries: `cond ? a : b`
+//! - Logic: `a || b`, `a && b`
+//! - Comparisons: `==`, `!=`, `<`, `<=`, `>`, `>=`
+//! - Sums: `a + b` (also string concatenation), `a - b`
+//! - Products: `a * b`, `a / b`, `a % b`
+//! - Unary minus and not: `-a`, `!a`
+//! - Exponents: `a ^ b` or `a ** b` (right-associative, so `-2 ^ 2` is -4)
+//! - Numbers (`1.2e-3`), strings (`"a"` or `'a'`), `true`/`false`, dotted key paths
+//!   (`results.change`, `samples[3].value`, `samples[1:3]`), function calls (`round(x, 2)`) and parentheses.
+use crate::functions;
+use crate::units::{self, Quantity};
+use serde_json::Value as Json;
+use std::fmt;
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// A number, string or boolean.
+    Literal(Json),
+    /// A key path in the data, e.g. "samples[1:3].value".
+    Path(Vec<PathSegment>),
+    /// An operator and its operand.
+    Unary(&'static str, Box<Expression>),
+    /// An operator and its left and right operands.
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+    /// A condition and the expressions to use if it is true or false.
+    Ternary(Box<Expression>, Box<Expression>, Box<Expression>),
+    /// A function name and its arguments.
+    Call(String, Vec<Expression>),
+}
+
+/// A function that is given each evaluated part of an expression and its value.
+pub type Recorder<'a> = dyn FnMut(&Expression, &Json) + 'a;
+
+/// A part of a key path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    /// An object key, e.g. ".value".
+    Key(String),
+    /// An array index, e.g. "[3]" or ".3". Negative indices count from the end.
+    Index(i64),
+    /// An array slice with an optional start and end, e.g. "[1:3]" or "[:-1]".
+    Slice(Option<i64>, Option<i64>),
+}
+
+/// An error from parsing an expression, with the (1-based) column that it occurred at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+/// The operators, with the longer ones first so that e.g. "**" is not read as two "*".
+const OPERATORS: [&str; 24] = [
+    "**", "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "^", "(", ")", "[", "]",
+    ",", ".", "?", ":", "<", ">", "!",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Json),
+    String(String),
+    Identifier(String),
+    Operator(&'static str),
+    End,
+}
+
+/// Split an expression into tokens and their (1-based) columns.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens: Vec<(Token, usize)> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let column = i + 1;
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            // Keys after a dot (like the "0" in "values.0") are integers, so decimals are only
+            // read for standalone numbers.
+            let after_dot = matches!(tokens.last(), Some((Token::Operator("."), _)));
+            if !after_dot {
+                if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                    i += 1;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                // Scientific notation, e.g. "1.2e-3". Without digits after it, the "e" is left.
+                if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                    let mut j = i + 1;
+                    if j < chars.len() && (chars[j] == '-' || chars[j] == '+') {
+                        j += 1;
+                    }
+                    if j < chars.len() && chars[j].is_ascii_digit() {
+                        i = j;
+                        while i < chars.len() && chars[i].is_ascii_digit() {
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = match text.parse::<i64>() {
+                Ok(n) => Json::from(n),
+                Err(_) => match text.parse::<f64>() {
+                    Ok(n) if n.is_finite() => Json::from(n),
+                    _ => {
+                        return Err(ParseError {
+                            column,
+                            message: format!("Invalid number '{}'", text),
+                        })
+                    }
+                },
+            };
+            tokens.push((Token::Number(number), column));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((Token::Identifier(chars[start..i].iter().collect()), column));
+        } else if c == '"' || c == '\'' {
+            let mut string = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(ParseError {
+                            column,
+                            message: "Unterminated string".into(),
+                        })
+                    }
+                    Some(&q) if q == c => break,
+                    Some('\\') if i + 1 < chars.len() => {
+                        string.push(chars[i + 1]);
+                        i += 2;
+                    }
+                    Some(&other) => {
+                        string.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            tokens.push((Token::String(string), column));
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    i += op.len();
+                    tokens.push((Token::Operator(op), column));
+                }
+                None => {
+                    return Err(ParseError {
+                        column,
+                        message: format!("Unexpected character '{}'", c),
+                    })
+                }
+            }
+        }
+    }
+    tokens.push((Token::End, chars.len() + 1));
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens of an expression.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].0
+    }
+
+    fn column(&self) -> usize {
+        self.tokens[self.position].1
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].0.clone();
+        if token != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    /// Consume the next token if it is one of the given operators.
+    fn accept(&mut self, operators: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Token::Operator(op) if operators.contains(op) => {
+                let op = *op;
+                self.position += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, ParseError> {
+        let found = match self.peek() {
+            Token::Number(n) => format!("'{}'", n),
+            Token::String(s) => format!("'\"{}\"'", s),
+            Token::Identifier(s) => format!("'{}'", s),
+            Token::Operator(op) => format!("'{}'", op),
+            Token::End => "the end".into(),
+        };
+        Err(ParseError {
+            column: self.column(),
+            message: format!("{}, found {}", message, found),
+        })
+    }
+
+    fn expect(&mut self, operator: &'static str) -> Result<(), ParseError> {
+        match self.accept(&[operator]) {
+            Some(_) => Ok(()),
+            None => self.error(&format!("Expected '{}'", operator)),
+        }
+    }
+
+    fn ternary(&mut self) -> Result<Expression, ParseError> {
+        let condition = self.binary(0)?;
+
+        if self.accept(&["?"]).is_none() {
+            return Ok(condition);
+        }
+        let if_true = self.ternary()?;
+        self.expect(":")?;
+        let if_false = self.ternary()?;
+
+        Ok(Expression::Ternary(
+            Box::new(condition),
+            Box::new(if_true),
+            Box::new(if_false),
+        ))
+    }
+
+    /// Parse left-associative binary operators, from the given precedence level and up.
+    fn binary(&mut self, level: usize) -> Result<Expression, ParseError> {
+        const LEVELS: [&[&str]; 5] = [
+            &["||"],
+            &["&&"],
+            &["==", "!=", "<", "<=", ">", ">="],
+            &["+", "-"],
+            &["*", "/", "%"],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.accept(LEVELS[level]) {
+            let right = self.binary(level + 1)?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, ParseError> {
+        match self.accept(&["-", "!"]) {
+            Some(op) => Ok(Expression::Unary(op, Box::new(self.unary()?))),
+            None => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<Expression, ParseError> {
+        let base = self.primary()?;
+
+        match self.accept(&["^", "**"]) {
+            // The exponent is parsed as a unary to allow e.g. "2 ^ -1" and "2 ^ 3 ^ 2".
+            Some(_) => Ok(Expression::Binary(
+                "^",
+                Box::new(base),
+                Box::new(self.unary()?),
+            )),
+            None => Ok(base),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, ParseError> {
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.next();
+                Ok(Expression::Literal(n))
+            }
+            Token::String(s) => {
+                self.next();
+                Ok(Expression::Literal(Json::String(s)))
+            }
+            Token::Operator("(") => {
+                self.next();
+                let inner = self.ternary()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Identifier(name) => {
+                self.next();
+                match name.as_str() {
+                    "true" => return Ok(Expression::Literal(Json::Bool(true))),
+                    "false" => return Ok(Expression::Literal(Json::Bool(false))),
+                    _ => (),
+                };
+                if self.accept(&["("]).is_some() {
+                    return self.call(name);
+                }
+                let mut segments = vec![PathSegment::Key(name)];
+                while let Some(op) = self.accept(&[".", "["]) {
+                    if op == "[" {
+                        segments.push(self.index()?);
+                        continue;
+                    }
+                    match self.peek().clone() {
+                        Token::Identifier(key) => segments.push(PathSegment::Key(key)),
+                        Token::Number(n) if n.is_u64() => {
+                            segments.push(PathSegment::Index(n.as_i64().unwrap()))
+                        }
+                        _ => return self.error("Expected a key after '.'"),
+                    };
+                    self.next();
+                }
+                Ok(Expression::Path(segments))
+            }
+            _ => self.error("Expected a value"),
+        }
+    }
+
+    /// Parse an optional (possibly negative) integer, e.g. in an index.
+    fn integer(&mut self) -> Result<Option<i64>, ParseError> {
+        let negative = self.accept(&["-"]).is_some();
+        match self.peek().clone() {
+            Token::Number(n) if n.is_i64() => {
+                self.next();
+                let n = n.as_i64().unwrap();
+                Ok(Some(if negative { -n } else { n }))
+            }
+            _ if negative => self.error("Expected an integer"),
+            _ => Ok(None),
+        }
+    }
+
+    /// Parse an index or a slice (after the opening bracket).
+    fn index(&mut self) -> Result<PathSegment, ParseError> {
+        let start = self.integer()?;
+
+        let segment = match (start, self.accept(&[":"])) {
+            (_, Some(_)) => PathSegment::Slice(start, self.integer()?),
+            (Some(i), None) => PathSegment::Index(i),
+            (None, None) => return self.error("Expected an index or a slice"),
+        };
+        self.expect("]")?;
+
+        Ok(segment)
+    }
+
+    /// Parse the arguments of a function call (after the opening parenthesis).
+    fn call(&mut self, name: String) -> Result<Expression, ParseError> {
+        let mut args: Vec<Expression> = Vec::new();
+
+        if self.accept(&[")"]).is_none() {
+            loop {
+                args.push(self.ternary()?);
+                if self.accept(&[")"]).is_some() {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(Expression::Call(name, args))
+    }
+}
+
+/// Parse an expression.
+///
+/// # Arguments
+/// * `source`: The expression, without the "expr:" prefix.
+///
+/// # Returns
+/// The parsed expression, or an error with the column where parsing failed.
+///
+/// # Examples
+/// ```
+/// let expression = parse("-results.change * 2").unwrap();
+///
+/// assert_eq!(expression.dependencies(), vec!["results.change".to_owned()]);
+/// ```
+pub fn parse(source: &str) -> Result<Expression, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let expression = parser.ternary()?;
+
+    match parser.peek() {
+        Token::End => Ok(expression),
+        _ => parser.error("Expected an operator or the end"),
+    }
+}
+
+/// Get a value from a json using a dotted key path ("results.change").
+///
+/// Numeric path components index arrays.
+pub fn get_by_path<'a>(data: &'a Json, path: &str) -> Option<&'a Json> {
+    let mut value = data;
+    for key in path.split('.') {
+        value = match value {
+            Json::Array(arr) => arr.get(key.parse::<usize>().ok()?)?,
+            v => v.get(key)?,
+        };
+    }
+    Some(value)
+}
+
+/// Split a key path into the dotted path that can be looked up directly and the rest.
+///
+/// Keys and positive indices are looked up directly, e.g. "samples[3].value" =>
+/// "samples.3.value", while everything from the first slice or negative index is left.
+fn lookup_path(segments: &[PathSegment]) -> (String, &[PathSegment]) {
+    let mut keys: Vec<String> = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            PathSegment::Key(key) => keys.push(key.to_owned()),
+            PathSegment::Index(index) if *index >= 0 => keys.push(index.to_string()),
+            _ => return (keys.join("."), &segments[i..]),
+        }
+    }
+    (keys.join("."), &[])
+}
+
+/// Convert a (possibly negative) index to a position in an array of a length.
+fn array_position(index: i64, length: usize) -> usize {
+    match index < 0 {
+        true => (length as i64 + index).max(0) as usize,
+        false => (index as usize).min(length),
+    }
+}
+
+/// Select a part of a value with the rest of a key path.
+///
+/// A key after a slice selects the key in every element, e.g. "samples[1:3].value".
+fn select(value: Json, segments: &[PathSegment]) -> Result<Json, String> {
+    let (segment, rest) = match segments.split_first() {
+        Some(s) => s,
+        None => return Ok(value),
+    };
+
+    let selected = match (segment, value) {
+        (PathSegment::Key(key), Json::Object(mut obj)) => obj
+            .remove(key)
+            .ok_or_else(|| format!("Key '{}' not found", key))?,
+        (PathSegment::Key(_), Json::Array(arr)) => Json::Array(
+            arr.into_iter()
+                .map(|element| select(element, &segments[..1]))
+                .collect::<Result<Vec<Json>, String>>()?,
+        ),
+        (PathSegment::Index(index), Json::Array(mut arr)) => {
+            let position = array_position(*index, arr.len());
+            if *index < -(arr.len() as i64) || position >= arr.len() {
+                return Err(format!(
+                    "Index {} is out of range for an array of length {}",
+                    index,
+                    arr.len()
+                ));
+            }
+            arr.swap_remove(position)
+        }
+        (PathSegment::Slice(start, end), Json::Array(arr)) => {
+            let length = arr.len();
+            let start = start.map_or(0, |s| array_position(s, length));
+            let end = end.map_or(length, |e| array_position(e, length));
+            Json::Array(
+                arr.into_iter()
+                    .skip(start)
+                    .take(end.saturating_sub(start))
+                    .collect(),
+            )
+        }
+        (PathSegment::Key(key), value) => {
+            return Err(format!("Cannot get the key '{}' from {}", key, value))
+        }
+        (_, value) => return Err(format!("Cannot index {}, as it is not an array", value)),
+    };
+
+    select(selected, rest)
+}
+
+/// Check if a key path refers to another key, i.e. if one of them contains the other.
+///
+/// # Examples
+/// ```
+/// assert!(refers_to("results", "results.change"));
+/// assert!(refers_to("results.change.0", "results.change"));
+/// assert!(!refers_to("results.change_pm", "results.change"));
+/// ```
+pub fn refers_to(path: &str, key: &str) -> bool {
+    path == key || key.starts_with(&format!("{}.", path)) || path.starts_with(&format!("{}.", key))
+}
+
+/// Get the value of a number as f64, or return an error that names the operator.
+fn as_number(value: &Json, operator: &str) -> Result<f64, String> {
+    value
+        .as_f64()
+        .ok_or_else(|| format!("'{}' expected a number. Got: {}", operator, value))
+}
+
+fn as_bool(value: &Json, operator: &str) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("'{}' expected true or false. Got: {}", operator, value))
+}
+
+/// Convert a float result to json, or fail if it is not a real number.
+fn real_number(value: f64, operator: &str) -> Result<Json, String> {
+    match value.is_finite() {
+        true => Ok(Json::from(value)),
+        false => Err(format!("'{}' did not give a real number", operator)),
+    }
+}
+
+/// Write a value into a string for concatenation. Strings are written without quotes.
+fn concat_string(value: &Json) -> String {
+    match (value, Quantity::from_json(value)) {
+        (Json::String(s), _) => s.to_owned(),
+        (_, Some(q)) => q.to_text(),
+        (v, None) => v.to_string(),
+    }
+}
+
+/// Apply a binary operator on two evaluated values.
+///
+/// Integers stay integers for "+", "-", "*", "%" and "^" (with a positive exponent), while "/"
+/// always gives a float.
+fn apply_binary(op: &str, left: &Json, right: &Json) -> Result<Json, String> {
+    // Strings like "12.5 km^2" are quantities, and are not joined as text.
+    let is_text = |value: &Json| value.is_string() && Quantity::from_json(value).is_none();
+    if op == "+" && (is_text(left) || is_text(right)) {
+        return Ok(Json::String(concat_string(left) + &concat_string(right)));
+    }
+    if let Some(result) = units::apply_binary(op, left, right) {
+        return result;
+    }
+    if op == "==" || op == "!=" {
+        let equal = match (left.as_f64(), right.as_f64()) {
+            (Some(a), Some(b)) => a == b,
+            _ => left == right,
+        };
+        return Ok(Json::Bool(equal == (op == "==")));
+    }
+    if let (Some(a), Some(b)) = (left.as_str(), right.as_str()) {
+        return match op {
+            "<" => Ok(Json::Bool(a < b)),
+            "<=" => Ok(Json::Bool(a <= b)),
+            ">" => Ok(Json::Bool(a > b)),
+            ">=" => Ok(Json::Bool(a >= b)),
+            _ => Err(format!("'{}' cannot be used on strings", op)),
+        };
+    }
+
+    if let (Some(a), Some(b)) = (left.as_i64(), right.as_i64()) {
+        let integer = match op {
+            "+" => a.checked_add(b),
+            "-" => a.checked_sub(b),
+            "*" => a.checked_mul(b),
+            "%" if b != 0 => Some(a % b),
+            "^" if (0..=u32::MAX as i64).contains(&b) => a.checked_pow(b as u32),
+            _ => None,
+        };
+        if let Some(value) = integer {
+            return Ok(Json::from(value));
+        }
+    }
+
+    let (a, b) = (as_number(left, op)?, as_number(right, op)?);
+    match op {
+        "+" => real_number(a + b, op),
+        "-" => real_number(a - b, op),
+        "*" => real_number(a * b, op),
+        "/" | "%" if b == 0.0 => Err("Division by zero".into()),
+        "/" => real_number(a / b, op),
+        "%" => real_number(a % b, op),
+        "^" => real_number(a.powf(b), op),
+        "<" => Ok(Json::Bool(a < b)),
+        "<=" => Ok(Json::Bool(a <= b)),
+        ">" => Ok(Json::Bool(a > b)),
+        ">=" => Ok(Json::Bool(a >= b)),
+        _ => Err(format!("Unknown operator '{}'", op)),
+    }
+}
+
+impl Expression {
+    /// List the dotted key paths that the expression refers to, in order of appearance.
+    pub fn dependencies(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        self.collect_dependencies(&mut paths);
+        paths
+    }
+
+    fn collect_dependencies(&self, paths: &mut Vec<String>) {
+        match self {
+            Expression::Literal(_) => (),
+            Expression::Path(segments) => {
+                let path = lookup_path(segments).0;
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+            Expression::Unary(_, operand) => operand.collect_dependencies(paths),
+            Expression::Binary(_, left, right) => {
+                left.collect_dependencies(paths);
+                right.collect_dependencies(paths);
+            }
+            Expression::Ternary(condition, if_true, if_false) => {
+                condition.collect_dependencies(paths);
+                if_true.collect_dependencies(paths);
+                if_false.collect_dependencies(paths);
+            }
+            Expression::Call(_, args) => {
+                for arg in args {
+                    arg.collect_dependencies(paths);
+                }
+            }
+        }
+    }
+
+    /// Evaluate the expression.
+    ///
+    /// # Arguments
+    /// * `lookup`: A function that returns the value of a dotted key path.
+    ///
+    /// # Returns
+    /// The resulting value, or an error detailing why it failed.
+    pub fn evaluate(
+        &self,
+        lookup: &mut dyn FnMut(&str) -> Result<Json, String>,
+    ) -> Result<Json, String> {
+        self.evaluate_recorded(lookup, &mut |_, _| ())
+    }
+
+    /// Evaluate the expression and record the value of each part of it.
+    ///
+    /// # Arguments
+    /// * `lookup`: A function that returns the value of a dotted key path.
+    /// * `record`: A function that is given each evaluated (sub-)expression and its value, with
+    ///   the innermost ones first and the whole expression last.
+    ///
+    /// # Returns
+    /// The resulting value, or an error detailing why it failed.
+    pub fn evaluate_recorded(
+        &self,
+        lookup: &mut dyn FnMut(&str) -> Result<Json, String>,
+        record: &mut Recorder,
+    ) -> Result<Json, String> {
+        let value = match self {
+            Expression::Literal(value) => Ok(value.clone()),
+            Expression::Path(segments) => {
+                let (path, rest) = lookup_path(segments);
+                select(lookup(&path)?, rest)
+            }
+            Expression::Unary(op, operand) => {
+                let value = operand.evaluate_recorded(lookup, record)?;
+                match (*op, Quantity::from_json(&value)) {
+                    ("!", _) => Ok(Json::Bool(!as_bool(&value, op)?)),
+                    (_, Some(q)) => Ok(Quantity {
+                        value: -q.value,
+                        unit: q.unit,
+                    }
+                    .to_json()),
+                    _ => match value.as_i64().and_then(|v| v.checked_neg()) {
+                        Some(v) => Ok(Json::from(v)),
+                        None => real_number(-as_number(&value, op)?, op),
+                    },
+                }
+            }
+            // The logic operators only evaluate the right side if needed.
+            Expression::Binary(op, left, right) if *op == "&&" || *op == "||" => {
+                let left = as_bool(&left.evaluate_recorded(lookup, record)?, op)?;
+                match (*op, left) {
+                    ("&&", false) => Ok(Json::Bool(false)),
+                    ("||", true) => Ok(Json::Bool(true)),
+                    _ => Ok(Json::Bool(as_bool(
+                        &right.evaluate_recorded(lookup, record)?,
+                        op,
+                    )?)),
+                }
+            }
+            Expression::Binary(op, left, right) => apply_binary(
+                op,
+                &left.evaluate_recorded(lookup, record)?,
+                &right.evaluate_recorded(lookup, record)?,
+            ),
+            Expression::Ternary(condition, if_true, if_false) => {
+                match as_bool(&condition.evaluate_recorded(lookup, record)?, "?")? {
+                    true => if_true.evaluate_recorded(lookup, record),
+                    false => if_false.evaluate_recorded(lookup, record),
+                }
+            }
+            Expression::Call(name, args) => {
+                let function =
+                    functions::find(name).ok_or_else(|| format!("Unknown function '{}'", name))?;
+                let values = args
+                    .iter()
+                    .map(|arg| arg.evaluate_recorded(lookup, record))
+                    .collect::<Result<Vec<Json>, String>>()?;
+
+                function(&values)
+            }
+        }?;
+        record(self, &value);
+
+        Ok(value)
+    }
+}
+
+/// Write a path as it would be written in an expression, e.g. "samples[1:].dh".
+fn write_path(f: &mut fmt::Formatter, segments: &[PathSegment]) -> fmt::Result {
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            PathSegment::Key(key) if i == 0 => write!(f, "{}", key)?,
+            PathSegment::Key(key) => write!(f, ".{}", key)?,
+            PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            PathSegment::Slice(start, end) => {
+                let bound = |b: &Option<i64>| b.map(|v| v.to_string()).unwrap_or_default();
+                write!(f, "[{}:{}]", bound(start), bound(end))?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write an operand, with parentheses around it if it contains operators itself.
+fn write_operand(f: &mut fmt::Formatter, operand: &Expression) -> fmt::Result {
+    match operand {
+        Expression::Binary(..) | Expression::Ternary(..) => write!(f, "({})", operand),
+        _ => write!(f, "{}", operand),
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Literal(value) => write!(f, "{}", value),
+            Expression::Path(segments) => write_path(f, segments),
+            Expression::Unary(op, operand) => {
+                write!(f, "{}", op)?;
+                write_operand(f, operand)
+            }
+            // "-a ^ 2" is read as "-(a ^ 2)", so a negated base needs parentheses.
+            Expression::Binary(op, left, right)
+                if *op == "^" && matches!(**left, Expression::Unary(..)) =>
+            {
+                write!(f, "({}) {} ", left, op)?;
+                write_operand(f, right)
+            }
+            Expression::Binary(op, left, right) => {
+                write_operand(f, left)?;
+                write!(f, " {} ", op)?;
+                write_operand(f, right)
+            }
+            Expression::Ternary(condition, if_true, if_false) => {
+                write_operand(f, condition)?;
+                write!(f, " ? ")?;
+                write_operand(f, if_true)?;
+                write!(f, " : ")?;
+                write_operand(f, if_false)
+            }
+            Expression::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_and_evaluate() {
+        let data = serde_json::json!({
+            "a": 3,
+            "results": {
+                "change": -1.5,
+                "name": "glacier",
+                "values": [10, 20],
+                "samples": [{"dh": 1}, {"dh": 2}, {"dh": 3}],
+            },
+        });
+        let evaluate = |source: &str| {
+            parse(source).unwrap().evaluate(&mut |key: &str| {
+                get_by_path(&data, key)
+                    .cloned()
+                    .ok_or_else(|| format!("Key not found: {}", key))
+            })
+        };
+
+        assert_eq!(evaluate("-1 + 2 * a"), Ok(serde_json::json!(5)));
+        assert_eq!(evaluate("-2 ^ 2"), Ok(serde_json::json!(-4)));
+        assert_eq!(evaluate("2 ** 3 ^ 2"), Ok(serde_json::json!(512)));
+        assert_eq!(evaluate("2 ^ -1"), Ok(serde_json::json!(0.5)));
+        assert_eq!(evaluate("1.2e-3 * 1E3"), Ok(serde_json::json!(1.2)));
+        assert_eq!(evaluate("6 / 3"), Ok(serde_json::json!(2.0)));
+        assert_eq!(evaluate("-results.change"), Ok(serde_json::json!(1.5)));
+        assert_eq!(evaluate("results.values.1 % 7"), Ok(serde_json::json!(6)));
+        assert_eq!(
+            evaluate("'The ' + results.name + \" moved \" + a + ' m'"),
+            Ok(serde_json::json!("The glacier moved 3 m"))
+        );
+        assert_eq!(
+            evaluate("results.change < 0 ? 'retreat' : 'advance'"),
+            Ok(serde_json::json!("retreat"))
+        );
+        assert_eq!(
+            evaluate("a > 2 && !(a == 4) || missing"),
+            Ok(serde_json::json!(true))
+        );
+        assert_eq!(evaluate("results.values[-1]"), Ok(serde_json::json!(20)));
+        assert_eq!(
+            evaluate("results.samples[1:].dh"),
+            Ok(serde_json::json!([2, 3]))
+        );
+        assert_eq!(
+            evaluate("results.samples[:-1][0].dh + results.samples.2.dh"),
+            Ok(serde_json::json!(4))
+        );
+        assert!(evaluate("results.values[2]").is_err());
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("'a' - 1").is_err());
+        assert!(evaluate("missing + 1").is_err());
+
+        assert_eq!(
+            parse("a + results.change * round(results.values.0, a)")
+                .unwrap()
+                .dependencies(),
+            vec!["a", "results.change", "results.values.0"]
+        );
+        assert_eq!(
+            parse("mean(samples[2:].dh) + samples[1].dh")
+                .unwrap()
+                .dependencies(),
+            vec!["samples", "samples.1.dh"]
+        );
+
+        for (source, column) in &[
+            ("1 + * 2", 5),
+            ("round(1, 2", 11),
+            ("a.", 3),
+            ("2 $ 1", 3),
+            ("a[1", 4),
+            ("a[x]", 3),
+        ] {
+            let error = parse(source).unwrap_err();
+            assert_eq!(error.column, *column, "{}: {}", source, error);
+        }
+        assert_eq!(
+            parse("(1 + 2").unwrap_err().to_string(),
+            "Expected ')', found the end at column 7"
+        );
+    }
+
+    #[test]
+    fn test_display_and_record() {
+        for source in &[
+            "a + b * c",
+            "(a + b) * -c",
+            "round(mean(samples[1:].dh), 2)",
+            "x > 1 ? \"big\" : samples[-1]",
+            "(-a) ^ 2",
+            "-a ^ 2",
+        ] {
+            let parsed = parse(source).unwrap();
+            assert_eq!(parse(&parsed.to_string()).unwrap(), parsed, "{}", source);
+        }
+        assert_eq!(parse("(a+b)*c").unwrap().to_string(), "(a + b) * c");
+        assert_eq!(parse("(-2)^2").unwrap().to_string(), "(-2) ^ 2");
+
+        let mut steps: Vec<(String, Json)> = Vec::new();
+        let value = parse("2 * (a + 1)")
+            .unwrap()
+            .evaluate_recorded(&mut |_| Ok(serde_json::json!(3)), &mut |e, v| {
+                steps.push((e.to_string(), v.clone()))
+            })
+            .unwrap();
+
+        assert_eq!(value, serde_json::json!(8));
+        let parts: Vec<&str> = steps.iter().map(|(e, _)| e.as_str()).collect();
+        assert_eq!(parts, vec!["2", "a", "1", "a + 1", "2 * (a + 1)"]);
+        assert_eq!(steps[3].1, serde_json::json!(4));
+    }
+}
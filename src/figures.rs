@@ -0,0 +1,318 @@
+//! Figures declared in the data, rendered as complete `figure` environments.
+//!
+//! Each figure has an id under the `figures` table:
+//! ```toml
+//! [figures.map]
+//! path = "figures/map.pdf"
+//! caption = "The {{n_sites}} sites."  # Optional. Rendered like the rest of the manuscript.
+//! label = "fig:map"  # Optional. Defaults to "fig:<id>".
+//! width = "0.8\\linewidth"  # Optional. Defaults to "\linewidth".
+//! placement = "htbp"  # Optional.
+//! sources = ["scripts/map.py", "sites.csv"]  # Optional. Files that the image is made from.
+//! ```
+//! and is written with `{{figure "map"}}`. Paths are relative to the data file that declares them.
+use serde_json::Value as Json;
+use std::io::Write;
+use std::path::Path;
+
+/// Make the image and source paths of the figures in a data file relative to the working directory.
+///
+/// # Arguments
+/// * `data`: The data of one file, before any included files are merged in.
+/// * `directory`: The directory of the data file, which its figure paths are relative to.
+pub fn resolve_paths(data: &mut Json, directory: &Path) {
+    let resolve = |value: &mut Json| {
+        if let Json::String(path) = value {
+            *path = directory.join(&path).display().to_string();
+        }
+    };
+    let figures = match data.get_mut("figures") {
+        Some(Json::Object(figures)) => figures,
+        _ => return,
+    };
+    for figure in figures.values_mut() {
+        if let Some(path) = figure.get_mut("path") {
+            resolve(path);
+        }
+        match figure.get_mut("sources") {
+            Some(Json::Array(sources)) => sources.iter_mut().for_each(resolve),
+            Some(source) => resolve(source),
+            None => (),
+        }
+    }
+}
+
+/// Find out if any source file of an image was modified after the image.
+///
+/// # Arguments
+/// * `image`: The path to the image.
+/// * `sources`: The scripts or data files that the image is made from.
+///
+/// # Returns
+/// The sources that are newer than the image, or an error if a file could not be read.
+pub fn newer_sources<'a>(image: &Path, sources: &[&'a str]) -> Result<Vec<&'a str>, String> {
+    let modified = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))
+    };
+    let image_modified = modified(image)?;
+
+    let mut newer: Vec<&str> = Vec::new();
+    for source in sources {
+        if modified(Path::new(source))? > image_modified {
+            newer.push(source);
+        }
+    }
+    Ok(newer)
+}
+
+/// The warning to print if an image is older than any of its sources.
+///
+/// # Returns
+/// The warning, None if the image is up to date, or an error if a file could not be read.
+fn outdated_warning(id: &str, image: &str, sources: &[&str]) -> Result<Option<String>, String> {
+    let newer = newer_sources(Path::new(image), sources)?;
+    Ok(match newer.is_empty() {
+        true => None,
+        false => Some(format!(
+            "WARNING: The image of '{}' ({}) is older than: {}\n",
+            id,
+            image,
+            newer.join(", ")
+        )),
+    })
+}
+
+/// Helper to write a figure from the "figures" table of the data.
+///
+/// Given the data:
+/// ```
+/// {
+///     "figures": {"map": {"path": "figures/map.pdf", "caption": "The sites."}}
+/// }
+/// ```
+/// the helper "{{figure "map"}}" will render as:
+/// ```latex
+/// \begin{figure}[htbp]
+/// \centering
+/// \includegraphics[width=\linewidth]{figures/map.pdf}
+/// \caption{The sites.}
+/// \label{fig:map}
+/// \end{figure}
+/// ```
+/// The image has to exist, and a warning is printed if it is older than any of its sources.
+/// The "width" and "placement" can also be given as arguments, e.g. `width="0.5\\linewidth"`.
+pub fn figure_helper(
+    h: &handlebars::Helper,
+    r: &handlebars::Handlebars,
+    context: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let error = |message: String| handlebars::RenderError::new(format!("figure: {}", message));
+
+    let id = h
+        .param(0)
+        .and_then(|p| p.value().as_str())
+        .ok_or_else(|| error("The id of the figure is required, e.g. {{figure \"map\"}}".into()))?;
+    let figure = context
+        .data()
+        .get("figures")
+        .and_then(|f| f.get(id))
+        .ok_or_else(|| error(format!("'{}' not found in the 'figures' table.", id)))?;
+    let field = |name: &str| match h.hash_get(name).map(|v| v.value()) {
+        Some(Json::String(s)) => Some(s.to_owned()),
+        _ => figure.get(name).and_then(|v| v.as_str()).map(String::from),
+    };
+
+    let path = field("path").ok_or_else(|| error(format!("'{}' has no 'path'.", id)))?;
+    if !Path::new(&path).is_file() {
+        return Err(error(format!("Image of '{}' not found: {}", id, path)));
+    }
+    let sources: Vec<&str> = match figure.get("sources") {
+        Some(Json::Array(arr)) => arr.iter().filter_map(|v| v.as_str()).collect(),
+        Some(Json::String(s)) => vec![s.as_str()],
+        _ => Vec::new(),
+    };
+    if let Some(warning) = outdated_warning(id, &path, &sources).map_err(error)? {
+        std::io::stderr().write_all(warning.as_bytes()).unwrap();
+    }
+
+    let mut lines = vec![
+        format!(
+            "\\begin{{figure}}[{}]",
+            field("placement").unwrap_or_else(|| "htbp".into())
+        ),
+        "\\centering".to_owned(),
+        format!(
+            "\\includegraphics[width={}]{{{}}}",
+            field("width").unwrap_or_else(|| "\\linewidth".into()),
+            path
+        ),
+    ];
+    if let Some(caption) = field("caption") {
+        lines.push(format!(
+            "\\caption{{{}}}",
+            r.render_template(&caption, context.data())
+                .map_err(|e| error(format!("Could not render the caption of '{}': {}", id, e)))?
+        ));
+    }
+    lines.push(format!(
+        "\\label{{{}}}",
+        field("label").unwrap_or_else(|| format!("fig:{}", id))
+    ));
+    lines.push("\\end{figure}".to_owned());
+
+    out.write(&lines.join("\n"))?;
+    Ok(())
+}
+
+/// Find the image files of a manuscript, i.e. the paths of all `\includegraphics` commands.
+///
+/// # Arguments
+/// * `lines`: The lines of the manuscript, with the data filled in.
+///
+/// # Returns
+/// The image paths in the order they first appear.
+pub fn image_paths(lines: &[String]) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for line in lines.iter().flat_map(|l| l.split('\n')) {
+        // Commented out figures are not part of the manuscript.
+        let line = &line[..crate::strip::comment_start(line).unwrap_or(line.len())];
+
+        for (i, _) in line.match_indices("\\includegraphics") {
+            let rest = &line[i + "\\includegraphics".len()..];
+            // Skip the optional [width=...] argument.
+            let rest = match rest.trim_start().strip_prefix('[') {
+                Some(r) => r.split_once(']').map(|r| r.1).unwrap_or_default(),
+                None => rest,
+            };
+            if let Some(path) = rest
+                .trim_start()
+                .strip_prefix('{')
+                .and_then(|r| r.split('}').next())
+            {
+                if !paths.iter().any(|p| p == path) {
+                    paths.push(path.to_owned());
+                }
+            }
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::templates::fill_data;
+
+    #[test]
+    fn test_figure_helper() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let image = temp_dir.path().join("map.pdf");
+        let script = temp_dir.path().join("map.py");
+        std::fs::write(&script, "").unwrap();
+        std::fs::write(&image, "").unwrap();
+
+        let data = serde_json::json!({
+            "n_sites": 4,
+            "figures": {
+                "map": {
+                    "path": image.to_str().unwrap(),
+                    "caption": "The {{n_sites}} sites.",
+                    "sources": [script.to_str().unwrap()],
+                },
+                "missing": {"path": "missing.pdf"},
+            },
+        });
+        let lines = fill_data(
+            &[
+                "{{figure \"map\" width=\"0.5\\\\linewidth\"}}".into(),
+                "{{figure \"missing\"}}".into(),
+            ],
+            &data,
+        )
+        .unwrap();
+        assert_eq!(
+            lines[0],
+            format!(
+                "\\begin{{figure}}[htbp]\n\\centering\n\\includegraphics[width=0.5\\linewidth]{{{}}}\n\\caption{{The 4 sites.}}\n\\label{{fig:map}}\n\\end{{figure}}",
+                image.to_str().unwrap()
+            )
+        );
+        // A missing image is not rendered.
+        assert_eq!(lines[1], "{{figure \"missing\"}}");
+
+        assert!(newer_sources(&image, &[script.to_str().unwrap()])
+            .unwrap()
+            .is_empty());
+        assert!(newer_sources(&image, &["missing.py"]).is_err());
+
+        // A script that was changed after the image was made gives a warning.
+        let now = std::time::SystemTime::now();
+        let set_modified = |path: &Path, time: std::time::SystemTime| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap()
+        };
+        set_modified(&image, now - std::time::Duration::from_secs(60));
+        set_modified(&script, now);
+        assert_eq!(
+            newer_sources(&image, &[script.to_str().unwrap()]).unwrap(),
+            vec![script.to_str().unwrap()]
+        );
+        assert_eq!(
+            outdated_warning("map", image.to_str().unwrap(), &[script.to_str().unwrap()]).unwrap(),
+            Some(format!(
+                "WARNING: The image of 'map' ({}) is older than: {}\n",
+                image.display(),
+                script.display()
+            ))
+        );
+        // The figure is still rendered.
+        assert!(fill_data(&["{{figure \"map\"}}".into()], &data).unwrap()[0]
+            .starts_with("\\begin{figure}"));
+
+        let mut declared = serde_json::json!({
+            "figures": {
+                "map": {"path": "map.pdf", "sources": ["map.py", "/abs/sites.csv"]},
+                "logo": {"path": "logo.pdf", "sources": "logo.py"},
+            },
+        });
+        resolve_paths(&mut declared, Path::new("figures"));
+        assert_eq!(
+            declared,
+            serde_json::json!({
+                "figures": {
+                    "map": {
+                        "path": Path::new("figures").join("map.pdf").display().to_string(),
+                        "sources": [
+                            Path::new("figures").join("map.py").display().to_string(),
+                            "/abs/sites.csv",
+                        ],
+                    },
+                    "logo": {
+                        "path": Path::new("figures").join("logo.pdf").display().to_string(),
+                        "sources": Path::new("figures").join("logo.py").display().to_string(),
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            image_paths(&[
+                lines[0].to_owned(),
+                "\\includegraphics{a.png} and \\includegraphics [scale=2] {b.png}".into(),
+                "% \\includegraphics{c.png}".into(),
+                "50\\% of \\includegraphics{d.png} \\\\% \\includegraphics{e.png}".into(),
+                "\\includegraphics{a.png}".into(),
+            ]),
+            vec![image.to_str().unwrap(), "a.png", "b.png", "d.png"]
+        );
+    }
+}
@@ -0,0 +1,518 @@
+//! Functions that can be called in "expr:" expressions.
+use crate::templates::{float_to_json, round_value};
+use crate::units::Quantity;
+use serde_json::Value as Json;
+
+/// A function that takes the evaluated arguments of an expression function call.
+pub type Function = fn(&[Json]) -> Result<Json, String>;
+
+/// Statistics functions and their names, e.g. "expr: mean(samples.dh)".
+///
+/// Arrays in the arguments are flattened, so "mean(a)", "mean(a, b)" and "mean(1, 2, 3)" all work,
+/// even if "a" is an array of arrays.
+pub const STATISTICS: [(&str, Function); 10] = [
+    ("mean", mean),
+    ("median", median),
+    ("std", std),
+    ("sem", sem),
+    ("min", min),
+    ("max", max),
+    ("sum", sum),
+    ("count", count),
+    ("percentile", percentile),
+    ("weighted_mean", weighted_mean),
+];
+
+/// Math functions and their names, e.g. "expr: sqrt(area) / 2".
+///
+/// Trigonometric functions work in radians, and the variants ending with "d" work in degrees.
+/// "len", "is_empty" and "array" work on arrays, objects and strings.
+pub const MATH: [(&str, Function); 28] = [
+    ("round", |args| keep_unit(args, round)),
+    ("pow", pow),
+    ("E", power_of_ten),
+    ("sqrt", |args| unary(args, "sqrt", f64::sqrt)),
+    ("abs", |args| keep_unit(args, |a| unary(a, "abs", f64::abs))),
+    ("exp", |args| unary(args, "exp", f64::exp)),
+    ("ln", |args| unary(args, "ln", f64::ln)),
+    ("log10", |args| unary(args, "log10", f64::log10)),
+    ("log", log),
+    ("sin", |args| unary(args, "sin", f64::sin)),
+    ("cos", |args| unary(args, "cos", f64::cos)),
+    ("tan", |args| unary(args, "tan", f64::tan)),
+    ("asin", |args| unary(args, "asin", f64::asin)),
+    ("acos", |args| unary(args, "acos", f64::acos)),
+    ("atan", |args| unary(args, "atan", f64::atan)),
+    ("sind", |args| unary(args, "sind", |x| x.to_radians().sin())),
+    ("cosd", |args| unary(args, "cosd", |x| x.to_radians().cos())),
+    ("tand", |args| unary(args, "tand", |x| x.to_radians().tan())),
+    ("asind", |args| {
+        unary(args, "asind", |x| x.asin().to_degrees())
+    }),
+    ("acosd", |args| {
+        unary(args, "acosd", |x| x.acos().to_degrees())
+    }),
+    ("atand", |args| {
+        unary(args, "atand", |x| x.atan().to_degrees())
+    }),
+    ("floor", |args| {
+        keep_unit(args, |a| unary(a, "floor", f64::floor))
+    }),
+    ("ceil", |args| {
+        keep_unit(args, |a| unary(a, "ceil", f64::ceil))
+    }),
+    ("clamp", clamp),
+    ("atan2", atan2),
+    ("len", |args| Ok(Json::from(length(args, "len")?))),
+    ("is_empty", is_empty),
+    ("array", |args| Ok(Json::Array(args.to_vec()))),
+];
+
+/// Unit functions and their names, e.g. "expr: to(volume, \"km^3\")".
+pub const UNITS: [(&str, Function); 1] = [("to", to)];
+
+/// Find a function by its name.
+pub fn find(name: &str) -> Option<Function> {
+    STATISTICS
+        .iter()
+        .chain(MATH.iter())
+        .chain(UNITS.iter())
+        .find(|(n, _)| *n == name)
+        .map(|(_, function)| *function)
+}
+
+/// Constants that can be used in expressions, e.g. "expr: pi * radius ^ 2".
+///
+/// Keys in the data with the same names take precedence.
+pub const CONSTANTS: [(&str, f64); 2] = [("pi", std::f64::consts::PI), ("e", std::f64::consts::E)];
+
+/// Parse the arguments of a function call as a fixed number of numbers.
+fn fixed_numbers(args: &[Json], function: &str, n: usize) -> Result<Vec<f64>, String> {
+    if args.len() != n {
+        return Err(format!(
+            "{}: Expected {} argument(s) but got {}.",
+            function,
+            n,
+            args.len()
+        ));
+    }
+    args.iter()
+        .map(|arg| match arg {
+            Json::Number(x) => Ok(x.as_f64().unwrap()),
+            v => Err(format!("{}: Expected a number. Got: {}", function, v)),
+        })
+        .collect()
+}
+
+/// Check that a result is a real number, and convert it to json.
+fn real_result(value: f64, function: &str, args: &[f64]) -> Result<Json, String> {
+    match value.is_finite() {
+        true => Ok(float_to_json(value)),
+        false => Err(format!(
+            "{}: The result is not a real number for the argument(s): {:?}",
+            function, args
+        )),
+    }
+}
+
+/// Call a function that takes one number.
+fn unary(args: &[Json], function: &str, f: fn(f64) -> f64) -> Result<Json, String> {
+    let values = fixed_numbers(args, function, 1)?;
+
+    real_result(f(values[0]), function, &values)
+}
+
+/// Call a function on the value of a quantity (the first argument) and keep its unit.
+///
+/// Other values are passed to the function as they are.
+fn keep_unit(args: &[Json], function: Function) -> Result<Json, String> {
+    let quantity = match args.first().and_then(Quantity::from_json) {
+        Some(q) => q,
+        None => return function(args),
+    };
+    let mut new_args = args.to_vec();
+    new_args[0] = Json::from(quantity.value);
+
+    match function(&new_args)?.as_f64() {
+        Some(value) => Ok(Quantity {
+            value,
+            unit: quantity.unit,
+        }
+        .to_json()),
+        None => Err("Expected a numeric result.".into()),
+    }
+}
+
+/// Convert a quantity to another unit, e.g. "to(volume, \"km^3\")".
+fn to(args: &[Json]) -> Result<Json, String> {
+    let (quantity, unit) = match args {
+        [q, Json::String(u)] => (
+            Quantity::from_json(q)
+                .ok_or_else(|| format!("to: Expected a quantity with a unit. Got: {}", q))?,
+            u,
+        ),
+        _ => return Err("to: The arguments have to be a quantity and a unit string.".into()),
+    };
+
+    Ok(quantity
+        .convert(unit)
+        .map_err(|e| format!("to: {}", e))?
+        .to_json())
+}
+
+/// Round a value to a number of decimals (default 0), e.g. "round(1.23, 1)" => 1.2.
+fn round(args: &[Json]) -> Result<Json, String> {
+    let values = match args.len() {
+        1 => fixed_numbers(args, "round", 1)?,
+        _ => fixed_numbers(args, "round", 2)?,
+    };
+    let decimals = values.get(1).cloned().unwrap_or(0.0);
+
+    // Return an error if the decimal number is not equivalent to an integer.
+    if decimals.fract() != 0.0 {
+        return Err(format!(
+            "Second rounding argument must be an integer. Given value: {}",
+            decimals
+        ));
+    };
+
+    Ok(float_to_json(round_value(values[0], decimals as i64)))
+}
+
+/// Raise a value to the power of an exponent, e.g. "pow(10, 3)" => 1000.
+fn pow(args: &[Json]) -> Result<Json, String> {
+    let values = fixed_numbers(args, "pow", 2)?;
+
+    real_result(values[0].powf(values[1]), "pow", &values)
+}
+
+/// Expand a power of ten, e.g. "E(3)" => 1000.
+fn power_of_ten(args: &[Json]) -> Result<Json, String> {
+    let values = fixed_numbers(args, "E", 1)?;
+
+    real_result(10_f64.powf(values[0]), "E", &values)
+}
+
+/// The logarithm of x with a given base, e.g. "log(8, 2)" => 3.
+fn log(args: &[Json]) -> Result<Json, String> {
+    let values = fixed_numbers(args, "log", 2)?;
+
+    real_result(values[0].log(values[1]), "log", &values)
+}
+
+/// Limit a value to a range, e.g. "clamp(x, 0, 1)".
+fn clamp(args: &[Json]) -> Result<Json, String> {
+    let values = fixed_numbers(args, "clamp", 3)?;
+
+    if values[1] > values[2] {
+        return Err(format!(
+            "clamp: The lower bound ({}) is larger than the upper bound ({}).",
+            values[1], values[2]
+        ));
+    }
+    Ok(float_to_json(values[0].max(values[1]).min(values[2])))
+}
+
+/// The angle (in radians) of the point (x, y), given as "atan2(y, x)".
+fn atan2(args: &[Json]) -> Result<Json, String> {
+    let values = fixed_numbers(args, "atan2", 2)?;
+
+    real_result(values[0].atan2(values[1]), "atan2", &values)
+}
+
+/// The number of elements of an array or object, or of characters in a string.
+fn length(args: &[Json], function: &str) -> Result<usize, String> {
+    match args {
+        [Json::Array(arr)] => Ok(arr.len()),
+        [Json::Object(obj)] => Ok(obj.len()),
+        [Json::String(s)] => Ok(s.chars().count()),
+        [v] => Err(format!(
+            "{}: Expected an array, object or string. Got: {}",
+            function, v
+        )),
+        _ => Err(format!(
+            "{}: Expected 1 argument(s) but got {}.",
+            function,
+            args.len()
+        )),
+    }
+}
+
+/// Check if an array, object or string is empty, e.g. "is_empty(samples)". Null is empty too.
+fn is_empty(args: &[Json]) -> Result<Json, String> {
+    match args {
+        [Json::Null] => Ok(Json::Bool(true)),
+        _ => Ok(Json::Bool(length(args, "is_empty")? == 0)),
+    }
+}
+
+/// Recursively collect all numbers in a json value.
+fn collect_numbers(value: &Json, numbers: &mut Vec<f64>) -> Result<(), String> {
+    match value {
+        Json::Number(n) => numbers.push(n.as_f64().unwrap()),
+        Json::Array(arr) => {
+            for val in arr {
+                collect_numbers(val, numbers)?;
+            }
+        }
+        v => {
+            return Err(format!(
+                "Expected a number or an array of numbers. Got: {}",
+                v
+            ))
+        }
+    };
+    Ok(())
+}
+
+/// Flatten the arguments of a function call into a vector of numbers.
+///
+/// # Arguments
+/// * `args`: The arguments to flatten.
+/// * `function`: The name of the function (for the error messages).
+/// * `allow_empty`: Whether zero numbers is a valid input.
+fn numbers(args: &[Json], function: &str, allow_empty: bool) -> Result<Vec<f64>, String> {
+    let mut numbers: Vec<f64> = Vec::new();
+    for arg in args {
+        collect_numbers(arg, &mut numbers).map_err(|e| format!("{}: {}", function, e))?;
+    }
+
+    if numbers.is_empty() & !allow_empty {
+        return Err(format!("{}: No values were given.", function));
+    }
+    Ok(numbers)
+}
+
+/// Sort the numbers in ascending order.
+fn sorted(mut numbers: Vec<f64>) -> Vec<f64> {
+    numbers.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    numbers
+}
+
+/// Find the p:th percentile (0-100) of sorted numbers with linear interpolation.
+fn interpolate_percentile(sorted_numbers: &[f64], p: f64) -> f64 {
+    let position = (p / 100.0) * (sorted_numbers.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+
+    sorted_numbers[lower] + (sorted_numbers[upper] - sorted_numbers[lower]) * position.fract()
+}
+
+/// The arithmetic mean.
+fn mean(args: &[Json]) -> Result<Json, String> {
+    let values = numbers(args, "mean", false)?;
+
+    Ok(float_to_json(
+        values.iter().sum::<f64>() / values.len() as f64,
+    ))
+}
+
+/// The median, i.e. the 50th percentile.
+fn median(args: &[Json]) -> Result<Json, String> {
+    let values = sorted(numbers(args, "median", false)?);
+
+    Ok(float_to_json(interpolate_percentile(&values, 50.0)))
+}
+
+/// The sample standard deviation (with N - 1 degrees of freedom).
+fn std(args: &[Json]) -> Result<Json, String> {
+    Ok(float_to_json(standard_deviation(&numbers(
+        args, "std", false,
+    )?)?))
+}
+
+/// Calculate the sample standard deviation of numbers.
+fn standard_deviation(values: &[f64]) -> Result<f64, String> {
+    if values.len() < 2 {
+        return Err("std: At least two values are needed.".into());
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance =
+        values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+
+    Ok(variance.sqrt())
+}
+
+/// The standard error of the mean: std / sqrt(N).
+fn sem(args: &[Json]) -> Result<Json, String> {
+    let values = numbers(args, "sem", false)?;
+
+    Ok(float_to_json(
+        standard_deviation(&values)? / (values.len() as f64).sqrt(),
+    ))
+}
+
+/// The smallest value.
+fn min(args: &[Json]) -> Result<Json, String> {
+    let values = numbers(args, "min", false)?;
+
+    Ok(float_to_json(
+        values.iter().cloned().fold(f64::INFINITY, f64::min),
+    ))
+}
+
+/// The largest value.
+fn max(args: &[Json]) -> Result<Json, String> {
+    let values = numbers(args, "max", false)?;
+
+    Ok(float_to_json(
+        values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+    ))
+}
+
+/// The sum of all values. The sum of no values is 0.
+fn sum(args: &[Json]) -> Result<Json, String> {
+    Ok(float_to_json(numbers(args, "sum", true)?.iter().sum()))
+}
+
+/// The amount of values.
+fn count(args: &[Json]) -> Result<Json, String> {
+    Ok(Json::from(numbers(args, "count", true)?.len()))
+}
+
+/// The p:th percentile (0-100), e.g. "percentile(samples.dh, 90)".
+fn percentile(args: &[Json]) -> Result<Json, String> {
+    let (p, values) = match args.split_last() {
+        Some((Json::Number(p), values)) => (p.as_f64().unwrap(), values),
+        _ => return Err("percentile: The last argument has to be the percentile (0-100).".into()),
+    };
+    if !(0.0..=100.0).contains(&p) {
+        return Err(format!(
+            "percentile: The percentile has to be between 0 and 100. Got: {}",
+            p
+        ));
+    }
+    let values = sorted(numbers(values, "percentile", false)?);
+
+    Ok(float_to_json(interpolate_percentile(&values, p)))
+}
+
+/// The mean of values weighted by weights, e.g. "weighted_mean(samples.dh, samples.area)".
+fn weighted_mean(args: &[Json]) -> Result<Json, String> {
+    if args.len() != 2 {
+        return Err("weighted_mean: Two arguments (values and weights) are required.".into());
+    }
+    let values = numbers(&args[..1], "weighted_mean", false)?;
+    let weights = numbers(&args[1..], "weighted_mean", false)?;
+
+    if values.len() != weights.len() {
+        return Err(format!(
+            "weighted_mean: There are {} values but {} weights.",
+            values.len(),
+            weights.len()
+        ));
+    }
+    let weight_sum: f64 = weights.iter().sum();
+    if weight_sum == 0.0 {
+        return Err("weighted_mean: The weights sum to zero.".into());
+    }
+
+    Ok(float_to_json(
+        values.iter().zip(&weights).map(|(v, w)| v * w).sum::<f64>() / weight_sum,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_statistics() {
+        let samples = serde_json::json!([[1, 2], [3, 4, 10]]);
+        let args = vec![samples];
+
+        assert_eq!(mean(&args).unwrap(), 4);
+        assert_eq!(median(&args).unwrap(), 3);
+        assert_approx_eq!(std(&args).unwrap().as_f64().unwrap(), 3.5355, 1e-4);
+        assert_approx_eq!(sem(&args).unwrap().as_f64().unwrap(), 1.5811, 1e-4);
+        assert_eq!(min(&args).unwrap(), 1);
+        assert_eq!(max(&args).unwrap(), 10);
+        assert_eq!(sum(&args).unwrap(), 20);
+        assert_eq!(count(&args).unwrap(), 5);
+        assert_eq!(sum(&[]).unwrap(), 0);
+
+        let args = vec![args[0].clone(), serde_json::json!(25)];
+        assert_eq!(percentile(&args).unwrap(), 2);
+
+        let args = vec![serde_json::json!([1, 3]), serde_json::json!([3, 1])];
+        assert_eq!(weighted_mean(&args).unwrap(), serde_json::json!(1.5));
+
+        mean(&[]).expect_err("The mean of nothing should fail");
+        mean(&[serde_json::json!("a")]).expect_err("Strings are not numbers");
+        std(&[serde_json::json!(1)]).expect_err("One value has no spread");
+    }
+
+    #[test]
+    fn test_math() {
+        let call = |name: &str, args: serde_json::Value| {
+            let function = MATH.iter().find(|(n, _)| *n == name).unwrap().1;
+            function(args.as_array().unwrap())
+        };
+
+        assert_eq!(
+            call("sqrt", serde_json::json!([16])),
+            Ok(serde_json::json!(4))
+        );
+        assert_eq!(
+            call("abs", serde_json::json!([-2.5])),
+            Ok(serde_json::json!(2.5))
+        );
+        assert_eq!(
+            call("log", serde_json::json!([8, 2])),
+            Ok(serde_json::json!(3))
+        );
+        assert_eq!(
+            call("log10", serde_json::json!([1000])),
+            Ok(serde_json::json!(3))
+        );
+        assert_eq!(
+            call("clamp", serde_json::json!([1.5, 0, 1])),
+            Ok(serde_json::json!(1))
+        );
+        assert_eq!(
+            call("floor", serde_json::json!([-1.5])),
+            Ok(serde_json::json!(-2))
+        );
+        assert_eq!(
+            call("asind", serde_json::json!([1])),
+            Ok(serde_json::json!(90))
+        );
+        assert_approx_eq!(
+            call("cosd", serde_json::json!([60]))
+                .unwrap()
+                .as_f64()
+                .unwrap(),
+            0.5
+        );
+
+        let error = call("sqrt", serde_json::json!([-1])).unwrap_err();
+        assert!(error.contains("not a real number"), "{}", error);
+        call("ln", serde_json::json!([0])).expect_err("ln(0) is not finite");
+        call("sin", serde_json::json!([1, 2])).expect_err("Too many arguments");
+        call("clamp", serde_json::json!([1, 2, 0])).expect_err("Invalid bounds");
+
+        assert_eq!(
+            call("len", serde_json::json!([[1, 2, 3]])),
+            Ok(serde_json::json!(3))
+        );
+        assert_eq!(
+            call("len", serde_json::json!(["äbc"])),
+            Ok(serde_json::json!(3))
+        );
+        assert_eq!(
+            call("is_empty", serde_json::json!([{}])),
+            Ok(serde_json::json!(true))
+        );
+        assert_eq!(
+            call("is_empty", serde_json::json!([null])),
+            Ok(serde_json::json!(true))
+        );
+        assert_eq!(
+            call("array", serde_json::json!([1, "a"])),
+            Ok(serde_json::json!([1, "a"]))
+        );
+        call("len", serde_json::json!([1])).expect_err("A number has no length");
+    }
+}
@@ -52,20 +52,18 @@ pub fn parse_filepath(
 /// * `output_path`: Optional. A string to parse as an output path. If None, create a fitting path.
 ///
 /// # Returns
-/// The parsed lines as a vector of strings and a fitting path for the output.
+/// The parsed lines, with the file and line number that each came from, and a fitting path for
+/// the output.
 pub fn get_lines_and_output_path(
     input_str: &str,
     output_path: Option<&str>,
-) -> Result<(Vec<String>, PathBuf), Box<dyn std::error::Error>> {
+) -> Result<(Vec<SourceLine>, PathBuf), Box<dyn std::error::Error>> {
     let filepath: PathBuf;
-    let lines: Vec<String>;
+    let lines: Vec<SourceLine>;
 
     // If the path is "-", read tex from stdin
     if input_str.trim() == "-" {
-        lines = match read_tex_from_stdin() {
-            Ok(l) => l,
-            Err(e) => return Err(e),
-        };
+        lines = get_source_lines(input_str)?;
         // Simply assign the filepath to something generic. If the output path is specified,
         // this is obsolete.
         filepath = PathBuf::from("main.tex");
@@ -77,10 +75,7 @@ pub fn get_lines_and_output_path(
         };
 
         // Read and merge all tex files.
-        lines = match crate::merge_tex(&filepath) {
-            Ok(l) => l,
-            Err(e) => return Err(e),
-        };
+        lines = merge_tex_with_sources(&filepath)?;
     }
 
     // Either get the filepath from the OUTPUT argument, or call it the same filename as the
@@ -97,26 +92,211 @@ pub fn get_lines_and_output_path(
     Ok((lines, pdf_filepath))
 }
 
+/// Read tex lines from disk or stdin, and keep track of where each line came from.
+///
+/// Files are merged recursively, like `get_lines_and_output_path` does.
+///
+/// # Arguments
+/// * `input_str`: An input string to be parsed as a filepath or "-" to read from stdin.
+///
+/// # Returns
+/// The parsed lines and their source files and line numbers.
+pub fn get_source_lines(input_str: &str) -> Result<Vec<SourceLine>, Box<dyn std::error::Error>> {
+    // If the path is "-", read tex from stdin
+    if input_str.trim() == "-" {
+        let lines = read_tex_from_stdin()?
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| SourceLine {
+                path: PathBuf::from("-"),
+                line_no: i + 1,
+                text,
+            })
+            .collect();
+        return Ok(lines);
+    }
+
+    // Check that the file exists and return a valid PathBuf.
+    let filepath = parse_filepath(input_str, Some("tex"))?;
+
+    merge_tex_with_sources(&filepath)
+}
+
+/// A line of merged tex and the location it was read from.
+#[derive(Clone)]
+pub struct SourceLine {
+    /// The file that the line was read from.
+    pub path: PathBuf,
+    /// The line number (starting at 1) within that file.
+    pub line_no: usize,
+    /// The text of the line.
+    pub text: String,
+}
+
+#[cfg(test)]
+impl SourceLine {
+    /// Number the given lines of a file from 1, as if they were read from `path`.
+    pub fn numbered(path: &str, lines: &[&str]) -> Vec<SourceLine> {
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, text)| SourceLine {
+                path: PathBuf::from(path),
+                line_no: i + 1,
+                text: text.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Read a tex file and recursively merge all \\input{} statements, keeping track of where each
+/// line came from.
+///
+/// # Arguments
+/// * `filepath`: A relative or absolute path to the main.tex.
+pub fn merge_tex_with_sources(
+    filepath: &Path,
+) -> Result<Vec<SourceLine>, Box<dyn std::error::Error>> {
+    merge_tex_at_revision(filepath, None)
+}
+
+/// Read a tex file and recursively merge all \\input{} statements, either from disk or from a
+/// git revision.
+///
+/// # Arguments
+/// * `filepath`: A relative or absolute path to the main.tex.
+/// * `revision`: Optional. The git revision to read the files from. If None, read from disk.
+pub fn merge_tex_at_revision(
+    filepath: &Path,
+    revision: Option<&str>,
+) -> Result<Vec<SourceLine>, Box<dyn std::error::Error>> {
+    // Create the output line vector
+    let mut lines: Vec<SourceLine> = Vec::new();
+
+    // Parse the lines of the main file.
+    let main_lines = read_tex_at_revision(filepath, revision)?;
+
+    // Loop over the lines and handle any \input clauses.
+    let mut i = 0;
+    for line in main_lines {
+        // If it doesn't contain and input, just continue.
+        if !line.contains(r"\input{") {
+            lines.push(SourceLine {
+                path: filepath.to_path_buf(),
+                line_no: i + 1,
+                text: line,
+            });
+            i += 1;
+            continue;
+        }
+        let mut trimmed_line = line[(line.find(r"\input{").unwrap() + 7)..].to_owned();
+        trimmed_line = trimmed_line[..trimmed_line
+            .find('}')
+            .unwrap_or_else(|| panic!("Unclosed delimiter at line {}", i))]
+            .to_owned();
+        let mut input_path = PathBuf::from(trimmed_line);
+
+        if input_path.extension().is_none() {
+            let _ = input_path.set_extension("tex");
+        }
+
+        // Paths relative to the working directory only make sense for files on disk.
+        if revision.is_some() | !input_path.is_file() {
+            input_path = [filepath.parent().unwrap(), &input_path].iter().collect();
+        }
+
+        let input_lines = merge_tex_at_revision(&PathBuf::from(&input_path), revision)?;
+
+        for input_line in input_lines {
+            lines.push(input_line)
+        }
+        i += 1;
+    }
+    Ok(lines)
+}
+
 /// Read a datafile either from stdin or from disk.
 ///
 /// # Arguments
 /// * `input_str`: An input string to be parsed as a filepath or "-" to read from stdin.
+/// * `format`: Optional. The format of data from stdin. Choices: [json, toml, yaml, csv].
+///   If None, the format is guessed from the content. Files always use their extension.
 ///
 /// # Returns
 /// The parsed data file.
-pub fn get_data_from_str(input_str: &str) -> Result<Json, Box<dyn std::error::Error>> {
+pub fn get_data_from_str(
+    input_str: &str,
+    format: Option<&str>,
+) -> Result<Json, Box<dyn std::error::Error>> {
     match input_str.trim() == "-" {
-        true => read_data_from_stdin(),
-        false => read_data(&PathBuf::from(input_str)),
+        true => read_data_from_stdin(format),
+        false => read_data_with_sources(&PathBuf::from(input_str)),
     }
 }
 
 /// Read a datafile from stdin.
-fn read_data_from_stdin() -> Result<Json, Box<dyn std::error::Error>> {
+///
+/// # Arguments
+/// * `format`: Optional. The format of the data. If None, it is guessed from the content.
+fn read_data_from_stdin(format: Option<&str>) -> Result<Json, Box<dyn std::error::Error>> {
     let mut buf = String::new();
     std::io::stdin().read_to_string(&mut buf)?;
 
-    Ok(serde_json::from_str(&buf)?)
+    let mut data = match format {
+        Some(f) => parse_data_as(&buf, f)?,
+        None => parse_data_as(&buf, sniff_data_format(&buf)?)?,
+    };
+    // Included files and sources are read relative to the current directory.
+    resolve_includes(&mut data, Path::new("-"), None, true, &[])?;
+    crate::sources::resolve_sources(&mut data, Path::new(""))?;
+
+    Ok(data)
+}
+
+/// Guess the format of data from its content.
+///
+/// JSON and TOML are the strictest formats, so they are tried first. YAML is a superset of JSON
+/// but a TOML or CSV file can also be a valid YAML string, so it has to give a table to count.
+/// CSV is only guessed if the first line looks like a header, so that e.g. broken JSON is
+/// reported as broken JSON.
+///
+/// # Returns
+/// One of "json", "toml", "yaml" or "csv", or an error if the data look like none of them.
+pub fn sniff_data_format(buf: &str) -> Result<&'static str, String> {
+    let json_error = match serde_json::from_str::<Json>(buf) {
+        Ok(_) => return Ok("json"),
+        Err(e) => e,
+    };
+    let toml_error = match toml::from_str::<toml::Value>(buf) {
+        Ok(_) => return Ok("toml"),
+        Err(e) => e,
+    };
+    if let Ok(Json::Object(_)) = serde_yaml::from_str::<Json>(buf) {
+        return Ok("yaml");
+    }
+    let first_line = buf.lines().find(|l| !l.trim().is_empty()).unwrap_or("");
+    if is_csv_header(first_line) {
+        return Ok("csv");
+    }
+
+    Err(format!(
+        "Could not guess the format of the data (as {}). Give the format explicitly.",
+        match first_line.trim_start().starts_with(&['{', '['][..]) {
+            true => format!("JSON: {}", json_error),
+            false => format!("TOML: {}", toml_error),
+        }
+    ))
+}
+
+/// Check if a line looks like a CSV header, i.e. names separated by commas.
+fn is_csv_header(line: &str) -> bool {
+    !line.trim().is_empty()
+        && line.split(',').all(|name| {
+            let name = name.trim().trim_matches('"');
+            !name.is_empty()
+                && name.parse::<f64>().is_err()
+                && !name.contains(&['{', '}', '[', ']', '=', ':'][..])
+        })
 }
 
 /// Read a tex file as a vector of Strings
@@ -156,28 +336,460 @@ pub fn read_tex_from_stdin() -> Result<Vec<String>, Box<dyn std::error::Error>>
     Ok(lines)
 }
 
+/// Read a file as it was in a git revision.
+///
+/// # Arguments
+/// - `filepath`: A relative or absolute filepath inside a git repository.
+/// - `revision`: The revision to read the file from, e.g. "HEAD~1" or a commit hash.
+///
+/// # Errors
+/// Fails if git could not be run or if the file did not exist in the revision.
+pub fn read_git_file(
+    filepath: &Path,
+    revision: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Run git in the file's directory, so that the path can be given relative to it.
+    let directory = match filepath.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let filename = filepath
+        .file_name()
+        .ok_or_else(|| format!("Invalid filepath: {:?}", filepath))?
+        .to_str()
+        .unwrap();
+
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&directory)
+        .arg("show")
+        .arg(format!("{}:./{}", revision, filename))
+        .output()?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "Could not read {} at revision {}: {}",
+            filepath.to_str().unwrap(),
+            revision,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Read a tex file as a vector of Strings, either from disk or from a git revision.
+///
+/// # Arguments
+/// - `filepath`: A relative or absolute filepath.
+/// - `revision`: Optional. The git revision to read the file from. If None, read from disk.
+pub fn read_tex_at_revision(
+    filepath: &Path,
+    revision: Option<&str>,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    match revision {
+        Some(rev) => Ok(read_git_file(filepath, rev)?
+            .lines()
+            .map(|s| s.to_owned())
+            .collect()),
+        None => read_tex(filepath),
+    }
+}
+
 /// Read a json data file into an arbitrary JSON dictionary.
+///
+/// Nothing is resolved, so keys like `include`, `sources` and `figures` are kept as they are.
+/// This is used for files like the configuration and schemas.
 pub fn read_data(filepath: &Path) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let file = File::open(filepath)?;
-    let mut reader = std::io::BufReader::new(file);
+    let buf = std::fs::read_to_string(filepath)
+        .map_err(|e| format!("Could not open {}: {}", filepath.display(), e))?;
+    parse_data(&buf, filepath)
+}
 
-    let mut buf = String::new();
-    reader.read_to_string(&mut buf)?;
+/// Read the data file of a manuscript, and run the sources that it (or its includes) declare.
+pub fn read_data_with_sources(
+    filepath: &Path,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    read_data_with_includes(filepath, None, true, &[])
+}
+
+/// Read a data file as it was in a git revision.
+///
+/// Included data files are read from the same revision.
+pub fn read_data_at_revision(
+    filepath: &Path,
+    revision: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    read_data_with_includes(filepath, Some(revision), false, &[])
+}
+
+/// Read a data file from disk or from a git revision, and resolve its includes.
+///
+/// # Arguments
+/// - `filepath`: A relative or absolute filepath.
+/// - `revision`: Optional. The git revision to read the file from. If None, read from disk.
+/// - `sources`: Whether to run the sources of the files. They are never run for revisions.
+/// - `parents`: The files that (directly or indirectly) included this one, to catch cycles.
+fn read_data_with_includes(
+    filepath: &Path,
+    revision: Option<&str>,
+    sources: bool,
+    parents: &[PathBuf],
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    // Binary files are read separately and cannot include other files.
+    if let Some(format) = binary_format(filepath) {
+        if revision.is_some() {
+            return Err(format!("{} files cannot be read from git revisions.", format).into());
+        }
+        return Ok(match format {
+            "SQLite" => crate::sqlite::read_tables(filepath)?,
+            _ => crate::columnar::read_parquet(filepath, None, &serde_json::Map::new())?,
+        });
+    }
+
+    let buf = match revision {
+        Some(rev) => read_git_file(filepath, rev)?,
+        None => {
+            let file = File::open(filepath)
+                .map_err(|e| format!("Could not open {}: {}", filepath.to_str().unwrap(), e))?;
+            let mut reader = std::io::BufReader::new(file);
+
+            let mut buf = String::new();
+            reader.read_to_string(&mut buf)?;
+            buf
+        }
+    };
+
+    let mut data = parse_data(&buf, filepath)?;
+    // Included files resolve their own figure paths, so this is done before they are merged in.
+    crate::figures::resolve_paths(
+        &mut data,
+        filepath.parent().unwrap_or_else(|| Path::new("")),
+    );
+    resolve_includes(&mut data, filepath, revision, sources, parents)?;
+
+    // Sources are run in the working tree, so they are not run for other revisions.
+    if sources && revision.is_none() {
+        let directory = filepath.parent().unwrap_or_else(|| Path::new(""));
+        crate::sources::resolve_sources(&mut data, directory)?;
+    }
+
+    Ok(data)
+}
+
+/// Replace the `include` keys of a data file with the contents of the files they list.
+///
+/// An `include` at the top level merges the files into the top level, while an `include` inside a
+/// table merges them into that table. Values in the including file take precedence over included
+/// ones. Paths are relative to the directory of the including file.
+///
+/// # Examples
+/// ```toml
+/// include = ["results.json"]
+///
+/// [calibration]
+/// include = "calibration/summary.toml"
+/// ```
+fn resolve_includes(
+    data: &mut Json,
+    filepath: &Path,
+    revision: Option<&str>,
+    sources: bool,
+    parents: &[PathBuf],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let obj = match data.as_object_mut() {
+        Some(o) => o,
+        None => return Ok(()),
+    };
+
+    // Resolve the includes of nested tables first.
+    for value in obj.values_mut() {
+        resolve_includes(value, filepath, revision, sources, parents)?;
+    }
+
+    let include = match obj.remove("include") {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+    let paths = match include {
+        Json::String(_) => vec![include],
+        Json::Array(arr) => arr,
+        _ => return Err(format!("Could not understand include: {}", include).into()),
+    };
+
+    // Keep track of this file (as reliably as possible) to avoid including it in itself.
+    let identity = std::fs::canonicalize(filepath).unwrap_or_else(|_| filepath.to_path_buf());
+    if parents.contains(&identity) {
+        return Err(format!("Circular include of {}", filepath.to_str().unwrap()).into());
+    }
+    let mut parents = parents.to_vec();
+    parents.push(identity);
+
+    let directory = filepath.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut included = Json::Object(serde_json::Map::new());
+    for path_value in paths.iter() {
+        let path_str = path_value
+            .as_str()
+            .ok_or_else(|| format!("Included data file is not a path: {}", path_value))?;
+        let path = directory.join(path_str);
+
+        if path.extension().is_none() {
+            return Err(format!("Included data file has no extension: {}", path_str).into());
+        }
+        merge_data(
+            &mut included,
+            read_data_with_includes(&path, revision, sources, &parents)?,
+        );
+    }
+
+    // The values of the including file have precedence.
+    merge_data(&mut included, data.take());
+    *data = included;
+
+    Ok(())
+}
+
+/// Merge one data tree into another. Tables are merged recursively while other values are replaced.
+pub fn merge_data(base: &mut Json, other: Json) {
+    match (base, other) {
+        (Json::Object(base_obj), Json::Object(other_obj)) => {
+            for (key, value) in other_obj {
+                match base_obj.get_mut(&key) {
+                    Some(existing) => merge_data(existing, value),
+                    None => {
+                        base_obj.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+/// The prefix of environment variables that override data values.
+const ENV_PREFIX: &str = "MANUS_DATA_";
+
+/// Parse an override value as JSON (numbers, booleans, arrays, ...) if possible, or else as a string.
+fn infer_value(value: &str) -> Json {
+    serde_json::from_str(value.trim()).unwrap_or_else(|_| Json::String(value.to_owned()))
+}
+
+/// Set a value at a dotted path, e.g. "results.change". Missing tables are created on the way.
+fn set_by_path(data: &mut Json, path: &str, value: Json) -> Result<(), String> {
+    let mut current = data;
+    for key in path.split('.') {
+        if key.is_empty() {
+            return Err(format!("Invalid key in override: '{}'", path));
+        }
+        current = match current {
+            Json::Object(obj) => obj
+                .entry(key)
+                .or_insert_with(|| Json::Object(serde_json::Map::new())),
+            Json::Array(arr) => {
+                let index = key.parse::<usize>().map_err(|_| {
+                    format!(
+                        "Cannot override '{}': '{}' is not an array index.",
+                        path, key
+                    )
+                })?;
+                arr.get_mut(index).ok_or_else(|| {
+                    format!(
+                        "Cannot override '{}': Index {} is out of range.",
+                        path, index
+                    )
+                })?
+            }
+            other => {
+                return Err(format!(
+                    "Cannot override '{}': {} is not a table.",
+                    path, other
+                ))
+            }
+        };
+    }
+    *current = value;
+    Ok(())
+}
 
+/// Get data overrides from environment variables starting with "MANUS_DATA_".
+///
+/// Tables are separated by a double underscore, since dots are not allowed in variable names.
+///
+/// # Examples
+/// ```text
+/// MANUS_DATA_results__change=1.4  ->  "results.change=1.4"
+/// ```
+///
+/// # Arguments
+/// * `vars`: The environment variables as (name, value), usually `std::env::vars()`.
+///
+/// # Returns
+/// The overrides as "key=value" strings, sorted by key.
+pub fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<String> {
+    let mut overrides: Vec<String> = vars
+        .filter_map(|(name, value)| {
+            name.strip_prefix(ENV_PREFIX)
+                .filter(|key| !key.is_empty())
+                .map(|key| format!("{}={}", key.replace("__", "."), value))
+        })
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+/// Apply "key=value" overrides to data, in order.
+///
+/// The key may be dotted (e.g. "results.change") and the value is parsed as JSON if possible, so
+/// "1.4" becomes a number, "true" a boolean and "[1, 2]" an array. Other values are strings.
+///
+/// # Arguments
+/// * `data`: The data to override values in.
+/// * `overrides`: The overrides, e.g. from `--set`.
+///
+/// # Returns
+/// Nothing if it worked, or an error if an override was invalid.
+pub fn apply_overrides(data: &mut Json, overrides: &[String]) -> Result<(), String> {
+    for o in overrides {
+        let (key, value) = o
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid override '{}'. Expected 'key=value'.", o))?;
+        set_by_path(data, key.trim(), infer_value(value))?;
+    }
+    Ok(())
+}
+
+/// Find the format of a binary data file (SQLite or Parquet) from its extension.
+///
+/// # Returns
+/// The name of the format, or None if the file is not binary.
+fn binary_format(filepath: &Path) -> Option<&'static str> {
+    match filepath.extension().and_then(|e| e.to_str()) {
+        Some("sqlite") | Some("sqlite3") | Some("db") => Some("SQLite"),
+        Some("parquet") => Some("Parquet"),
+        _ => None,
+    }
+}
+
+/// Parse the contents of a data file, with the format given by its extension.
+fn parse_data(buf: &str, filepath: &Path) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
     let extension = filepath
         .extension()
         .expect("Data file read with no extension!")
         .to_str()
         .unwrap();
 
-    let data: Json = match extension {
-        "json" => serde_json::from_str(&buf)?,
-        "toml" => toml::from_str(&buf)?,
+    parse_data_as(buf, extension)
+}
+
+/// Parse data in a given format.
+///
+/// # Arguments
+/// * `buf`: The data to parse.
+/// * `format`: The format of the data. Choices: [json, toml, yaml (or yml), csv].
+pub fn parse_data_as(
+    buf: &str,
+    format: &str,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let data: Json = match format {
+        "json" => serde_json::from_str(buf)?,
+        "toml" => toml_to_json(toml::from_str(buf)?),
+        "yaml" | "yml" => serde_yaml::from_str(buf)?,
+        "csv" => csv_to_json(buf)?,
         s => return Err(format!("Could not read data type: {}", s).into()),
     };
     Ok(data)
 }
 
+/// Parse a CSV field as a number or boolean if possible, or else as a string.
+fn csv_value(field: &str) -> Json {
+    if let Ok(i) = field.parse::<i64>() {
+        return Json::from(i);
+    }
+    match (field.parse::<f64>(), field) {
+        (Ok(f), _) if f.is_finite() => Json::from(f),
+        (_, "true") => Json::Bool(true),
+        (_, "false") => Json::Bool(false),
+        _ => Json::String(field.to_owned()),
+    }
+}
+
+/// Convert CSV with a header row to JSON.
+///
+/// If the first column is called "key", each row becomes one key (dotted keys become tables).
+/// The value is the "value" column if there are two columns, or else a table of the other columns.
+/// Otherwise, each column becomes an array of its values, e.g. for statistics in expressions.
+///
+/// # Examples
+/// ```text
+/// key,value           ->  {"n_total": 20, "results": {"change": -1.5}}
+/// n_total,20
+/// results.change,-1.5
+///
+/// site,dh             ->  {"site": ["A", "B"], "dh": [1.2, 0.8]}
+/// A,1.2
+/// B,0.8
+/// ```
+fn csv_to_json(buf: &str) -> Result<Json, Box<dyn std::error::Error>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(buf.as_bytes());
+    let headers: Vec<String> = reader.headers()?.iter().map(|h| h.to_owned()).collect();
+
+    let mut data = Json::Object(serde_json::Map::new());
+    for record in reader.records() {
+        let record = record?;
+
+        if headers.first().map(|h| h.as_str()) == Some("key") {
+            let value = match headers.len() {
+                2 => csv_value(&record[1]),
+                _ => Json::Object(
+                    headers[1..]
+                        .iter()
+                        .zip(record.iter().skip(1))
+                        .map(|(header, field)| (header.to_owned(), csv_value(field)))
+                        .collect(),
+                ),
+            };
+            // Build the (possibly nested) table from the inside out and merge it in.
+            let nested = record[0]
+                .rsplit('.')
+                .fold(value, |inner, key| serde_json::json!({ key: inner }));
+            merge_data(&mut data, nested);
+        } else {
+            for (header, field) in headers.iter().zip(record.iter()) {
+                match data[header].as_array_mut() {
+                    Some(column) => column.push(csv_value(field)),
+                    None => data[header] = Json::Array(vec![csv_value(field)]),
+                }
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Convert TOML to JSON, keeping datetimes as strings that the date helpers can read.
+///
+/// Converting directly through serde would turn a datetime into an internal TOML object, e.g.
+/// `{"$__toml_private_datetime": "2020-06-01"}`.
+fn toml_to_json(value: toml::Value) -> Json {
+    match value {
+        toml::Value::String(s) => Json::String(s),
+        toml::Value::Integer(i) => Json::from(i),
+        toml::Value::Float(f) => Json::from(f),
+        toml::Value::Boolean(b) => Json::Bool(b),
+        toml::Value::Datetime(d) => Json::String(d.to_string()),
+        toml::Value::Array(arr) => Json::Array(arr.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Json::Object(
+            table
+                .into_iter()
+                .map(|(key, val)| (key, toml_to_json(val)))
+                .collect(),
+        ),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -193,4 +805,129 @@ mod tests {
 
         parse_filepath("Cargo.toml", Some("toml")).expect("This should pass");
     }
+
+    #[test]
+    fn test_toml_dates() {
+        let data = parse_data(
+            "start = 2020-06-01\nend = 2020-09-15T12:00:00Z",
+            Path::new("data.toml"),
+        )
+        .unwrap();
+
+        assert_eq!(data["start"], "2020-06-01");
+        assert_eq!(data["end"], "2020-09-15T12:00:00Z");
+    }
+
+    #[test]
+    fn test_data_formats() {
+        let json = r#"{"a": 1, "b": {"c": "text"}}"#;
+        let toml = "a = 1\n\n[b]\nc = \"text\"";
+        let yaml = "a: 1\nb:\n  c: text\n";
+        let csv = "key, value\na, 1\nb.c, text\n";
+
+        for (buf, format) in &[(json, "json"), (toml, "toml"), (yaml, "yaml"), (csv, "csv")] {
+            assert_eq!(sniff_data_format(buf), Ok(*format));
+            let data = parse_data_as(buf, format).unwrap();
+            assert_eq!(
+                data,
+                serde_json::json!({"a": 1, "b": {"c": "text"}}),
+                "{}",
+                format
+            );
+        }
+
+        let table = parse_data_as("key,dh,site\nA,1.5,true\nB,-2,false", "csv").unwrap();
+        assert_eq!(table["B"], serde_json::json!({"dh": -2, "site": false}));
+
+        let columns = parse_data_as("site,dh\nA,1.5\nB,0.5", "csv").unwrap();
+        assert_eq!(
+            columns,
+            serde_json::json!({"site": ["A", "B"], "dh": [1.5, 0.5]})
+        );
+
+        assert!(parse_data_as("a,b\n1,2,3", "csv").is_err());
+        assert!(parse_data_as("{}", "xml").is_err());
+
+        // Broken data is not read as CSV, but gives the error of the format it looks like.
+        let error = sniff_data_format("{\"a\": 1").unwrap_err();
+        assert!(error.contains("JSON"), "{}", error);
+        let error = sniff_data_format("a = 1\nb = [").unwrap_err();
+        assert!(error.contains("TOML"), "{}", error);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut data = serde_json::json!({"results": {"change": -1.5}, "dh": [1, 2]});
+        let overrides = [
+            "results.change=1.4",
+            "separator=\\,",
+            "draft=true",
+            "dh.1=5",
+            "new.nested=[1, 2]",
+            r#"label="1.0""#,
+        ];
+        apply_overrides(
+            &mut data,
+            &overrides.iter().map(|o| o.to_string()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        assert_eq!(
+            data,
+            serde_json::json!({
+                "results": {"change": 1.4},
+                "separator": "\\,",
+                "draft": true,
+                "dh": [1, 5],
+                "new": {"nested": [1, 2]},
+                "label": "1.0",
+            })
+        );
+
+        for invalid in &["results.change", "results.change.x=1", "dh.5=1", "a..b=1"] {
+            assert!(apply_overrides(&mut data, &[invalid.to_string()]).is_err());
+        }
+
+        let vars = vec![
+            ("MANUS_DATA_results__change", "2"),
+            ("MANUS_DATA_", "1"),
+            ("HOME", "/home"),
+            ("MANUS_DATA_draft", "false"),
+        ];
+        assert_eq!(
+            env_overrides(
+                vars.into_iter()
+                    .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            ),
+            vec!["draft=false", "results.change=2"]
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let data = read_data_with_sources(Path::new("tests/data/case5/data.toml")).unwrap();
+
+        // Included at the top level, but overridden by the including file.
+        assert_eq!(data["dh"], serde_json::json!([3.1, 2.9, 3.4]));
+        assert_eq!(data["label"], "Revised");
+        assert_eq!(data["results"]["instrument"], "Spectrometer");
+        assert_eq!(data["results"]["operator"], "AB");
+        // Included inside a table.
+        assert_eq!(data["calibration"]["offset"], 0.2);
+        assert!(data.get("include").is_none());
+
+        let mut cyclic = serde_json::json!({"include": ["data.toml"]});
+        resolve_includes(
+            &mut cyclic,
+            Path::new("tests/data/case5/data.toml"),
+            None,
+            false,
+            &[std::fs::canonicalize("tests/data/case5/data.toml").unwrap()],
+        )
+        .expect_err("A file should not be able to include itself");
+
+        // Configuration and schemas are read without resolving anything.
+        let plain = read_data(Path::new("tests/data/case5/data.toml")).unwrap();
+        assert!(plain.get("include").is_some());
+        assert!(plain.get("dh").is_none());
+    }
 }
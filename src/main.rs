@@ -3,13 +3,29 @@
 //! This is an early work in progress.
 //! All improvement suggestions are welcome [on
 //! GitHub](https://github.com/erikmannerfelt/manus/issues)!
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+mod acronyms;
+mod columnar;
+mod config;
+mod diff;
+mod eval;
+mod expressions;
+mod figures;
+mod functions;
 mod io;
+mod provenance;
+mod refs;
+mod schema;
+mod sources;
+mod sqlite;
+mod stats;
+mod strip;
 mod templates;
+mod units;
 
 fn main() -> std::io::Result<()> {
     match parse_cli_args() {
@@ -24,6 +40,47 @@ fn main() -> std::io::Result<()> {
     };
 }
 
+/// The arguments that change the data of a subcommand: "SET" and "DATA_FORMAT".
+///
+/// The "DATA" argument itself differs between the subcommands, so it is not included.
+fn data_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("SET")
+            .about("Override a data value, e.g. 'results.change=1.4'. Can be repeated.")
+            .long("set")
+            .takes_value(true)
+            .multiple_occurrences(true),
+        Arg::new("DATA_FORMAT")
+            .about("Format of data from stdin. Choices: [json, toml, yaml, csv]. Guessed if not given.")
+            .long("data-format")
+            .takes_value(true),
+    ]
+}
+
+/// The arguments that strip the output: "STRIP_COMMENTS", "STRIP_TODOS" and "PROFILE".
+fn strip_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new("STRIP_COMMENTS")
+            .about("Remove comments from the output.")
+            .long("strip-comments"),
+        Arg::new("STRIP_TODOS")
+            .about("Remove \\todo{} notes from the output.")
+            .long("strip-todos"),
+        Arg::new("PROFILE")
+            .about("A set of options from manus.toml. 'submission' strips comments and refuses todos.")
+            .long("profile")
+            .takes_value(true),
+    ]
+}
+
+/// The optional "SCHEMA" argument to validate the data with.
+fn schema_arg() -> Arg<'static> {
+    Arg::new("SCHEMA")
+        .about("Validate the data against a schema file (compact spec or JSON Schema).")
+        .long("schema")
+        .takes_value(true)
+}
+
 ///Handle the CLI arguments.
 ///
 /// # Returns
@@ -65,6 +122,9 @@ fn parse_cli_args() -> Result<String, String> {
                         .long("data")
                         .takes_value(true),
                 )
+                .args(data_args())
+                .arg(schema_arg())
+                .args(strip_args())
                 .arg(
                     Arg::new("KEEP_INTERMEDIATES")
                         .about("Keep intermediate files.")
@@ -94,6 +154,9 @@ fn parse_cli_args() -> Result<String, String> {
                         .long("data")
                         .takes_value(true),
                 )
+                .args(data_args())
+                .arg(schema_arg())
+                .args(strip_args())
                 .arg(
                     Arg::new("FORMAT")
                         .about("Format. Choices: [tex]. Defaults to tex.")
@@ -102,12 +165,196 @@ fn parse_cli_args() -> Result<String, String> {
                 ),
         )
         .subcommand(
-            App::new("merge").about("Merge 'input' clauses.").arg(
-                Arg::new("INPUT")
-                    .about("The input root tex file.")
-                    .required(true)
-                    .index(1),
-            ),
+            App::new("merge")
+                .about("Merge 'input' clauses.")
+                .arg(
+                    Arg::new("INPUT")
+                        .about("The input root tex file.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("FIGURES")
+                        .about("List the image files of the manuscript instead of the merged tex.")
+                        .long("figures"),
+                )
+                .arg(
+                    Arg::new("DATA")
+                        .about("Data filepath, to find the images of figure helpers with --figures.")
+                        .short('d')
+                        .long("data")
+                        .takes_value(true),
+                )
+                .args(data_args())
+                .args(strip_args()),
+        )
+        .subcommand(
+            App::new("provenance")
+                .about("List where each rendered value came from.")
+                .arg(
+                    Arg::new("INPUT")
+                        .about("The input root tex file. If '-', read from stdin.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("DATA")
+                        .about("Data filepath. If '-', read from stdin.")
+                        .short('d')
+                        .long("data")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .args(data_args())
+                .arg(
+                    Arg::new("FORMAT")
+                        .about("Format. Choices: [csv, json, md]. Defaults to csv.")
+                        .short('f')
+                        .long("format")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("diff-data")
+                .about("Show how the manuscript changes between two data files.")
+                .arg(
+                    Arg::new("OLD_DATA")
+                        .about("The old data filepath.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("NEW_DATA")
+                        .about("The new data filepath.")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("INPUT")
+                        .about("The input root tex file. If '-', read from stdin.")
+                        .required(true)
+                        .index(3),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .about("Format. Choices: [text, tex]. Defaults to text.")
+                        .short('f')
+                        .long("format")
+                        .takes_value(true),
+                )
+                .args(data_args()),
+        )
+        .subcommand(
+            App::new("revdiff")
+                .about("Render a PDF with the changes between two git revisions highlighted.")
+                .arg(
+                    Arg::new("OLD_REVISION")
+                        .about("The old git revision, e.g. 'HEAD~1' or a commit hash.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("NEW_REVISION")
+                        .about("The new git revision, e.g. 'HEAD'.")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("INPUT")
+                        .about("The input root tex file.")
+                        .required(true)
+                        .index(3),
+                )
+                .arg(
+                    Arg::new("OUTPUT")
+                        .about("The output pdf path. Defaults to '{INPUT}_changes.pdf' in the current directory.")
+                        .required(false)
+                        .index(4),
+                )
+                .arg(
+                    Arg::new("DATA")
+                        .about("Data filepath. Read from each of the revisions.")
+                        .short('d')
+                        .long("data")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("TEX")
+                        .about("Write the marked up tex to stdout instead of rendering a PDF.")
+                        .long("tex"),
+                ),
+        )
+        .subcommand(
+            App::new("eval")
+                .about("Evaluate the expressions of a data file and print the result.")
+                .arg(
+                    Arg::new("DATA")
+                        .about("Data filepath. If '-', read from stdin.")
+                        .required(true)
+                        .index(1),
+                )
+                .args(data_args())
+                .arg(
+                    Arg::new("KEY")
+                        .about("Only print the value of this key, e.g. 'results.change'.")
+                        .short('k')
+                        .long("key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("EXPLAIN")
+                        .about("Show the inputs and intermediate results of each expression.")
+                        .long("explain"),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .about("Format. Choices: [json, toml]. Defaults to json.")
+                        .short('f')
+                        .long("format")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("check")
+                .about("Validate a data file against a schema and print every violation.")
+                .arg(
+                    Arg::new("DATA")
+                        .about("Data filepath. If '-', read from stdin.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("SCHEMA")
+                        .about("The schema file (compact spec or JSON Schema).")
+                        .long("schema")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .args(data_args()),
+        )
+        .subcommand(
+            App::new("stats")
+                .about("Count the words of each section and compare them with the limits in manus.toml.")
+                .arg(
+                    Arg::new("INPUT")
+                        .about("The input root tex file. If '-', read from stdin.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("DATA")
+                        .about("Data filepath. If '-', read from stdin.")
+                        .short('d')
+                        .long("data")
+                        .takes_value(true),
+                )
+                .args(data_args())
+                .arg(
+                    Arg::new("CONFIG")
+                        .about("The configuration file with limits. Defaults to manus.toml next to the input.")
+                        .long("config")
+                        .takes_value(true),
+                ),
         )
         .get_matches();
 
@@ -131,20 +378,18 @@ fn parse_cli_args() -> Result<String, String> {
                 Err(e) => return Err(e.to_string()),
             };
 
-        // Fill the data if a data path was given.
-        if let Some(datafile) = matches.value_of("DATA") {
-            // If both the datafile and path_str was -, raise an error.
-            if (datafile.trim() == "-") & (path_str.trim() == "-") {
-                return Err("Input tex and data cannot both be from stdin.".into());
-            };
-            let data = match io::get_data_from_str(&datafile) {
-                Ok(v) => v,
-                Err(e) => return Err(e.to_string()),
-            };
+        lines = strip_lines(matches, path_str, lines)?;
 
-            lines = templates::fill_data(&lines, &data)?;
+        // Fill the data if a data path (or data overrides) was given.
+        let data = get_data(matches, path_str)?;
+        check_schema(matches, data.as_ref())?;
+        if let Some(data) = data {
+            lines = fill_lines(lines, &data)?;
         };
 
+        // Broken references are easier to find here than in the tectonic output.
+        warn_about_refs(&lines);
+
         let keep_intermediates = matches.is_present("KEEP_INTERMEDIATES");
         let synctex = matches.is_present("SYNCTEX");
 
@@ -158,7 +403,7 @@ fn parse_cli_args() -> Result<String, String> {
         }
         // Render the PDF
         match run_tectonic(
-            &lines.join("\n"),
+            &join_lines(&lines),
             &pdf_filepath,
             verbosity > 0,
             keep_intermediates,
@@ -185,22 +430,19 @@ fn parse_cli_args() -> Result<String, String> {
                 Err(e) => return Err(e.to_string()),
             };
 
-        // Fill the data if a data path was given.
-        if let Some(datafile) = matches.value_of("DATA") {
-            // If both the datafile and path_str was -, raise an error.
-            if (datafile.trim() == "-") & (path_str.trim() == "-") {
-                return Err("Input tex and data cannot both be from stdin.".into());
-            };
-            let data = match io::get_data_from_str(&datafile) {
-                Ok(v) => v,
-                Err(e) => return Err(e.to_string()),
-            };
+        lines = strip_lines(matches, path_str, lines)?;
 
-            lines = templates::fill_data(&lines, &data)?;
+        // Fill the data if a data path (or data overrides) was given.
+        let data = get_data(matches, path_str)?;
+        check_schema(matches, data.as_ref())?;
+        if let Some(data) = data {
+            lines = fill_lines(lines, &data)?;
         };
 
+        warn_about_refs(&lines);
+
         // Return the text to write to stdout.
-        return Ok(lines.join("\n"));
+        return Ok(join_lines(&lines));
     }
 
     // 'merge' subcommand parser.
@@ -217,16 +459,395 @@ fn parse_cli_args() -> Result<String, String> {
         };
 
         // Write the result to stdout if it worked or the error to stderr if it didn't.
-        match merge_tex(&filepath) {
-            Ok(lines) => return Ok(lines.join("\n")),
+        let mut lines = match io::merge_tex_with_sources(&filepath) {
+            Ok(lines) => lines,
             Err(message) => return Err(format!("{:?}", message)),
         };
+        lines = strip_lines(matches, path_str, lines)?;
+        if !matches.is_present("FIGURES") {
+            return Ok(join_lines(&lines));
+        }
+
+        // The figure helpers have to be rendered to know their images.
+        if let Some(data) = get_data(matches, path_str)? {
+            lines = fill_lines(lines, &data)?;
+        }
+        let texts: Vec<String> = lines.into_iter().map(|line| line.text).collect();
+        return Ok(figures::image_paths(&texts)
+            .into_iter()
+            .map(|path| path + "\n")
+            .collect());
+    }
+
+    // 'provenance' subcommand parser.
+    if let Some(matches) = matches.subcommand_matches("provenance") {
+        let path_str = matches
+            .value_of("INPUT")
+            .expect("It's a reqired argument so this won't fail.");
+
+        // Read the data first, so that a stdin conflict is caught before reading the tex.
+        let data = get_data(matches, path_str)?
+            .expect("The data is a required argument so this won't fail.");
+
+        // Read the lines and keep track of which file (and line) they came from.
+        let lines = match io::get_source_lines(path_str) {
+            Ok(l) => l,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let rows = provenance::trace_placeholders(&lines, &data)?;
+
+        return provenance::format_table(&rows, matches.value_of("FORMAT").unwrap_or("csv"));
+    }
+
+    // 'diff-data' subcommand parser.
+    if let Some(matches) = matches.subcommand_matches("diff-data") {
+        let path_str = matches
+            .value_of("INPUT")
+            .expect("It's a reqired argument so this won't fail.");
+
+        let lines = match io::get_source_lines(path_str) {
+            Ok(l) => l,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // Read the old and new data.
+        let mut datasets: Vec<serde_json::Value> = Vec::new();
+        for key in &["OLD_DATA", "NEW_DATA"] {
+            let datafile = matches
+                .value_of(key)
+                .expect("It's a reqired argument so this won't fail.");
+            let data = get_data_from(matches, path_str, Some(datafile))
+                .map_err(|e| format!("Could not read {}: {}", datafile, e))?
+                .expect("A data file was given, so there are data.");
+            datasets.push(data);
+        }
+
+        return diff::diff_data(
+            &lines,
+            &datasets[0],
+            &datasets[1],
+            matches.value_of("FORMAT").unwrap_or("text"),
+        );
+    }
+
+    // 'revdiff' subcommand parser.
+    if let Some(matches) = matches.subcommand_matches("revdiff") {
+        let mut filepath = PathBuf::from(
+            matches
+                .value_of("INPUT")
+                .expect("It's a reqired argument so this won't fail."),
+        );
+        if filepath.extension().is_none() {
+            filepath.set_extension("tex");
+        }
+
+        // Read, merge and fill the manuscript at both revisions.
+        let mut documents: Vec<Vec<String>> = Vec::new();
+        for key in &["OLD_REVISION", "NEW_REVISION"] {
+            let revision = matches
+                .value_of(key)
+                .expect("It's a reqired argument so this won't fail.");
+
+            let mut lines: Vec<String> = match io::merge_tex_at_revision(&filepath, Some(revision))
+            {
+                Ok(l) => l.into_iter().map(|source_line| source_line.text).collect(),
+                Err(e) => return Err(e.to_string()),
+            };
+
+            if let Some(datafile) = matches.value_of("DATA") {
+                let data = match io::read_data_at_revision(&PathBuf::from(datafile), revision) {
+                    Ok(v) => v,
+                    Err(e) => return Err(e.to_string()),
+                };
+                lines = templates::fill_data(&lines, &data)?;
+            }
+            documents.push(lines);
+        }
+
+        let marked = diff::markup_documents(&documents[0], &documents[1]);
+
+        if matches.is_present("TEX") {
+            return Ok(marked.join("\n"));
+        }
+
+        let pdf_filepath = match matches.value_of("OUTPUT") {
+            Some(x) => PathBuf::from(x),
+            None => PathBuf::from(format!(
+                "{}_changes.pdf",
+                filepath.file_stem().unwrap().to_str().unwrap()
+            )),
+        };
+
+        // Render the PDF
+        match run_tectonic(
+            &marked.join("\n"),
+            &pdf_filepath,
+            verbosity > 0,
+            false,
+            false,
+        ) {
+            Ok(_) => (),
+            Err(_) if verbosity == 0 => return Err("Tectonic exited with an error. Run the command with --verbose to find out what went wrong.".into()),
+            Err(_) => ()
+        };
+
+        return Ok("".into());
+    }
+
+    // 'eval' subcommand parser.
+    if let Some(matches) = matches.subcommand_matches("eval") {
+        let data = get_data(matches, "")?.expect("It's a reqired argument so this won't fail.");
+
+        let output = eval::evaluate_data(
+            &data,
+            matches.value_of("KEY"),
+            matches.is_present("EXPLAIN"),
+            matches.value_of("FORMAT").unwrap_or("json"),
+        )?;
+        return Ok(output + "\n");
+    }
+
+    // 'check' subcommand parser.
+    if let Some(matches) = matches.subcommand_matches("check") {
+        let data = get_data(matches, "")?.expect("It's a reqired argument so this won't fail.");
+
+        check_schema(matches, Some(&data))?;
+        return Ok("The data matches the schema.\n".into());
+    }
+
+    // 'stats' subcommand parser.
+    if let Some(matches) = matches.subcommand_matches("stats") {
+        let path_str = matches
+            .value_of("INPUT")
+            .expect("It's a reqired argument so this won't fail.");
+
+        let (mut lines, _) = match io::get_lines_and_output_path(path_str, None) {
+            Ok(x) => x,
+            Err(e) => return Err(e.to_string()),
+        };
+        if let Some(data) = get_data(matches, path_str)? {
+            lines = fill_lines(lines, &data)?;
+        };
+
+        let texts: Vec<String> = lines.into_iter().map(|line| line.text).collect();
+        let stats = stats::count(&texts);
+        let output = stats::format_stats(&stats);
+
+        let config = config::read_config(matches.value_of("CONFIG"), path_str)?;
+        let exceeded = match config.get("limits") {
+            Some(limits) => stats::check_limits(&stats, limits)?,
+            None => Vec::new(),
+        };
+        return match exceeded.is_empty() {
+            true => Ok(output),
+            false => Err(format!(
+                "{}\nLimits exceeded:\n  {}\n",
+                output,
+                exceeded.join("\n  ")
+            )),
+        };
     }
 
     // If no return statements were reached. Write an empty string to stderr.
     Err("".into())
 }
 
+/// Read the data of a subcommand, with the `--set` and `MANUS_DATA_*` overrides applied on top.
+///
+/// The sources in the manus.toml of the manuscript are run and merged in as well.
+///
+/// # Arguments
+/// * `matches`: The subcommand arguments, with the optional "DATA", "DATA_FORMAT" and "SET".
+/// * `path_str`: The input tex path, to check that the tex and data are not both from stdin.
+///
+/// # Returns
+/// The data, or None if neither a data file, `--set` nor any sources were given.
+fn get_data(matches: &ArgMatches, path_str: &str) -> Result<Option<serde_json::Value>, String> {
+    get_data_from(matches, path_str, matches.value_of("DATA"))
+}
+
+/// Read the data of a subcommand from a given data file, like `get_data` does for "DATA".
+///
+/// # Arguments
+/// * `matches`: The subcommand arguments, with the optional "DATA_FORMAT" and "SET".
+/// * `path_str`: The input tex path, to find its manus.toml and check for stdin.
+/// * `datafile`: The data filepath (or "-" for stdin), if any.
+///
+/// # Returns
+/// The data, or None if neither a data file, `--set` nor any sources were given.
+fn get_data_from(
+    matches: &ArgMatches,
+    path_str: &str,
+    datafile: Option<&str>,
+) -> Result<Option<serde_json::Value>, String> {
+    let set_values: Vec<String> = match matches.values_of("SET") {
+        Some(values) => values.map(|v| v.to_owned()).collect(),
+        None => Vec::new(),
+    };
+
+    // Only subcommands that read a manuscript use the sources of its configuration.
+    let config_sources = match path_str.is_empty() {
+        true => None,
+        false => config::read_sources(path_str)?,
+    };
+
+    let mut data = match datafile {
+        Some(datafile) => {
+            // If both the datafile and path_str was -, raise an error.
+            if (datafile.trim() == "-") & (path_str.trim() == "-") {
+                return Err("Input tex and data cannot both be from stdin.".into());
+            };
+            // The format of a file is taken from its extension.
+            if (datafile.trim() != "-") & matches.is_present("DATA_FORMAT") {
+                return Err(format!(
+                    "--data-format only works for data from stdin, not for {}",
+                    datafile
+                ));
+            }
+            match io::get_data_from_str(datafile, matches.value_of("DATA_FORMAT")) {
+                Ok(v) => v,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        None if set_values.is_empty() && config_sources.is_none() => return Ok(None),
+        None => serde_json::json!({}),
+    };
+    // Values in the data file take precedence over the sources of the configuration.
+    if let Some(mut sourced) = config_sources {
+        io::merge_data(&mut sourced, data);
+        data = sourced;
+    }
+    // Variables only change data that is already given, and are applied first, so that the
+    // command line has the last say.
+    let mut overrides = io::env_overrides(std::env::vars());
+    overrides.extend(set_values);
+    io::apply_overrides(&mut data, &overrides)?;
+
+    Ok(Some(data))
+}
+
+/// Remove comments and todo notes, as asked for with the strip options or the profile.
+///
+/// # Arguments
+/// * `matches`: The subcommand arguments, with "STRIP_COMMENTS", "STRIP_TODOS" and "PROFILE".
+/// * `path_str`: The input tex path, to find the manus.toml that profiles are read from.
+/// * `lines`: The lines of the merged manuscript.
+///
+/// # Returns
+/// The stripped lines, each with the file and line that it starts on, or an error if the profile
+/// does not allow the todos that remain.
+fn strip_lines(
+    matches: &ArgMatches,
+    path_str: &str,
+    mut lines: Vec<io::SourceLine>,
+) -> Result<Vec<io::SourceLine>, String> {
+    let profile = match matches.value_of("PROFILE") {
+        Some(name) => config::read_profile(Some(name), &config::read_config(None, path_str)?)?,
+        None => config::Profile::default(),
+    };
+    let strip_todos = matches.is_present("STRIP_TODOS") || profile.strip_todos;
+
+    let texts = |lines: &[io::SourceLine]| -> Vec<String> {
+        lines.iter().map(|line| line.text.to_owned()).collect()
+    };
+    // Each stripped line keeps the source of the line that it came from.
+    let with_sources = |lines: &[io::SourceLine], stripped: Vec<(usize, String)>| {
+        stripped
+            .into_iter()
+            .map(|(i, text)| io::SourceLine {
+                text,
+                ..lines[i].clone()
+            })
+            .collect()
+    };
+
+    if !profile.allow_todos && !strip_todos {
+        let todos = strip::find_todos(&texts(&lines));
+        if !todos.is_empty() {
+            let locations: Vec<String> = todos
+                .iter()
+                .map(|i| format!("{}:{}", lines[i - 1].path.display(), lines[i - 1].line_no))
+                .collect();
+            return Err(format!(
+                "The '{}' profile does not allow todos, but some remain:\n  {}\n",
+                matches.value_of("PROFILE").unwrap_or_default(),
+                locations.join("\n  ")
+            ));
+        }
+    }
+    if matches.is_present("STRIP_COMMENTS") || profile.strip_comments {
+        lines = with_sources(&lines, strip::strip_comments(&texts(&lines)));
+    }
+    if strip_todos {
+        lines = with_sources(&lines, strip::strip_todos(&texts(&lines))?);
+    }
+    Ok(lines)
+}
+
+/// Fill the data into the lines of a manuscript, keeping the source of each line.
+fn fill_lines(
+    lines: Vec<io::SourceLine>,
+    data: &serde_json::Value,
+) -> Result<Vec<io::SourceLine>, String> {
+    let texts: Vec<String> = lines.iter().map(|line| line.text.to_owned()).collect();
+    let filled = templates::fill_data(&texts, data)?;
+
+    Ok(lines
+        .into_iter()
+        .zip(filled)
+        .map(|(line, text)| io::SourceLine { text, ..line })
+        .collect())
+}
+
+/// Join the lines of a manuscript into one text.
+fn join_lines(lines: &[io::SourceLine]) -> String {
+    lines
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Print a warning with the file and line of each broken reference, if there are any.
+fn warn_about_refs(lines: &[io::SourceLine]) {
+    if let Err(e) = refs::check_refs(lines) {
+        std::io::stderr()
+            .write_all(format!("WARNING: {}", e).as_bytes())
+            .unwrap();
+    }
+}
+
+/// Validate data against the schema given with `--schema`, if any.
+///
+/// The expressions of the data are evaluated first, so that their results are validated.
+///
+/// # Returns
+/// Nothing if the data are valid (or no schema was given), or an error listing every violation.
+/// A schema without any data is an error, since nothing would be validated.
+fn check_schema(matches: &ArgMatches, data: Option<&serde_json::Value>) -> Result<(), String> {
+    let (schema_path, data) = match (matches.value_of("SCHEMA"), data) {
+        (Some(p), Some(data)) => (p, data),
+        (Some(_), None) => {
+            return Err(
+                "A schema was given, but no data to validate. Give the data with --data or --set."
+                    .into(),
+            )
+        }
+        (None, _) => return Ok(()),
+    };
+    let rules = schema::read_schema(Path::new(schema_path))?;
+    let data = templates::evaluate_all_expressions(data)?;
+
+    match schema::validate(&data, &rules).as_slice() {
+        [] => Ok(()),
+        violations => Err(format!(
+            "The data does not match the schema:\n  {}\n",
+            violations.join("\n  ")
+        )),
+    }
+}
+
 /// Run tectonic to generate an output file.
 fn run_tectonic(
     tex_string: &str,
@@ -319,51 +940,6 @@ fn run_tectonic(
     Ok(())
 }
 
-/// Read a tex file and recursively merge all \\input{} statements.
-///
-/// # Arguments
-/// * `filepath`: A relative or absolute path to the main.tex.
-fn merge_tex(filepath: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    // Create the output line vector
-    let mut lines: Vec<String> = Vec::new();
-
-    // Parse the lines of the main file.
-    let main_lines = io::read_tex(&filepath)?;
-
-    // Loop over the lines and handle any \input clauses.
-    let mut i = 0;
-    for line in main_lines {
-        // If it doesn't contain and input, just continue.
-        if !line.contains(r"\input{") {
-            lines.push(line);
-            i += 1;
-            continue;
-        }
-        let mut trimmed_line = line[(line.find(r"\input{").unwrap() + 7)..].to_owned();
-        trimmed_line = trimmed_line[..trimmed_line
-            .find('}')
-            .unwrap_or_else(|| panic!("Unclosed delimiter at line {}", i))]
-            .to_owned();
-        let mut input_path = PathBuf::from(trimmed_line);
-
-        if input_path.extension().is_none() {
-            let _ = input_path.set_extension("tex");
-        }
-
-        if !input_path.is_file() {
-            input_path = [filepath.parent().unwrap(), &input_path].iter().collect();
-        }
-
-        let input_lines = merge_tex(&PathBuf::from(&input_path))?;
-
-        for input_line in input_lines {
-            lines.push(input_line)
-        }
-        i += 1;
-    }
-    Ok(lines)
-}
-
 #[cfg(test)]
 mod tests {
 
@@ -373,7 +949,7 @@ mod tests {
     fn test_merge_tex() {
         let testpath = PathBuf::from("tests/data/case1/main.tex");
 
-        let lines = merge_tex(&testpath).unwrap();
+        let lines = io::merge_tex_with_sources(&testpath).unwrap();
 
         assert_eq!(lines.len(), 13);
     }
@@ -0,0 +1,527 @@
+//! Trace each rendered placeholder back to the data that it came from.
+use crate::acronyms;
+use crate::expressions::{self, get_by_path};
+use crate::io::SourceLine;
+use crate::templates;
+use crate::units;
+use serde_json::Value as Json;
+use std::collections::HashSet;
+
+/// The provenance of one rendered placeholder.
+pub struct Provenance {
+    /// The file that the placeholder was written in.
+    pub source: String,
+    /// The line number (starting at 1) in the source file.
+    pub line_no: usize,
+    /// The placeholder as written in the tex, e.g. "{{pm 1 results.change}}".
+    pub placeholder: String,
+    /// The data key paths that the placeholder refers to.
+    pub keys: Vec<String>,
+    /// The raw (unevaluated) values of the keys.
+    pub raw_values: Vec<String>,
+    /// The chain of expressions that the values were derived from.
+    pub expressions: Vec<String>,
+    /// The helpers that were applied.
+    pub helpers: Vec<String>,
+    /// The final rendered string.
+    pub rendered: String,
+}
+
+/// Find all rendered placeholders ("{{...}}") in a line.
+///
+/// Block helpers ("{{#if ...}}", "{{/if}}", "{{else}}") and comments ("{{! ...}}") are not
+/// rendered themselves, so they are skipped.
+///
+/// # Examples
+/// ```
+/// assert_eq!(find_placeholders("{{a}} and {{sep b}}"), vec!["{{a}}", "{{sep b}}"]);
+/// ```
+fn find_placeholders(line: &str) -> Vec<String> {
+    let mut placeholders: Vec<String> = Vec::new();
+
+    let mut rest = line;
+    while let Some(start) = rest.find("{{") {
+        // Triple braces ("{{{value}}}") are closed by triple braces.
+        let closing = match rest[start..].starts_with("{{{") {
+            true => "}}}",
+            false => "}}",
+        };
+        let end = match rest[start..].find(closing) {
+            Some(i) => start + i + closing.len(),
+            None => break,
+        };
+        let placeholder = &rest[start..end];
+
+        let inner = placeholder.trim_matches(&['{', '}'][..]).trim();
+        let is_block = inner.starts_with(|c| matches!(c, '#' | '/' | '!' | '>'))
+            || inner == "else"
+            || inner.starts_with("else ");
+        if !is_block {
+            placeholders.push(placeholder.to_owned());
+        }
+        rest = &rest[end..];
+    }
+
+    placeholders
+}
+
+/// Split the inside of a placeholder into tokens.
+///
+/// Parentheses become separate tokens, quoted strings are kept whole and hash arguments
+/// ("decimals=2") are reduced to their values.
+fn tokenize_placeholder(inner: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let mut token = String::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for c in inner.chars() {
+        if let Some(q) = quote {
+            token.push(c);
+            // Quotes can be escaped inside strings, e.g. "to(area, \"ha\")".
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                tokens.push(std::mem::take(&mut token));
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => {
+                token.push(c);
+                quote = Some(c);
+            }
+            '(' | ')' => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+                tokens.push(c.to_string());
+            }
+            // Drop the name of hash arguments and only keep their value.
+            '=' => token.clear(),
+            c if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Find the chain of expressions that a key is derived from.
+///
+/// # Arguments
+/// * `key`: The dotted key path to trace.
+/// * `data`: The raw (unevaluated) data.
+/// * `visited`: Keys that are already in the chain (only needed internally for recursion).
+///
+/// # Returns
+/// A vector of "key = expression" strings, starting with the key itself.
+fn expression_chain(key: &str, data: &Json, visited: &mut HashSet<String>) -> Vec<String> {
+    let mut chain: Vec<String> = Vec::new();
+
+    if !visited.insert(key.to_owned()) {
+        return chain;
+    }
+
+    let expression = match get_by_path(data, key) {
+        Some(Json::String(s)) if s.trim().starts_with("expr:") => s.replacen("expr:", "", 1),
+        _ => return chain,
+    };
+    let expression = expression.trim().to_owned();
+
+    chain.push(format!("{} = {}", key, expression));
+
+    // Follow any other expressions that this expression refers to.
+    let paths = match expressions::parse(&expression) {
+        Ok(parsed) => parsed.dependencies(),
+        Err(_) => return chain,
+    };
+    for (keys, _) in templates::find_expressions(data, None) {
+        let dependency = keys.join(".");
+        if paths
+            .iter()
+            .any(|path| expressions::refers_to(path, &dependency))
+        {
+            chain.append(&mut expression_chain(&dependency, data, visited));
+        }
+    }
+
+    chain
+}
+
+/// The markers that are put around each placeholder before a line is rendered: the start marker,
+/// the index of the placeholder, the end of the index and (after the placeholder) the end marker.
+const MARKERS: [char; 3] = ['\u{E000}', '\u{E001}', '\u{E002}'];
+
+/// Render a whole line and find what each of its placeholders rendered as.
+///
+/// The line is rendered as a whole (like `templates::fill_data` does), so that placeholders
+/// inside blocks like "{{#each}}" get the context of the block.
+///
+/// # Arguments
+/// * `reg`: The registry to render with.
+/// * `usage`: The acronyms used by `reg`, to keep their uses only if the line renders.
+/// * `line`: The line to render.
+/// * `placeholders`: The placeholders of the line, from `find_placeholders`.
+/// * `data`: The evaluated data.
+///
+/// # Returns
+/// The rendered string of each placeholder. A placeholder that is rendered several times (in a
+/// loop) gets all its results separated by "; ", and one that is not rendered (e.g. in a false
+/// "{{#if}}") an empty string. If the line fails, every placeholder gets the error, since the
+/// line is then left as it is in the output.
+fn render_placeholders(
+    reg: &handlebars::Handlebars,
+    usage: &acronyms::Usage,
+    line: &str,
+    placeholders: &[String],
+    data: &Json,
+) -> Vec<String> {
+    // Wrap each placeholder in markers that tell which placeholder it is.
+    let mut marked = String::new();
+    let mut rest = line;
+    for (i, placeholder) in placeholders.iter().enumerate() {
+        if let Some(start) = rest.find(placeholder.as_str()) {
+            let end = start + placeholder.len();
+            marked += &format!(
+                "{}{}{}{}{}{}",
+                &rest[..start],
+                MARKERS[0],
+                i,
+                MARKERS[1],
+                &rest[start..end],
+                MARKERS[2]
+            );
+            rest = &rest[end..];
+        }
+    }
+    marked += rest;
+
+    let rendered = reg.render_template(&templates::expand_inline_expressions(&marked), data);
+    usage.end_line(rendered.is_ok());
+    let output = match rendered {
+        Ok(s) => s,
+        Err(e) => {
+            let error = format!(
+                "ERROR: {}",
+                match e.as_render_error() {
+                    Some(re) => re.desc.replace(" in strict mode", ""),
+                    None => "Template render error.".into(),
+                }
+            );
+            return vec![error; placeholders.len()];
+        }
+    };
+
+    // Collect the rendered parts between the markers.
+    let mut rendered: Vec<Vec<&str>> = vec![Vec::new(); placeholders.len()];
+    for part in output.split(MARKERS[0]).skip(1) {
+        let (index, rest) = match part.split_once(MARKERS[1]) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let (Ok(i), Some((value, _))) = (index.parse::<usize>(), rest.split_once(MARKERS[2])) {
+            if let Some(values) = rendered.get_mut(i) {
+                values.push(value);
+            }
+        }
+    }
+    rendered.iter().map(|values| values.join("; ")).collect()
+}
+
+/// Trace all rendered placeholders in a merged tex document.
+///
+/// # Arguments
+/// * `lines`: The merged lines and their sources.
+/// * `data`: The raw (unevaluated) data.
+///
+/// # Returns
+/// One `Provenance` per placeholder, in the order they appear, or an error if the data
+/// expressions could not be evaluated.
+pub fn trace_placeholders(lines: &[SourceLine], data: &Json) -> Result<Vec<Provenance>, String> {
+    let parsed_data = units::quantities_as_text(&templates::evaluate_all_expressions(data)?);
+    // Acronyms are written in full the first time they are used, like in the rendered manuscript.
+    let usage = acronyms::Usage::default();
+    let reg = templates::create_registry(usage.clone());
+
+    let mut rows: Vec<Provenance> = Vec::new();
+
+    for line in lines {
+        let placeholders = find_placeholders(&line.text);
+        if placeholders.is_empty() {
+            continue;
+        }
+        let rendered_values =
+            render_placeholders(&reg, &usage, &line.text, &placeholders, &parsed_data);
+
+        for (placeholder, rendered) in placeholders.into_iter().zip(rendered_values) {
+            let expanded = templates::expand_inline_expressions(&placeholder);
+            let inner = expanded.trim_matches(&['{', '}'][..]);
+            let tokens = tokenize_placeholder(inner);
+
+            let mut keys: Vec<String> = Vec::new();
+            let mut helpers: Vec<String> = Vec::new();
+            for (i, token) in tokens.iter().enumerate() {
+                // The keys of inline expressions ("{{expr "a - b"}}") are inside the string.
+                if i > 0 && tokens[i - 1] == "expr" {
+                    let source: String = serde_json::from_str(token).unwrap_or_default();
+                    if let Ok(parsed) = expressions::parse(&source) {
+                        for key in parsed.dependencies() {
+                            if !keys.contains(&key) {
+                                keys.push(key);
+                            }
+                        }
+                    }
+                }
+                // Skip parentheses, quoted strings and literals.
+                if token == "("
+                    || token == ")"
+                    || token.starts_with(&['"', '\''][..])
+                    || token.parse::<f64>().is_ok()
+                    || matches!(token.as_str(), "true" | "false" | "null")
+                {
+                    continue;
+                }
+                // A helper is only called at the start of a placeholder or a subexpression.
+                let in_call_position = (i == 0) | (i > 0 && tokens[i - 1] == "(");
+                if in_call_position & reg.get_helper(token).is_some() {
+                    if !helpers.contains(token) {
+                        helpers.push(token.to_owned());
+                    }
+                    continue;
+                }
+                let key = token.trim_start_matches("this.").to_owned();
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+
+            // The pm helper implicitly reads the "_pm" key as well.
+            if helpers.iter().any(|h| h == "pm") {
+                for key in keys.clone() {
+                    let pm_key = key + "_pm";
+                    if get_by_path(data, &pm_key).is_some() {
+                        keys.push(pm_key);
+                    }
+                }
+            }
+
+            let raw_values = keys
+                .iter()
+                .map(|key| match get_by_path(data, key) {
+                    Some(Json::String(s)) => s.to_owned(),
+                    Some(v) => v.to_string(),
+                    None => "".into(),
+                })
+                .collect();
+
+            let mut visited: HashSet<String> = HashSet::new();
+            let expressions = keys
+                .iter()
+                .flat_map(|key| expression_chain(key, data, &mut visited))
+                .collect();
+
+            rows.push(Provenance {
+                source: line.path.display().to_string(),
+                line_no: line.line_no,
+                placeholder,
+                keys,
+                raw_values,
+                expressions,
+                helpers,
+                rendered,
+            });
+        }
+    }
+
+    Ok(rows)
+}
+
+/// The column names of a provenance table.
+const COLUMNS: [&str; 8] = [
+    "source",
+    "line",
+    "placeholder",
+    "keys",
+    "raw_values",
+    "expressions",
+    "helpers",
+    "rendered",
+];
+
+/// Convert a provenance row into its column values.
+fn row_fields(row: &Provenance) -> Vec<String> {
+    vec![
+        row.source.to_owned(),
+        row.line_no.to_string(),
+        row.placeholder.to_owned(),
+        row.keys.join("; "),
+        row.raw_values.join("; "),
+        row.expressions.join(" <- "),
+        row.helpers.join("; "),
+        row.rendered.to_owned(),
+    ]
+}
+
+/// Format provenance rows as a table.
+///
+/// # Arguments
+/// * `rows`: The rows to format.
+/// * `format`: The output format. Choices: "csv", "json" or "md" (markdown).
+///
+/// # Returns
+/// The formatted table or an error if the format was not recognized.
+pub fn format_table(rows: &[Provenance], format: &str) -> Result<String, String> {
+    match format {
+        "csv" => {
+            let quote = |field: &String| match field.contains(&[',', '"', '\n'][..]) {
+                true => format!("\"{}\"", field.replace('"', "\"\"")),
+                false => field.to_owned(),
+            };
+            let mut output = COLUMNS.join(",") + "\n";
+            for row in rows {
+                output += &row_fields(row)
+                    .iter()
+                    .map(quote)
+                    .collect::<Vec<String>>()
+                    .join(",");
+                output.push('\n');
+            }
+            Ok(output)
+        }
+        "json" => {
+            let table: Vec<Json> = rows
+                .iter()
+                .map(|row| {
+                    serde_json::json!({
+                        "source": row.source,
+                        "line": row.line_no,
+                        "placeholder": row.placeholder,
+                        "keys": row.keys,
+                        "raw_values": row.raw_values,
+                        "expressions": row.expressions,
+                        "helpers": row.helpers,
+                        "rendered": row.rendered,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&table).map_err(|e| e.to_string())
+        }
+        "md" | "markdown" => {
+            let mut output = format!("| {} |\n", COLUMNS.join(" | "));
+            output += &format!("|{}\n", "---|".repeat(COLUMNS.len()));
+            for row in rows {
+                let fields: Vec<String> = row_fields(row)
+                    .iter()
+                    .map(|field| format!("`{}`", field.replace('|', "\\|")))
+                    .collect();
+                output += &format!("| {} |\n", fields.join(" | "));
+            }
+            Ok(output)
+        }
+        s => Err(format!(
+            "Unknown provenance format: {}. Choices: [csv, json, md]",
+            s
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_trace_placeholders() {
+        assert_eq!(
+            find_placeholders("{{#if x}}{{a}}{{else}}{{sep (pm b)}}{{/if}} {{! comment}}"),
+            vec!["{{a}}", "{{sep (pm b)}}"]
+        );
+        assert_eq!(
+            tokenize_placeholder("sep (pm 1 results.change) x=\"a b\""),
+            vec!["sep", "(", "pm", "1", "results.change", ")", "\"a b\""]
+        );
+        assert_eq!(
+            tokenize_placeholder("expr \"to(a, \\\"ha\\\")\""),
+            vec!["expr", "\"to(a, \\\"ha\\\")\""]
+        );
+
+        let lines = SourceLine::numbered(
+            "main.tex",
+            &[
+                "We ate {{n_eaten}} of the snacks.",
+                "{{sep n_remaining}} remain ({{pm 1 remaining_percentage}}).",
+                "{{= n_total - n_remaining}} were eaten.",
+                "{{#each samples}}{{dh}};{{/each}} {{#if false}}{{n_total}}{{/if}}",
+                "A {{acr \"DEM\"}}, the same {{acr \"DEM\"}}.",
+            ],
+        );
+
+        let data = serde_json::json!({
+            "separator": ",",
+            "n_total": 20420,
+            "n_eaten": 1567,
+            "n_remaining": "expr: n_total - n_eaten",
+            "remaining_percentage": "expr: 100 * n_remaining / n_total",
+            "remaining_percentage_pm": 0.25,
+            "samples": [{"dh": 1.5}, {"dh": 2}],
+            "acronyms": {"DEM": "Digital Elevation Model"},
+        });
+
+        let rows = trace_placeholders(&lines, &data).unwrap();
+
+        assert_eq!(rows.len(), 8);
+
+        assert_eq!(rows[0].keys, vec!["n_eaten"]);
+        assert!(rows[0].helpers.is_empty());
+        assert_eq!(rows[0].rendered, "1567");
+
+        assert_eq!(rows[1].line_no, 2);
+        assert_eq!(rows[1].helpers, vec!["sep"]);
+        assert_eq!(rows[1].raw_values, vec!["expr: n_total - n_eaten"]);
+        assert_eq!(rows[1].rendered, "18,853");
+
+        assert_eq!(
+            rows[2].keys,
+            vec!["remaining_percentage", "remaining_percentage_pm"]
+        );
+        assert_eq!(
+            rows[2].expressions,
+            vec![
+                "remaining_percentage = 100 * n_remaining / n_total",
+                "n_remaining = n_total - n_eaten"
+            ]
+        );
+        assert_eq!(rows[2].rendered, "92.3$\\pm$0.3");
+
+        assert_eq!(rows[3].keys, vec!["n_total", "n_remaining"]);
+        assert_eq!(rows[3].helpers, vec!["expr"]);
+        assert_eq!(rows[3].expressions, vec!["n_remaining = n_total - n_eaten"]);
+        assert_eq!(rows[3].rendered, "1567");
+
+        // Placeholders are rendered in the context of their blocks, and not at all if skipped.
+        assert_eq!(rows[4].rendered, "1.5; 2");
+        assert_eq!(rows[5].rendered, "");
+        // Acronyms are only written in full the first time.
+        assert_eq!(rows[6].rendered, "Digital Elevation Model (DEM)");
+        assert_eq!(rows[7].rendered, "DEM");
+
+        let csv = format_table(&rows, "csv").unwrap();
+        assert!(csv.starts_with("source,line,placeholder"));
+        assert!(csv.contains("main.tex,2,{{sep n_remaining}},n_remaining"));
+        assert!(format_table(&rows, "md")
+            .unwrap()
+            .contains("| `main.tex` | `1` |"));
+        assert!(format_table(&rows, "xml").is_err());
+    }
+}
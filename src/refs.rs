@@ -0,0 +1,233 @@
+//! Cross-references to figures and tables declared in the data, and checks for broken references.
+//!
+//! Figures and tables are referred to by their id in the data, e.g. `{{cref "map"}}` for
+//! `[figures.map]`, so their labels only have to be written in one place:
+//! ```toml
+//! [figures.map]
+//! path = "figures/map.pdf"  # See the `figures` module.
+//!
+//! [tables.results]
+//! label = "tab:results"  # Optional. Defaults to "tab:<id>".
+//! ```
+use crate::io::SourceLine;
+use crate::strip::comment_start;
+use serde_json::Value as Json;
+
+/// The data tables that ids are declared in, with their label prefix and name.
+const KINDS: [(&str, &str, &str); 2] = [("figures", "fig", "Figure"), ("tables", "tab", "Table")];
+
+/// The names of labels with common prefixes that are not declared in the data.
+const PREFIX_NAMES: [(&str, &str); 5] = [
+    ("fig", "Figure"),
+    ("tab", "Table"),
+    ("sec", "Section"),
+    ("eq", "Equation"),
+    ("app", "Appendix"),
+];
+
+/// The commands that refer to a label.
+const REF_COMMANDS: [&str; 6] = ["ref", "cref", "Cref", "autoref", "eqref", "pageref"];
+
+/// Find the label of an id and the name of what it refers to.
+///
+/// # Arguments
+/// * `data`: The data that figures and tables are declared in.
+/// * `id`: The id of a figure or table, or a label like "sec:methods".
+///
+/// # Returns
+/// The label and the name of its kind (e.g. "Figure"), or an error if the id is unknown.
+pub fn resolve_label(data: &Json, id: &str) -> Result<(String, Option<&'static str>), String> {
+    for (table, prefix, name) in KINDS.iter() {
+        if let Some(declared) = data.get(table).and_then(|t| t.get(id)) {
+            let label = match declared.get("label").and_then(|l| l.as_str()) {
+                Some(l) => l.to_owned(),
+                None => format!("{}:{}", prefix, id),
+            };
+            return Ok((label, Some(name)));
+        }
+    }
+    // Labels that are written by hand, like "sec:methods", are used as they are.
+    match id.split_once(':') {
+        Some((prefix, _)) => Ok((
+            id.to_owned(),
+            PREFIX_NAMES
+                .iter()
+                .find(|(p, _)| *p == prefix)
+                .map(|(_, name)| *name),
+        )),
+        None => Err(format!(
+            "'{}' is not a figure or table in the data, or a label like 'sec:{}'",
+            id, id
+        )),
+    }
+}
+
+/// Read the id argument of a reference helper and resolve its label.
+fn helper_label(
+    h: &handlebars::Helper,
+    context: &handlebars::Context,
+    helper_name: &str,
+) -> Result<(String, Option<&'static str>), handlebars::RenderError> {
+    let id = h.param(0).and_then(|p| p.value().as_str()).ok_or_else(|| {
+        handlebars::RenderError::new(format!(
+            "{}: The id to refer to is required, e.g. {{{{{} \"map\"}}}}",
+            helper_name, helper_name
+        ))
+    })?;
+    resolve_label(context.data(), id)
+        .map_err(|e| handlebars::RenderError::new(format!("{}: {}", helper_name, e)))
+}
+
+/// Helper to refer to a figure or table by its id in the data.
+///
+/// Given the data:
+/// ```
+/// {
+///     "tables": {"results": {"label": "tab:main_results"}}
+/// }
+/// ```
+/// the helper "{{ref "results"}}" will render as "`\ref{tab:main_results}`".
+pub fn ref_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    context: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let (label, _) = helper_label(h, context, "ref")?;
+    out.write(&format!("\\ref{{{}}}", label))?;
+    Ok(())
+}
+
+/// Helper to refer to a figure or table by its id, with its name in front.
+///
+/// Given the same data as `ref_helper`, "{{cref "results"}}" will render as
+/// "`Table~\ref{tab:main_results}`". The name can be changed with `name=`, e.g. `name="Tab."`.
+pub fn cref_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    context: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let (label, kind_name) = helper_label(h, context, "cref")?;
+    let name = match h.hash_get("name").and_then(|n| n.value().as_str()) {
+        Some(n) => n,
+        None => kind_name.ok_or_else(|| {
+            handlebars::RenderError::new(format!(
+                "cref: Unknown kind of label '{}'. Give its name with name=\"...\"",
+                label
+            ))
+        })?,
+    };
+    out.write(&format!("{}~\\ref{{{}}}", name, label))?;
+    Ok(())
+}
+
+/// Find the arguments of a command in a line, e.g. "tab:a" in "\ref{tab:a}".
+///
+/// Arguments with commas, like "\cref{fig:a,fig:b}", are split.
+fn command_arguments<'a>(line: &'a str, command: &str) -> Vec<&'a str> {
+    let pattern = format!("\\{}{{", command);
+    line.match_indices(&pattern)
+        .filter_map(|(i, _)| line[i + pattern.len()..].split('}').next())
+        .flat_map(|arguments| arguments.split(','))
+        .map(|argument| argument.trim())
+        .collect()
+}
+
+/// Find references to labels that are not defined anywhere in the manuscript.
+///
+/// # Arguments
+/// * `lines`: The lines of the merged manuscript, with the data filled in.
+///
+/// # Returns
+/// Nothing if all references are defined, or an error with the file, line and label of each
+/// broken one.
+pub fn check_refs(lines: &[SourceLine]) -> Result<(), String> {
+    // Comments are not part of the manuscript. Helpers like `figure` write several lines in one.
+    let code: Vec<(&SourceLine, &str)> = lines
+        .iter()
+        .flat_map(|line| line.text.split('\n').map(move |text| (line, text)))
+        .map(|(line, text)| (line, &text[..comment_start(text).unwrap_or(text.len())]))
+        .collect();
+
+    let labels: Vec<&str> = code
+        .iter()
+        .flat_map(|(_, line)| command_arguments(line, "label"))
+        .collect();
+
+    let mut broken: Vec<String> = Vec::new();
+    for (source, line) in code.iter() {
+        for command in REF_COMMANDS.iter() {
+            for label in command_arguments(line, command) {
+                if !labels.contains(&label) {
+                    broken.push(format!(
+                        "{}:{}: \\{}{{{}}}",
+                        source.path.display(),
+                        source.line_no,
+                        command,
+                        label
+                    ));
+                }
+            }
+        }
+    }
+
+    match broken.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "Undefined references:\n  {}\n",
+            broken.join("\n  ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::templates::fill_data;
+
+    #[test]
+    fn test_refs() {
+        let data = serde_json::json!({
+            "tables": {"results": {"label": "tab:main_results"}, "sites": {}},
+        });
+        let lines = fill_data(
+            &[
+                "(Table~{{ref \"results\"}})".into(),
+                "{{cref \"sites\"}} and {{cref \"sec:methods\"}}".into(),
+                "{{cref \"sites\" name=\"Tab.\"}}".into(),
+                "{{ref \"missing\"}}".into(),
+                "{{cref \"x:y\"}}".into(),
+            ],
+            &data,
+        )
+        .unwrap();
+        assert_eq!(lines[0], "(Table~\\ref{tab:main_results})");
+        assert_eq!(
+            lines[1],
+            "Table~\\ref{tab:sites} and Section~\\ref{sec:methods}"
+        );
+        assert_eq!(lines[2], "Tab.~\\ref{tab:sites}");
+        // Unknown ids and kinds are not rendered.
+        assert_eq!(lines[3], "{{ref \"missing\"}}");
+        assert_eq!(lines[4], "{{cref \"x:y\"}}");
+
+        let manuscript = SourceLine::numbered(
+            "methods.tex",
+            &[
+                "\\section{Methods}\\label{sec:methods}",
+                "See \\cref{sec:methods, tab:a} and \\ref{fig:b}. % \\ref{fig:c}",
+                "A 5\\% change (\\eqref{eq:d}). A line break\\\\% \\ref{fig:e}",
+                "\\label{tab:a}",
+            ],
+        );
+        assert_eq!(
+            check_refs(&manuscript).unwrap_err(),
+            "Undefined references:\n  methods.tex:2: \\ref{fig:b}\n  methods.tex:3: \\eqref{eq:d}\n"
+        );
+        assert!(check_refs(&manuscript[..1]).is_ok());
+    }
+}
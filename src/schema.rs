@@ -0,0 +1,377 @@
+//! Validate data against a schema, to find typos and wrong values before they reach the manuscript.
+//!
+//! A schema is either a compact spec with one table per key (TOML, JSON or YAML):
+//! ```toml
+//! [n_total]
+//! type = "integer"
+//! min = 0
+//!
+//! [results.change]
+//! type = "number"
+//! pm = true  # A "results.change_pm" error has to exist.
+//!
+//! [volume]
+//! unit = "km^3"  # The value has to be a quantity that can be converted to km^3.
+//! max = 10  # Limits are in the unit of the schema.
+//!
+//! [label]
+//! allowed = ["Draft", "Final"]
+//! required = false  # Keys are required unless stated otherwise.
+//! ```
+//! or a JSON Schema, of which "properties", "required", "type", "minimum", "maximum" and "enum"
+//! are used (together with the non-standard "unit" and "pm").
+use crate::expressions::get_by_path;
+use crate::units::Quantity;
+use serde_json::Value as Json;
+use std::path::Path;
+
+/// The fields of a rule in the compact spec. A table with none of these is a group of rules.
+const RULE_FIELDS: [&str; 7] = ["type", "required", "min", "max", "pm", "unit", "allowed"];
+
+/// The types that a value can be declared as.
+const TYPES: [&str; 6] = ["number", "integer", "string", "boolean", "array", "table"];
+
+/// The rule of one key.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rule {
+    pub key: String,
+    pub kind: Option<String>,
+    pub required: bool,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub pm: bool,
+    pub unit: Option<String>,
+    pub allowed: Option<Vec<Json>>,
+    /// The key of the table that the key is in, if it is only required when that table exists.
+    pub parent: Option<String>,
+}
+
+impl Rule {
+    /// Read a rule from its table in the compact spec (or its JSON Schema property).
+    ///
+    /// # Arguments
+    /// * `key`: The dotted key path that the rule applies to.
+    /// * `spec`: The table of the rule.
+    /// * `required`: Whether the key is required if the table does not say.
+    fn parse(key: &str, spec: &Json, required: bool) -> Result<Rule, String> {
+        let number = |names: &[&str]| -> Result<Option<f64>, String> {
+            match names.iter().find_map(|name| spec.get(name)) {
+                Some(v) => v
+                    .as_f64()
+                    .map(Some)
+                    .ok_or_else(|| format!("Invalid limit for '{}' in the schema: {}", key, v)),
+                None => Ok(None),
+            }
+        };
+
+        let kind = match spec.get("type") {
+            Some(Json::String(t)) if t == "object" => Some("table".to_owned()),
+            Some(Json::String(t)) if TYPES.contains(&t.as_str()) => Some(t.to_owned()),
+            Some(t) => {
+                return Err(format!(
+                    "Invalid type for '{}' in the schema: {}. Choices: [{}]",
+                    key,
+                    t,
+                    TYPES.join(", ")
+                ))
+            }
+            None => None,
+        };
+        let allowed = match spec.get("allowed").or_else(|| spec.get("enum")) {
+            Some(Json::Array(values)) => Some(values.to_owned()),
+            Some(v) => {
+                return Err(format!(
+                    "The allowed values of '{}' in the schema is not an array: {}",
+                    key, v
+                ))
+            }
+            None => None,
+        };
+
+        Ok(Rule {
+            key: key.to_owned(),
+            kind,
+            required: match spec.get("required") {
+                Some(Json::Bool(r)) => *r,
+                _ => required,
+            },
+            min: number(&["min", "minimum"])?,
+            max: number(&["max", "maximum"])?,
+            pm: spec.get("pm").and_then(|p| p.as_bool()).unwrap_or(false),
+            unit: spec
+                .get("unit")
+                .and_then(|u| u.as_str())
+                .map(|u| u.to_owned()),
+            allowed,
+            parent: None,
+        })
+    }
+
+    /// Check a value (or its absence) against the rule.
+    ///
+    /// # Arguments
+    /// * `data`: The evaluated data.
+    ///
+    /// # Returns
+    /// A description of each violation.
+    fn check(&self, data: &Json) -> Vec<String> {
+        let value = match get_by_path(data, &self.key) {
+            Some(v) => v,
+            None if !self.required => return Vec::new(),
+            // A missing table is reported by its own rule (if it is required).
+            None => match &self.parent {
+                Some(parent) if get_by_path(data, parent).is_none() => return Vec::new(),
+                _ => return vec!["Required key not found.".into()],
+            },
+        };
+        let mut violations: Vec<String> = Vec::new();
+
+        // Quantities are converted to the unit of the schema, and then checked as numbers.
+        let number = match &self.unit {
+            Some(unit) => match Quantity::from_json(value).map(|q| q.convert(unit)) {
+                Some(Ok(q)) => Some(Json::from(q.value)),
+                Some(Err(e)) => return vec![e],
+                None => return vec![format!("Expected a quantity in {}, found {}", unit, value)],
+            },
+            None => None,
+        };
+        let value = number.as_ref().unwrap_or(value);
+
+        if let Some(kind) = &self.kind {
+            let matches = match kind.as_str() {
+                "number" => value.is_number(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "string" => value.is_string(),
+                "boolean" => value.is_boolean(),
+                "array" => value.is_array(),
+                _ => value.is_object(),
+            };
+            if !matches {
+                violations.push(format!(
+                    "Expected {} {}, found {}",
+                    article(kind),
+                    kind,
+                    value
+                ));
+            }
+        }
+        if let Some(number) = value.as_f64() {
+            if let Some(min) = self.min.filter(|min| number < *min) {
+                violations.push(format!("{} is smaller than the minimum ({})", number, min));
+            }
+            if let Some(max) = self.max.filter(|max| number > *max) {
+                violations.push(format!("{} is larger than the maximum ({})", number, max));
+            }
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(value) {
+                let allowed: Vec<String> = allowed.iter().map(|v| v.to_string()).collect();
+                violations.push(format!("{} is not one of: {}", value, allowed.join(", ")));
+            }
+        }
+        if self.pm {
+            let pm_key = self.key.to_owned() + "_pm";
+            match get_by_path(data, &pm_key) {
+                Some(pm) if pm.as_f64().map(|p| p >= 0.0).unwrap_or(false) => (),
+                Some(pm) if Quantity::from_json(pm).is_some() => (),
+                Some(pm) => violations.push(format!(
+                    "The error '{}' is not a positive number: {}",
+                    pm_key, pm
+                )),
+                None => violations.push(format!("The error '{}' was not found.", pm_key)),
+            }
+        }
+
+        violations
+    }
+}
+
+/// "a" or "an", depending on the word that follows.
+fn article(word: &str) -> &'static str {
+    match word.starts_with(|c| "aeiou".contains(c)) {
+        true => "an",
+        false => "a",
+    }
+}
+
+/// Read the rules of a compact spec, recursing into tables that are groups of rules.
+fn compact_rules(spec: &Json, parent: &str, rules: &mut Vec<Rule>) -> Result<(), String> {
+    let table = match spec {
+        Json::Object(obj) => obj,
+        v => return Err(format!("Invalid schema for '{}': {}", parent, v)),
+    };
+    for (name, value) in table {
+        let key = match parent.is_empty() {
+            true => name.to_owned(),
+            false => format!("{}.{}", parent, name),
+        };
+        match value {
+            Json::Object(obj) if RULE_FIELDS.iter().any(|f| obj.contains_key(*f)) => {
+                rules.push(Rule::parse(&key, value, true)?)
+            }
+            _ => compact_rules(value, &key, rules)?,
+        }
+    }
+    Ok(())
+}
+
+/// Read the rules of a JSON Schema, recursing into the properties of objects.
+fn json_schema_rules(schema: &Json, parent: &str, rules: &mut Vec<Rule>) -> Result<(), String> {
+    let properties = match schema.get("properties") {
+        Some(Json::Object(p)) => p,
+        Some(p) => return Err(format!("Invalid properties for '{}': {}", parent, p)),
+        None => return Ok(()),
+    };
+    let required_keys = schema
+        .get("required")
+        .and_then(|r| r.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    for (name, property) in properties {
+        let key = match parent.is_empty() {
+            true => name.to_owned(),
+            false => format!("{}.{}", parent, name),
+        };
+        // A key is only required if the object that it is in exists, which is only known when
+        // the data are checked.
+        let mut rule = Rule::parse(&key, property, false)?;
+        rule.required = required_keys.contains(&Json::String(name.to_owned()));
+        rule.parent = Some(parent.to_owned()).filter(|p| !p.is_empty());
+        rules.push(rule);
+        json_schema_rules(property, &key, rules)?;
+    }
+    Ok(())
+}
+
+/// Read the rules of a schema, either a compact spec or a JSON Schema.
+///
+/// # Arguments
+/// * `schema`: The parsed schema file.
+///
+/// # Returns
+/// The rules of each key, or an error if the schema is invalid.
+pub fn parse_schema(schema: &Json) -> Result<Vec<Rule>, String> {
+    let mut rules: Vec<Rule> = Vec::new();
+    match schema.get("$schema").is_some() || schema.get("properties").is_some() {
+        true => json_schema_rules(schema, "", &mut rules)?,
+        false => compact_rules(schema, "", &mut rules)?,
+    };
+    Ok(rules)
+}
+
+/// Read a schema file. The format is given by its extension.
+pub fn read_schema(filepath: &Path) -> Result<Vec<Rule>, String> {
+    let schema = crate::io::read_data(filepath)
+        .map_err(|e| format!("Could not read schema {}: {}", filepath.display(), e))?;
+    parse_schema(&schema)
+}
+
+/// Validate data against the rules of a schema.
+///
+/// # Arguments
+/// * `data`: The data, with its expressions evaluated.
+/// * `rules`: The rules to check.
+///
+/// # Returns
+/// Every violation, as "key: description", in the order of the rules (sorted by key).
+pub fn validate(data: &Json, rules: &[Rule]) -> Vec<String> {
+    rules
+        .iter()
+        .flat_map(|rule| {
+            rule.check(data)
+                .into_iter()
+                .map(move |v| format!("{}: {}", rule.key, v))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let schema = serde_json::json!({
+            "n_total": {"type": "integer", "min": 0},
+            "results": {
+                "change": {"type": "number", "pm": true},
+                "label": {"allowed": ["Draft", "Final"], "required": false},
+            },
+            "volume": {"unit": "km^3", "max": 10},
+            "missing": {"type": "string"},
+        });
+        let rules = parse_schema(&schema).unwrap();
+        assert_eq!(rules.len(), 5);
+
+        let data = serde_json::json!({
+            "n_total": -2.5,
+            "results": {"change": "1.4"},
+            "volume": "2e10 m^3",
+        });
+        assert_eq!(
+            validate(&data, &rules),
+            vec![
+                "missing: Required key not found.",
+                "n_total: Expected an integer, found -2.5",
+                "n_total: -2.5 is smaller than the minimum (0)",
+                "results.change: Expected a number, found \"1.4\"",
+                "results.change: The error 'results.change_pm' was not found.",
+                "volume: 20 is larger than the maximum (10)",
+            ]
+        );
+
+        let data = serde_json::json!({
+            "n_total": 20,
+            "results": {"change": 1.4, "change_pm": 0.2, "label": "Final"},
+            "volume": {"value": 2, "unit": "km^3"},
+            "missing": "here",
+        });
+        assert!(validate(&data, &rules).is_empty());
+
+        // The same rules as a JSON Schema.
+        let json_schema = serde_json::json!({
+            "type": "object",
+            "required": ["n_total", "results"],
+            "properties": {
+                "n_total": {"type": "integer", "minimum": 0},
+                "results": {
+                    "type": "object",
+                    "required": ["change"],
+                    "properties": {"change": {"type": "number", "pm": true}},
+                },
+                "label": {"enum": ["Draft", "Final"]},
+            },
+        });
+        let rules = parse_schema(&json_schema).unwrap();
+        assert!(validate(&data, &rules).is_empty());
+        assert_eq!(
+            validate(&serde_json::json!({"label": "Other"}), &rules),
+            vec![
+                "label: \"Other\" is not one of: \"Draft\", \"Final\"",
+                "n_total: Required key not found.",
+                "results: Required key not found.",
+            ]
+        );
+
+        // The required keys of an optional object are checked if the object exists.
+        let json_schema = serde_json::json!({
+            "properties": {
+                "model": {
+                    "type": "object",
+                    "required": ["name"],
+                    "properties": {"name": {"type": "string"}, "version": {"type": "string"}},
+                },
+            },
+        });
+        let rules = parse_schema(&json_schema).unwrap();
+        assert!(validate(&serde_json::json!({}), &rules).is_empty());
+        assert_eq!(
+            validate(&serde_json::json!({"model": {"version": "1"}}), &rules),
+            vec!["model.name: Required key not found."]
+        );
+
+        assert!(parse_schema(&serde_json::json!({"x": {"type": "float"}})).is_err());
+    }
+}
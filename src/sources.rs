@@ -0,0 +1,336 @@
+//! Data from external commands, declared as sources in a data file.
+//!
+//! A source is run in the directory of the data file, and its output is put under its name:
+//! ```toml
+//! [sources.stats]
+//! cmd = "python stats.py"
+//! format = "json"  # Optional. Guessed from the output if not given.
+//! inputs = ["measurements.csv"]  # Optional. Files that the output depends on.
+//! ```
+//! The output is cached in a `.manus-cache` directory, and the command is only run again if the
+//! command, its script, its format or its inputs have changed.
+//!
+//! A source can also be an SQLite database, with named queries:
+//! ```toml
+//! [sources.db]
+//! sqlite = "results.sqlite"
+//!
+//! [sources.db.queries]
+//! sites = "SELECT * FROM sites"
+//! n_sites = {sql = "SELECT COUNT(*) FROM sites", scalar = true}
+//! ```
+//! Without queries, all tables of the database are read.
+//!
+//! Parquet files are read with `parquet = "output.parquet"`, see the `columnar` module.
+use crate::columnar;
+use crate::io;
+use crate::sqlite;
+use serde_json::Value as Json;
+use std::path::Path;
+
+/// The name of the cache directory, which is created next to the data file.
+const CACHE_DIRECTORY: &str = ".manus-cache";
+
+/// Calculate a hash of a source, to know when its cached output is stale.
+///
+/// The hash covers the command itself, the contents of any file named in the command (e.g. the
+/// script in "python stats.py"), the format that the output is parsed as (since the cache holds
+/// the parsed data) and the listed input files with their contents.
+///
+/// # Arguments
+/// * `cmd`: The command to run.
+/// * `format`: The format of the output, if given.
+/// * `inputs`: Paths to the files that the output depends on.
+/// * `directory`: The directory that the command is run in.
+fn source_hash(
+    cmd: &str,
+    format: Option<&str>,
+    inputs: &[&str],
+    directory: &Path,
+) -> Result<String, String> {
+    let mut hasher = StableHasher::new();
+    hasher.write(cmd.as_bytes());
+    hasher.write(format.unwrap_or_default().as_bytes());
+
+    for word in cmd.split_whitespace() {
+        if let Ok(content) = std::fs::read(directory.join(word)) {
+            hasher.write(&content);
+        }
+    }
+    for input in inputs {
+        let content = std::fs::read(directory.join(input))
+            .map_err(|e| format!("Could not read source input '{}': {}", input, e))?;
+        hasher.write(input.as_bytes());
+        hasher.write(&content);
+    }
+
+    Ok(format!("{:016x}", hasher.0))
+}
+
+/// A 64-bit FNV-1a hash.
+///
+/// Unlike `DefaultHasher`, it stays the same across Rust releases, so the cache is kept when the
+/// toolchain is upgraded.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Add bytes to the hash. The length goes first, so that e.g. "ab" + "c" differs from "a" + "bc".
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Run a command in a shell and return its stdout.
+fn run_command(cmd: &str, directory: &Path) -> Result<String, String> {
+    let mut command = match cfg!(windows) {
+        true => std::process::Command::new("cmd"),
+        false => std::process::Command::new("sh"),
+    };
+    let output = command
+        .arg(if cfg!(windows) { "/C" } else { "-c" })
+        .arg(cmd)
+        .current_dir(directory)
+        .output()
+        .map_err(|e| format!("Could not run '{}': {}", cmd, e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "'{}' exited with {}: {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|e| format!("'{}' gave invalid text: {}", cmd, e))
+}
+
+/// Get the data of one source, either from the cache or by running its command.
+///
+/// # Arguments
+/// * `name`: The name of the source.
+/// * `source`: The source table, with a "cmd" and optionally a "format" and "inputs", an
+///   "sqlite" path and optionally "queries", or a "parquet" path and optionally "columns" and
+///   "aggregates".
+/// * `directory`: The directory of the data file, which the command is run in.
+fn read_source(name: &str, source: &Json, directory: &Path) -> Result<Json, String> {
+    // Databases are quick to query, so they are not cached.
+    if let Some(database) = source.get("sqlite") {
+        let path = directory.join(
+            database
+                .as_str()
+                .ok_or_else(|| format!("Source '{}' has an invalid 'sqlite' path.", name))?,
+        );
+        return match source.get("queries") {
+            Some(Json::Object(queries)) => sqlite::run_queries(&path, queries),
+            Some(q) => Err(format!(
+                "Could not understand the queries of '{}': {}",
+                name, q
+            )),
+            None => sqlite::read_tables(&path),
+        }
+        .map_err(|e| format!("Error in source '{}': {}", name, e));
+    }
+
+    if let Some(parquet) = source.get("parquet") {
+        let path = directory.join(
+            parquet
+                .as_str()
+                .ok_or_else(|| format!("Source '{}' has an invalid 'parquet' path.", name))?,
+        );
+        let columns: Option<Vec<String>> = source.get("columns").map(|c| {
+            c.as_array()
+                .map(|arr| {
+                    arr.iter()
+                        .filter_map(|v| v.as_str())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+        let no_aggregates = serde_json::Map::new();
+        let aggregates = match source.get("aggregates") {
+            Some(Json::Object(a)) => a,
+            Some(a) => {
+                return Err(format!(
+                    "Could not understand the aggregates of '{}': {}",
+                    name, a
+                ))
+            }
+            None => &no_aggregates,
+        };
+        return columnar::read_parquet(&path, columns.as_deref(), aggregates)
+            .map_err(|e| format!("Error in source '{}': {}", name, e));
+    }
+
+    let cmd = source
+        .get("cmd")
+        .and_then(|c| c.as_str())
+        .ok_or_else(|| format!("Source '{}' has no 'cmd', 'sqlite' or 'parquet' key.", name))?;
+    let format = source.get("format").and_then(|f| f.as_str());
+    let inputs: Vec<&str> = match source.get("inputs") {
+        Some(Json::Array(arr)) => arr.iter().filter_map(|v| v.as_str()).collect(),
+        Some(Json::String(s)) => vec![s.as_str()],
+        _ => Vec::new(),
+    };
+
+    let hash = source_hash(cmd, format, &inputs, directory)?;
+    let cache_path = directory
+        .join(CACHE_DIRECTORY)
+        .join(format!("{}.json", name));
+
+    // Use the cached output if nothing has changed since it was made.
+    if let Ok(cached) = std::fs::read_to_string(&cache_path) {
+        if let Ok(cached) = serde_json::from_str::<Json>(&cached) {
+            if cached["hash"] == hash {
+                return Ok(cached["data"].clone());
+            }
+        }
+    }
+
+    let output = run_command(cmd, directory)?;
+    let data = match format {
+        Some(f) => io::parse_data_as(&output, f),
+        None => io::sniff_data_format(&output)
+            .map_err(|e| e.into())
+            .and_then(|f| io::parse_data_as(&output, f)),
+    }
+    .map_err(|e| format!("Could not parse the output of source '{}': {}", name, e))?;
+
+    // A failing cache is not a reason to fail, since the data are already there.
+    if std::fs::create_dir_all(directory.join(CACHE_DIRECTORY)).is_ok() {
+        let cached = serde_json::json!({"hash": hash, "data": data});
+        std::fs::write(&cache_path, cached.to_string()).ok();
+    }
+
+    Ok(data)
+}
+
+/// Replace the `sources` table of a data file with the output of each source.
+///
+/// # Arguments
+/// * `data`: The data file to resolve sources in.
+/// * `directory`: The directory of the data file.
+///
+/// # Returns
+/// Nothing if it worked, or an error if a command failed or its output could not be parsed.
+pub fn resolve_sources(data: &mut Json, directory: &Path) -> Result<(), String> {
+    let sources = match data.as_object_mut().and_then(|obj| obj.remove("sources")) {
+        Some(Json::Object(s)) => s,
+        Some(s) => return Err(format!("Could not understand sources: {}", s)),
+        None => return Ok(()),
+    };
+    let directory = match directory.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => directory,
+    };
+
+    for (name, source) in sources.iter() {
+        // The name is also the name of the cache file, so it cannot point to other directories.
+        if name.is_empty() || name.starts_with('.') || name.contains(&['/', '\\'][..]) {
+            return Err(format!(
+                "Invalid source name '{}'. Names cannot start with '.' or contain '/' or '\\'.",
+                name
+            ));
+        }
+        let output = read_source(name, source, directory)?;
+        // Values that are already in the data file take precedence.
+        match data.get_mut(name) {
+            Some(existing) => {
+                let mut merged = output;
+                io::merge_data(&mut merged, existing.take());
+                *existing = merged;
+            }
+            None => data[name] = output,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_sources() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let directory = temp_dir.path();
+        std::fs::write(directory.join("values.json"), r#"{"mean": 1.5}"#).unwrap();
+
+        // The command counts how many times it was run.
+        let mut data = serde_json::json!({
+            "sources": {"stats": {"cmd": "cat values.json && echo run >>runs.txt"}},
+            "stats": {"name": "Stats"},
+        });
+        let read = |data: &Json| {
+            let mut data = data.clone();
+            resolve_sources(&mut data, directory).map(|_| data)
+        };
+        let runs = || {
+            std::fs::read_to_string(directory.join("runs.txt"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let resolved = read(&data).unwrap();
+        assert_eq!(resolved["stats"]["mean"], 1.5);
+        assert_eq!(resolved["stats"]["name"], "Stats");
+        assert!(resolved.get("sources").is_none());
+
+        // The second time, the output is read from the cache.
+        read(&data).unwrap();
+        assert_eq!(runs(), 1);
+
+        // Changing a file in the command makes the cache stale.
+        std::fs::write(directory.join("values.json"), r#"{"mean": 2.5}"#).unwrap();
+        assert_eq!(read(&data).unwrap()["stats"]["mean"], 2.5);
+        assert_eq!(runs(), 2);
+
+        // ... and so does changing an input.
+        std::fs::write(directory.join("raw.csv"), "a\n1").unwrap();
+        data["sources"]["stats"]["inputs"] = serde_json::json!(["raw.csv"]);
+        read(&data).unwrap();
+        read(&data).unwrap();
+        std::fs::write(directory.join("raw.csv"), "a\n2").unwrap();
+        read(&data).unwrap();
+        assert_eq!(runs(), 4);
+
+        // The cache holds the parsed output, so a new format makes it stale as well.
+        data["sources"]["stats"]["format"] = serde_json::json!("json");
+        read(&data).unwrap();
+        assert_eq!(runs(), 5);
+        read(&data).unwrap();
+        assert_eq!(runs(), 5);
+
+        data["sources"]["stats"]["cmd"] = serde_json::json!("exit 1");
+        assert!(read(&data).is_err());
+
+        // Sources only run when reading the data of a manuscript, e.g. not for configurations.
+        let data_path = directory.join("data.toml");
+        std::fs::write(&data_path, "[sources.stats]\ncmd = \"echo run >>runs.txt\"").unwrap();
+        assert!(io::read_data(&data_path).unwrap().get("sources").is_some());
+        assert_eq!(runs(), 5);
+        io::read_data_with_sources(&data_path).unwrap();
+        assert_eq!(runs(), 6);
+
+        for name in &["../stats", "a/b", ".."] {
+            let data = serde_json::json!({"sources": {*name: {"cmd": "echo '{}'"}}});
+            read(&data).expect_err("The name would write outside of the cache");
+        }
+
+        // The hash only depends on the command and the files, e.g. not on the Rust version.
+        assert_eq!(
+            source_hash("echo 1", None, &[], directory).unwrap(),
+            "456cd058683b3aad"
+        );
+    }
+}
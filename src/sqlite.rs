@@ -0,0 +1,167 @@
+//! Read data from SQLite databases, either whole tables or the results of named queries.
+use rusqlite::types::ValueRef;
+use serde_json::Value as Json;
+use std::path::Path;
+
+/// Convert an SQLite value to JSON.
+fn value_to_json(value: ValueRef) -> Result<Json, String> {
+    Ok(match value {
+        ValueRef::Null => Json::Null,
+        ValueRef::Integer(i) => Json::from(i),
+        ValueRef::Real(f) => Json::from(f),
+        ValueRef::Text(t) => Json::String(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(_) => return Err("Binary (BLOB) values are not supported.".into()),
+    })
+}
+
+/// Run a query and return its rows as an array of objects (column name: value).
+fn query_rows(connection: &rusqlite::Connection, sql: &str) -> Result<Vec<Json>, String> {
+    let mut statement = connection.prepare(sql).map_err(|e| e.to_string())?;
+    let columns: Vec<String> = statement
+        .column_names()
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+
+    let mut rows = statement.query([]).map_err(|e| e.to_string())?;
+    let mut output: Vec<Json> = Vec::new();
+    while let Some(row) = rows.next().map_err(|e| e.to_string())? {
+        let mut object = serde_json::Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            let value = row.get_ref(i).map_err(|e| e.to_string())?;
+            object.insert(column.to_owned(), value_to_json(value)?);
+        }
+        output.push(Json::Object(object));
+    }
+    Ok(output)
+}
+
+/// Open a database without being able to modify it.
+fn open(path: &Path) -> Result<rusqlite::Connection, String> {
+    if !path.is_file() {
+        return Err(format!("Database not found: {}", path.display()));
+    }
+    rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Could not open {}: {}", path.display(), e))
+}
+
+/// Read all tables of a database.
+///
+/// # Returns
+/// An object with one key per table, with the rows of the table as an array of objects.
+pub fn read_tables(path: &Path) -> Result<Json, String> {
+    let connection = open(path)?;
+
+    let tables = query_rows(
+        &connection,
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+    )?;
+
+    let mut data = serde_json::Map::new();
+    for table in tables {
+        let name = table["name"].as_str().unwrap_or_default().to_owned();
+        let rows = query_rows(&connection, &format!("SELECT * FROM \"{}\"", name))?;
+        data.insert(name, Json::Array(rows));
+    }
+    Ok(Json::Object(data))
+}
+
+/// Run named queries on a database.
+///
+/// A query is either the SQL itself, which gives an array of rows, or a table with the "sql" and
+/// "scalar = true" for a query that gives a single value (one row and one column), e.g.
+/// `{sql = "SELECT COUNT(*) FROM measurements", scalar = true}` => 1234.
+///
+/// # Arguments
+/// * `path`: The path to the database.
+/// * `queries`: The name and SQL (or table) of each query.
+///
+/// # Returns
+/// An object with the result of each query under its name.
+pub fn run_queries(path: &Path, queries: &serde_json::Map<String, Json>) -> Result<Json, String> {
+    let connection = open(path)?;
+
+    let mut data = serde_json::Map::new();
+    for (name, query) in queries {
+        let (sql, scalar) = match query {
+            Json::String(sql) => (sql.as_str(), false),
+            Json::Object(obj) => (
+                obj.get("sql")
+                    .and_then(|s| s.as_str())
+                    .ok_or_else(|| format!("Query '{}' has no 'sql' string.", name))?,
+                obj.get("scalar").and_then(|s| s.as_bool()).unwrap_or(false),
+            ),
+            _ => return Err(format!("Query '{}' is not a string or a table.", name)),
+        };
+        let rows = query_rows(&connection, sql)
+            .map_err(|e| format!("Error in query '{}': {}", name, e))?;
+
+        // The type of a result should not depend on how many rows the data happen to have.
+        let value = match (scalar, rows.as_slice()) {
+            (false, _) => Json::Array(rows),
+            (true, [Json::Object(row)]) if row.len() == 1 => row.values().next().unwrap().clone(),
+            (true, _) => {
+                return Err(format!(
+                    "Query '{}' is a scalar, but did not give one row with one column.",
+                    name
+                ))
+            }
+        };
+        data.insert(name.to_owned(), value);
+    }
+    Ok(Json::Object(data))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_sqlite() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("results.sqlite");
+        {
+            let connection = rusqlite::Connection::open(&path).unwrap();
+            connection
+                .execute_batch(
+                    "CREATE TABLE measurements (site TEXT, dh REAL, n INTEGER);
+                     INSERT INTO measurements VALUES ('A', 1.5, 3), ('B', -0.5, NULL);",
+                )
+                .unwrap();
+        }
+
+        let tables = read_tables(&path).unwrap();
+        assert_eq!(
+            tables["measurements"],
+            serde_json::json!([
+                {"site": "A", "dh": 1.5, "n": 3},
+                {"site": "B", "dh": -0.5, "n": null}
+            ])
+        );
+
+        let queries = serde_json::json!({
+            "n_measurements": {"sql": "SELECT COUNT(*) FROM measurements", "scalar": true},
+            "sites": "SELECT site FROM measurements ORDER BY site",
+            "first_site": "SELECT site FROM measurements ORDER BY site LIMIT 1",
+            "lowest": "SELECT site, MIN(dh) AS dh FROM measurements",
+        });
+        let data = run_queries(&path, queries.as_object().unwrap()).unwrap();
+        assert_eq!(data["n_measurements"], 2);
+        assert_eq!(
+            data["sites"],
+            serde_json::json!([{"site": "A"}, {"site": "B"}])
+        );
+        assert_eq!(data["lowest"][0]["site"], "B");
+        // A list with one element is still a list.
+        assert_eq!(data["first_site"], serde_json::json!([{"site": "A"}]));
+
+        let not_scalar =
+            serde_json::json!({"x": {"sql": "SELECT * FROM measurements", "scalar": true}});
+        assert!(run_queries(&path, not_scalar.as_object().unwrap()).is_err());
+
+        let bad = serde_json::json!({"x": "SELECT * FROM missing"});
+        assert!(run_queries(&path, bad.as_object().unwrap()).is_err());
+        assert!(read_tables(&temp_dir.path().join("missing.sqlite")).is_err());
+    }
+}
@@ -0,0 +1,528 @@
+//! Word and character counts of a manuscript, per section, for the limits of journals.
+//!
+//! Math, tables, commands, comments, labels, citations and the bibliography are not counted.
+//! Captions are counted separately from the text of the sections that they are in.
+use serde_json::Value as Json;
+
+/// Commands whose arguments are not text, e.g. the key of "\cite{key}".
+const NON_TEXT_COMMANDS: [&str; 22] = [
+    "label",
+    "ref",
+    "cref",
+    "Cref",
+    "autoref",
+    "eqref",
+    "pageref",
+    "cite",
+    "citep",
+    "citet",
+    "citealt",
+    "citeauthor",
+    "citeyear",
+    "includegraphics",
+    "input",
+    "include",
+    "bibliography",
+    "bibliographystyle",
+    "url",
+    "hspace",
+    "vspace",
+    "usepackage",
+];
+
+/// Environments that are math or tables, and are not counted.
+const NON_TEXT_ENVIRONMENTS: [&str; 13] = [
+    "equation",
+    "equation*",
+    "align",
+    "align*",
+    "gather",
+    "gather*",
+    "multline",
+    "multline*",
+    "displaymath",
+    "math",
+    "tabular",
+    "tabular*",
+    "tabularx",
+];
+
+/// The number of words and characters (without whitespace) of a text.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counts {
+    pub words: usize,
+    pub characters: usize,
+}
+
+impl Counts {
+    /// Count the words and characters of a piece of tex.
+    fn of(tex: &str) -> Counts {
+        let text = plain_text(tex);
+        let words: Vec<&str> = text
+            .split_whitespace()
+            .filter(|w| w.chars().any(|c| c.is_alphanumeric()))
+            .collect();
+        Counts {
+            words: words.len(),
+            characters: words.iter().map(|w| w.chars().count()).sum(),
+        }
+    }
+
+    fn add(&mut self, other: Counts) {
+        self.words += other.words;
+        self.characters += other.characters;
+    }
+}
+
+/// The statistics of a manuscript.
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The counts of the abstract.
+    pub abstract_counts: Counts,
+    /// The title and counts of each section, in order. Text before the first section is called
+    /// "(Before sections)".
+    pub sections: Vec<(String, Counts)>,
+    /// The counts of all captions.
+    pub captions: Counts,
+    pub figures: usize,
+    pub tables: usize,
+    /// The number of unique cited references.
+    pub references: usize,
+}
+
+impl Stats {
+    /// The counts of the main text, i.e. all sections.
+    pub fn main_text(&self) -> Counts {
+        let mut total = Counts::default();
+        for (_, counts) in &self.sections {
+            total.add(*counts);
+        }
+        total
+    }
+}
+
+/// Find the content of a braced argument starting at `start` (which has to be a '{').
+///
+/// # Returns
+/// The content and the index after the closing brace, or None if there is no braced argument.
+fn braced(tex: &str, start: usize) -> Option<(&str, usize)> {
+    if !tex[start..].starts_with('{') {
+        return None;
+    }
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in tex[start..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&tex[start + 1..start + i], start + i + 1));
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Remove everything between (and including) two markers, e.g. a whole environment.
+fn remove_between(tex: &str, start: &str, end: &str) -> String {
+    let mut output = String::new();
+    let mut rest = tex;
+    while let Some(i) = rest.find(start) {
+        output.push_str(&rest[..i]);
+        rest = match rest[i..].find(end) {
+            Some(j) => &rest[i + j + end.len()..],
+            None => "",
+        };
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Remove inline and display math ("$...$", "$$...$$", "\(...\)" and "\[...\]"), and math and
+/// table environments.
+fn strip_math(tex: &str) -> String {
+    let mut tex = tex.to_owned();
+    for environment in NON_TEXT_ENVIRONMENTS.iter() {
+        tex = remove_between(
+            &tex,
+            &format!("\\begin{{{}}}", environment),
+            &format!("\\end{{{}}}", environment),
+        );
+    }
+    tex = remove_between(&tex, "\\[", "\\]");
+    tex = remove_between(&tex, "\\(", "\\)");
+
+    // Dollar signs toggle math, unless they are escaped ("\$").
+    let mut output = String::new();
+    let mut in_math = false;
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'$') => {
+                chars.next();
+                if !in_math {
+                    output.push('$');
+                }
+            }
+            '$' => {
+                if chars.peek() == Some(&'$') {
+                    chars.next();
+                }
+                in_math = !in_math;
+                output.push(' ');
+            }
+            _ if in_math => (),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+/// Convert tex to plain text, by removing commands (and the arguments of non-text commands).
+fn plain_text(tex: &str) -> String {
+    let mut output = String::new();
+    let mut i = 0;
+    while i < tex.len() {
+        let c = tex[i..].chars().next().unwrap();
+        if c != '\\' {
+            output.push(match c {
+                '{' | '}' | '~' => ' ',
+                c => c,
+            });
+            i += c.len_utf8();
+            continue;
+        }
+
+        // Read the command name, e.g. "textbf" in "\textbf{word}".
+        let name_end = tex[i + 1..]
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .map(|j| i + 1 + j)
+            .unwrap_or_else(|| tex.len());
+        let name = &tex[i + 1..name_end];
+        i = name_end;
+        if name.is_empty() {
+            // An escaped character like "\%" is text, but a line break ("\\") is not.
+            if let Some(escaped) = tex[i..].chars().next() {
+                if escaped != '\\' {
+                    output.push(escaped);
+                }
+                i += escaped.len_utf8();
+            }
+            continue;
+        }
+        i += tex[i..].starts_with('*') as usize;
+        output.push(' ');
+
+        if NON_TEXT_COMMANDS.contains(&name) || name == "begin" || name == "end" {
+            // Skip optional arguments and the braced arguments.
+            while tex[i..].starts_with('[') {
+                i += tex[i..].find(']').map(|j| j + 1).unwrap_or(tex.len() - i);
+            }
+            while let Some((_, end)) = braced(tex, i) {
+                i = end;
+                if name == "begin" || name == "end" {
+                    break;
+                }
+            }
+            // Environments can have options after their name, e.g. "\begin{figure}[htbp]".
+            while tex[i..].starts_with('[') {
+                i += tex[i..].find(']').map(|j| j + 1).unwrap_or(tex.len() - i);
+            }
+        }
+    }
+    output
+}
+
+/// Find all arguments of a command, e.g. the keys of every "\cite{a,b}".
+fn command_arguments<'a>(tex: &'a str, command: &str) -> Vec<&'a str> {
+    let pattern = format!("\\{}", command);
+    let mut arguments: Vec<&str> = Vec::new();
+    for (i, _) in tex.match_indices(&pattern) {
+        let mut start = i + pattern.len();
+        // Only match the whole command name (e.g. not "\section" for "\sec").
+        if tex[start..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            continue;
+        }
+        start += tex[start..].starts_with('*') as usize;
+        while tex[start..].starts_with('[') {
+            match tex[start..].find(']') {
+                Some(j) => start += j + 1,
+                None => break,
+            }
+        }
+        if let Some((content, _)) = braced(tex, start) {
+            arguments.push(content);
+        }
+    }
+    arguments
+}
+
+/// Count the words and characters of a rendered manuscript.
+///
+/// # Arguments
+/// * `lines`: The merged manuscript, with the data filled in.
+///
+/// # Returns
+/// The counts of the abstract, each section and the captions, and the number of figures, tables
+/// and references.
+pub fn count(lines: &[String]) -> Stats {
+    let tex = crate::strip::strip_comments(lines)
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    // Only the document itself is counted, not the preamble.
+    let body = match tex.find("\\begin{document}") {
+        Some(i) => &tex[i + "\\begin{document}".len()..],
+        None => &tex,
+    };
+    let body = &body[..body.find("\\end{document}").unwrap_or(body.len())];
+    let body = remove_between(body, "\\begin{thebibliography}", "\\end{thebibliography}");
+
+    let mut stats = Stats {
+        figures: body.matches("\\begin{figure").count(),
+        tables: body.matches("\\begin{table").count(),
+        ..Stats::default()
+    };
+    let mut cited: Vec<&str> = Vec::new();
+    for command in &["cite", "citep", "citet", "citealt"] {
+        for keys in command_arguments(&body, command) {
+            for key in keys.split(',').map(|k| k.trim()) {
+                if !cited.contains(&key) {
+                    cited.push(key);
+                }
+            }
+        }
+    }
+    stats.references = cited.len();
+
+    let body = strip_math(&body);
+
+    // Captions are counted on their own, and removed from the text.
+    let mut text = String::new();
+    let mut rest = body.as_str();
+    while let Some(i) = rest.find("\\caption") {
+        text.push_str(&rest[..i]);
+        let mut start = i + "\\caption".len();
+        while rest[start..].starts_with('[') {
+            start += rest[start..]
+                .find(']')
+                .map(|j| j + 1)
+                .unwrap_or(rest.len() - start);
+        }
+        match braced(rest, start) {
+            Some((caption, end)) => {
+                stats.captions.add(Counts::of(caption));
+                rest = &rest[end..];
+            }
+            None => rest = &rest[start..],
+        }
+    }
+    text.push_str(rest);
+
+    // The same for the abstract.
+    for abstract_text in text
+        .split("\\begin{abstract}")
+        .skip(1)
+        .filter_map(|a| a.split("\\end{abstract}").next())
+    {
+        stats.abstract_counts.add(Counts::of(abstract_text));
+    }
+    let text = remove_between(&text, "\\begin{abstract}", "\\end{abstract}");
+
+    // Split the text at each section.
+    let mut title = "(Before sections)".to_owned();
+    let mut rest = text.as_str();
+    loop {
+        let next = rest.match_indices("\\section").find(|(i, _)| {
+            !rest[i + "\\section".len()..].starts_with(|c: char| c.is_ascii_alphabetic())
+        });
+        let (i, section_start) = match next {
+            Some((i, _)) => (i, i + "\\section".len()),
+            None => (rest.len(), rest.len()),
+        };
+        let counts = Counts::of(&rest[..i]);
+        if counts.words > 0 || title != "(Before sections)" {
+            stats.sections.push((title.to_owned(), counts));
+        }
+        if i == rest.len() {
+            break;
+        }
+
+        let mut start = section_start + rest[section_start..].starts_with('*') as usize;
+        while rest[start..].starts_with('[') {
+            start += rest[start..]
+                .find(']')
+                .map(|j| j + 1)
+                .unwrap_or(rest.len() - start);
+        }
+        let (section_title, end) = braced(rest, start).unwrap_or(("", start));
+        title = plain_text(section_title)
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        rest = &rest[end..];
+    }
+
+    stats
+}
+
+/// Format the statistics as a table.
+///
+/// # Examples
+/// ```text
+/// Section        Words  Characters
+/// Abstract          12          70
+/// Introduction     120         700
+/// Main text        120         700
+/// Captions          10          60
+///
+/// Figures: 1, tables: 0, references: 3
+/// ```
+pub fn format_stats(stats: &Stats) -> String {
+    let mut rows: Vec<(String, Counts)> = vec![("Abstract".into(), stats.abstract_counts)];
+    rows.extend(stats.sections.iter().cloned());
+    rows.push(("Main text".into(), stats.main_text()));
+    rows.push(("Captions".into(), stats.captions));
+
+    let width = rows
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("Section".len());
+    let mut output = format!(
+        "{:<width$}  {:>6}  {:>10}\n",
+        "Section", "Words", "Characters"
+    );
+    for (name, counts) in rows {
+        output += &format!(
+            "{:<width$}  {:>6}  {:>10}\n",
+            name,
+            counts.words,
+            counts.characters,
+            width = width
+        );
+    }
+    output += &format!(
+        "\nFigures: {}, tables: {}, references: {}\n",
+        stats.figures, stats.tables, stats.references
+    );
+    output
+}
+
+/// Compare the statistics with limits.
+///
+/// # Arguments
+/// * `stats`: The statistics of the manuscript.
+/// * `limits`: The "limits" table of manus.toml, with the optional keys "abstract", "main" and
+///   "captions" (words), "figures", "tables" and "references", and a "sections" table with the
+///   word limit of sections by title.
+///
+/// # Returns
+/// A description of each exceeded limit, or an error if a limit is invalid.
+pub fn check_limits(stats: &Stats, limits: &Json) -> Result<Vec<String>, String> {
+    let mut counts: Vec<(String, usize, &str)> = vec![
+        ("abstract".into(), stats.abstract_counts.words, "words"),
+        ("main".into(), stats.main_text().words, "words"),
+        ("captions".into(), stats.captions.words, "words"),
+        ("figures".into(), stats.figures, "figures"),
+        ("tables".into(), stats.tables, "tables"),
+        ("references".into(), stats.references, "references"),
+    ];
+    for (title, section_counts) in &stats.sections {
+        counts.push((format!("sections.{}", title), section_counts.words, "words"));
+    }
+
+    let mut exceeded: Vec<String> = Vec::new();
+    for (key, count, unit) in counts {
+        let limit = match crate::expressions::get_by_path(limits, &key) {
+            Some(l) => l
+                .as_u64()
+                .ok_or_else(|| format!("Invalid limit for '{}': {}", key, l))?,
+            None => continue,
+        };
+        if count as u64 > limit {
+            exceeded.push(format!("{}: {} {} (limit: {})", key, count, unit, limit));
+        }
+    }
+    Ok(exceeded)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        let lines: Vec<String> = r"\documentclass{article}
+\title{Not counted}
+\begin{document}
+\begin{abstract}
+We measured the \textbf{whole} glacier. % A comment.
+\end{abstract}
+\section{Introduction}\label{sec:intro}
+Glaciers shrink by 5\% per year $\frac{dh}{dt}$ \citep{a,b}.
+\begin{equation}
+x = y
+\end{equation}
+\begin{figure}[htbp]
+\includegraphics{map.pdf}
+\caption[Short]{A map of the \emph{sites}.}
+\end{figure}
+\section*{Methods~and data}
+\begin{table}
+\begin{tabular}{lr}
+Site & Value \\
+\end{tabular}
+\end{table}
+See Figure~\ref{fig:map} \cite{b, c}.
+\begin{thebibliography}{9}
+\bibitem{a} Some reference.
+\end{thebibliography}
+\end{document}"
+            .lines()
+            .map(String::from)
+            .collect();
+
+        let stats = count(&lines);
+        assert_eq!(
+            stats.abstract_counts,
+            Counts {
+                words: 5,
+                characters: 26
+            }
+        );
+        assert_eq!(stats.sections.len(), 2);
+        assert_eq!(stats.sections[0].0, "Introduction");
+        // "Glaciers shrink by 5% per year ."
+        assert_eq!(stats.sections[0].1.words, 6);
+        assert_eq!(stats.sections[1].0, "Methods and data");
+        assert_eq!(stats.sections[1].1.words, 2);
+        assert_eq!(stats.main_text().words, 8);
+        assert_eq!(stats.captions.words, 5);
+        assert_eq!((stats.figures, stats.tables, stats.references), (1, 1, 3));
+
+        let table = format_stats(&stats);
+        assert!(table.contains("Methods and data       2"));
+        assert!(table.contains("Figures: 1, tables: 1, references: 3"));
+
+        let limits =
+            serde_json::json!({"abstract": 4, "main": 100, "sections": {"Introduction": 5}});
+        assert_eq!(
+            check_limits(&stats, &limits).unwrap(),
+            vec![
+                "abstract: 5 words (limit: 4)",
+                "sections.Introduction: 6 words (limit: 5)"
+            ]
+        );
+        assert!(check_limits(&stats, &serde_json::json!({"main": "many"})).is_err());
+    }
+}
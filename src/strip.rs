@@ -0,0 +1,328 @@
+//! Remove comments and draft notes (`\todo{}`) that should not reach editors and reviewers.
+//!
+//! Escaped percent signs (`\%`), `\verb`, verbatim environments and `{{placeholders}}` are left as
+//! they are.
+
+/// Environments whose content is written as it is, so '%' does not start a comment.
+const VERBATIM_ENVIRONMENTS: [&str; 5] =
+    ["verbatim", "verbatim*", "Verbatim", "lstlisting", "minted"];
+
+/// Find where the comment of a line starts.
+///
+/// # Returns
+/// The index of the '%' that starts the comment, or None if the line has no comment.
+pub(crate) fn comment_start(line: &str) -> Option<usize> {
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '%' => return Some(i),
+            // "\verbatiminput" and other commands that start with "\verb" are not "\verb".
+            '\\' if line[i..].starts_with("\\verb")
+                && !line[i + "\\verb".len()..].starts_with(|c: char| c.is_ascii_alphabetic()) =>
+            {
+                // Skip "\verb|...|", where the first character after "\verb" is the delimiter.
+                let start =
+                    i + "\\verb".len() + line[i + "\\verb".len()..].starts_with('*') as usize;
+                let delimiter = line[start..].chars().next()?;
+                let end = line[start + delimiter.len_utf8()..]
+                    .find(delimiter)
+                    .map(|j| start + delimiter.len_utf8() + j)?;
+                while chars.peek().map(|(j, _)| *j <= end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            // Placeholders like {{pick x "5%"}} are not tex, so they cannot contain comments.
+            '{' if line[i..].starts_with("{{") => {
+                let end = line[i..].find("}}").map(|j| i + j + 1)?;
+                while chars.peek().map(|(j, _)| *j <= end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            // The character after a backslash is escaped, e.g. "\%" or the second '\' of "\\".
+            '\\' => {
+                chars.next();
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Find out if a line starts or ends a verbatim environment.
+///
+/// A line can have both, e.g. "\begin{verbatim}50%\end{verbatim}", so the last one counts.
+///
+/// # Returns
+/// Some(true) if a verbatim environment is open after the line, Some(false) if the line ends
+/// one, or None if it has neither.
+fn verbatim_boundary(line: &str) -> Option<bool> {
+    let mut last: Option<(usize, bool)> = None;
+    for environment in VERBATIM_ENVIRONMENTS.iter() {
+        for (command, starts) in &[("begin", true), ("end", false)] {
+            if let Some(i) = line.rfind(&format!("\\{}{{{}}}", command, environment)) {
+                if last.map(|(j, _)| i > j).unwrap_or(true) {
+                    last = Some((i, *starts));
+                }
+            }
+        }
+    }
+    last.map(|(_, starts)| starts)
+}
+
+/// Remove line and trailing comments.
+///
+/// Lines that are only a comment are removed completely. A '%' that joins a line with the next
+/// (e.g. "}%") is kept, so that no spaces are added to the output.
+///
+/// # Arguments
+/// * `lines`: The lines of the manuscript.
+///
+/// # Returns
+/// The lines without comments, each with the index of the line in `lines` that it came from.
+pub fn strip_comments(lines: &[String]) -> Vec<(usize, String)> {
+    let mut output: Vec<(usize, String)> = Vec::new();
+    let mut in_verbatim = false;
+
+    let split_lines = lines
+        .iter()
+        .enumerate()
+        .flat_map(|(i, l)| l.split('\n').map(move |line| (i, line)));
+    for (i, line) in split_lines {
+        match verbatim_boundary(line) {
+            Some(true) => in_verbatim = true,
+            Some(false) => {
+                in_verbatim = false;
+                output.push((i, line.to_owned()));
+                continue;
+            }
+            None => (),
+        }
+        let start = match comment_start(line) {
+            Some(i) if !in_verbatim => i,
+            _ => {
+                output.push((i, line.to_owned()));
+                continue;
+            }
+        };
+
+        let code = &line[..start];
+        if code.trim().is_empty() {
+            continue;
+        }
+        match code.ends_with(char::is_whitespace) {
+            true => output.push((i, code.trim_end().to_owned())),
+            false => output.push((i, code.to_owned() + "%")),
+        }
+    }
+    output
+}
+
+/// Find the end of a "\todo[options]{note}" that starts at `start`.
+///
+/// # Returns
+/// The index after the closing brace, or None if the note is not closed.
+fn todo_end(tex: &str, start: usize) -> Option<usize> {
+    let mut i = start + "\\todo".len();
+    if tex[i..].starts_with('[') {
+        i += tex[i..].find(']')? + 1;
+    }
+    if !tex[i..].starts_with('{') {
+        return Some(i);
+    }
+    let mut depth = 0;
+    let mut escaped = false;
+    for (j, c) in tex[i..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + j + 1);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Find the start of each "\todo" (but not e.g. "\todolist") in a text.
+fn todo_starts(tex: &str) -> Vec<usize> {
+    tex.match_indices("\\todo")
+        .map(|(i, _)| i)
+        .filter(|i| !tex[i + "\\todo".len()..].starts_with(|c: char| c.is_ascii_alphabetic()))
+        .collect()
+}
+
+/// Remove all `\todo{}` notes, also those that span several lines.
+///
+/// Lines that only contained notes are removed completely.
+///
+/// # Arguments
+/// * `lines`: The lines of the manuscript.
+///
+/// # Returns
+/// The lines without notes, each with the index of the line in `lines` that it starts on, or an
+/// error if a note is not closed.
+pub fn strip_todos(lines: &[String]) -> Result<Vec<(usize, String)>, String> {
+    let tex = lines.join("\n");
+    let newlines: Vec<usize> = tex.match_indices('\n').map(|(i, _)| i).collect();
+    let mut output = String::new();
+    let mut position = 0;
+
+    // The line that the output starts on, and the position in the output and the line after each
+    // newline that is written.
+    let mut first_line = 0;
+    let mut line_starts: Vec<(usize, usize)> = Vec::new();
+    let push = |output: &mut String, line_starts: &mut Vec<(usize, usize)>, from, to| {
+        for (i, _) in tex[from..to].match_indices('\n') {
+            let line = newlines.binary_search(&(from + i)).unwrap_or_default() + 1;
+            line_starts.push((output.len() + i, line));
+        }
+        output.push_str(&tex[from..to]);
+    };
+
+    for start in todo_starts(&tex) {
+        // Notes inside notes are already removed.
+        if start < position {
+            continue;
+        }
+        let end = todo_end(&tex, start).ok_or_else(|| {
+            format!(
+                "Unclosed \\todo at line {}",
+                tex[..start].matches('\n').count() + 1
+            )
+        })?;
+        push(&mut output, &mut line_starts, position, start);
+        position = end;
+
+        // Remove the whole line if the note was the only thing on it.
+        let line_start = output.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = tex[end..].find('\n').map(|i| end + i).unwrap_or(tex.len());
+        if output[line_start..].trim().is_empty() && tex[end..line_end].trim().is_empty() {
+            output.truncate(line_start.saturating_sub(1));
+            line_starts.retain(|(i, _)| *i < output.len());
+            position = line_end;
+            // The first line has no newline before it to remove, so the one after it is removed.
+            if line_start == 0 && position < tex.len() {
+                position += 1;
+                first_line = newlines.binary_search(&line_end).unwrap_or_default() + 1;
+            }
+        }
+    }
+    push(&mut output, &mut line_starts, position, tex.len());
+
+    let origins = std::iter::once(first_line).chain(line_starts.into_iter().map(|(_, line)| line));
+    Ok(origins
+        .zip(output.split('\n'))
+        .map(|(i, line)| (i, line.to_owned()))
+        .collect())
+}
+
+/// Find the lines that contain a `\todo` outside of comments.
+///
+/// # Returns
+/// The line number (starting at 1) of each line with a note.
+pub fn find_todos(lines: &[String]) -> Vec<usize> {
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| {
+            let code = match comment_start(line) {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            !todo_starts(code).is_empty()
+        })
+        .map(|(i, _)| i + 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        let lines: Vec<String> = r#"% A comment line.
+A 5\% change. % A trailing comment.
+\newcommand{\x}{x}% Joined with the next line.
+A line break\\% Then a comment.
+\verb|50%| of it, {{pick n "5%" "6%"}} % of it.
+\begin{verbatim}
+100% verbatim
+\end{verbatim}
+Text\todo{Check this.} and more.
+\todo[inline]{A {nested} note
+over two lines.}
+The end."#
+            .lines()
+            .map(String::from)
+            .collect();
+
+        let (origins, stripped): (Vec<usize>, Vec<String>) =
+            strip_comments(&lines).into_iter().unzip();
+        assert_eq!(origins[..6], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            stripped[..6],
+            [
+                "A 5\\% change.",
+                "\\newcommand{\\x}{x}%",
+                "A line break\\\\%",
+                "\\verb|50%| of it, {{pick n \"5%\" \"6%\"}}",
+                "\\begin{verbatim}",
+                "100% verbatim",
+            ]
+        );
+
+        assert_eq!(find_todos(&lines), vec![9, 10]);
+        assert!(find_todos(&["% \\todo{Commented out.}".to_owned()]).is_empty());
+
+        // "\verbatiminput" is not "\verb", and a verbatim environment can end on its first line.
+        let lines: Vec<String> = vec![
+            "\\verbatiminput{a.txt} % A comment.".into(),
+            "\\begin{verbatim}100%\\end{verbatim}".into(),
+            "After % A comment.".into(),
+        ];
+        assert_eq!(
+            strip_comments(&lines),
+            vec![
+                (0, "\\verbatiminput{a.txt}".to_owned()),
+                (1, "\\begin{verbatim}100%\\end{verbatim}".to_owned()),
+                (2, "After".to_owned()),
+            ]
+        );
+
+        let (todo_origins, without_todos): (Vec<usize>, Vec<String>) =
+            strip_todos(&stripped).unwrap().into_iter().unzip();
+        assert_eq!(without_todos[7], "Text and more.");
+        assert_eq!(without_todos[8], "The end.");
+        assert_eq!(todo_origins[6..], [6, 7, 10]);
+        assert!(find_todos(&without_todos).is_empty());
+
+        assert!(strip_todos(&["\\todo{Unclosed".to_owned()]).is_err());
+        assert_eq!(
+            strip_todos(&["\\todo{First}".to_owned(), "Second".to_owned()]).unwrap(),
+            vec![(1, "Second".to_owned())]
+        );
+        // Each line keeps the index of the line that it starts on.
+        let lines: Vec<String> = vec![
+            "A".into(),
+            "\\todo{B}".into(),
+            "C \\todo{D".into(),
+            "E} F".into(),
+            "G".into(),
+        ];
+        assert_eq!(
+            strip_todos(&lines).unwrap(),
+            vec![
+                (0, "A".to_owned()),
+                (2, "C  F".to_owned()),
+                (4, "G".to_owned())
+            ]
+        );
+    }
+}
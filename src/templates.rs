@@ -1,3 +1,8 @@
+use crate::acronyms;
+use crate::expressions;
+use crate::figures;
+use crate::refs;
+use crate::units::{self, Quantity};
 use handlebars::{self, handlebars_helper};
 use serde_json::Value as Json;
 use std::io::Write;
@@ -151,6 +156,7 @@ fn add_separators(number: f64, separator: &str) -> String {
 ///
 /// "{{pm 1 value}}" => "`1.2$\pm$0.5`"
 ///
+/// The error can also be given with a "pm" argument, e.g. "{{pm 1 (expr "value * 2") pm=0.9}}".
 fn pm_helper(
     h: &handlebars::Helper,
     _: &handlebars::Handlebars,
@@ -174,75 +180,94 @@ fn pm_helper(
         false => 0,
     };
 
-    // Try to find the key to the value.
-    let keys = match h.param(key_index) {
-        // If the attribute exists, try to see if it is a path.
-        Some(attr) => match attr.context_path() {
-            // If a data path was associated, return it.
-            Some(v) => v,
-            // Otherwise, raise an error.
+    // The error can also be given directly, e.g. for values that are not in the data:
+    // "{{pm 1 (expr "a - b") pm=0.5}}". Otherwise, it is read from the "_pm" key of the value.
+    let (value_json, pm_json) = match h.hash_get("pm") {
+        Some(pm) => match h.param(key_index) {
+            Some(attr) => (attr.value().clone(), pm.value().clone()),
             None => {
-                let e = match attr.relative_path() {
-                    Some(rp) => format!("pm got invalid data path: {:?}", rp),
-                    None => match attr.value() {
-                        Json::Null => "No argument was found.".to_string(),
-                        v => format!("pm argument: {} is not a valid data path.", v.to_string()),
-                    },
-                };
-                return Err(handlebars::RenderError::new::<String>(e));
+                return Err(handlebars::RenderError::new::<String>(
+                    "No argument was given for pm".into(),
+                ))
             }
         },
-        // It only reaches here if no argument was given.
         None => {
-            return Err(handlebars::RenderError::new::<String>(
-                "No argument was given for pm".into(),
-            ))
+            // Try to find the key to the value.
+            let keys = match h.param(key_index) {
+                // If the attribute exists, try to see if it is a path.
+                Some(attr) => match attr.context_path() {
+                    // If a data path was associated, return it.
+                    Some(v) => v,
+                    // Otherwise, raise an error.
+                    None => {
+                        let e = match attr.relative_path() {
+                            Some(rp) => format!("pm got invalid data path: {:?}", rp),
+                            None => match attr.value() {
+                                Json::Null => "No argument was found.".to_string(),
+                                v => format!(
+                                    "pm argument: {} is not a valid data path.",
+                                    v.to_string()
+                                ),
+                            },
+                        };
+                        return Err(handlebars::RenderError::new::<String>(e));
+                    }
+                },
+                // It only reaches here if no argument was given.
+                None => {
+                    return Err(handlebars::RenderError::new::<String>(
+                        "No argument was given for pm".into(),
+                    ))
+                }
+            };
+
+            // The last key is the value key.
+            let value_key = keys[(keys.len() - 1)].to_owned();
+            // The first keys are the parent keys (may be empty, but that's fine).
+            let parent_keys = &keys[..(keys.len() - 1)];
+
+            // Find the parent json value by iteratively running .get methods.
+            let mut parent: &Json = context.data();
+            // Loop through each parent key (if any).
+            for key in parent_keys {
+                parent = parent
+                    .get(key)
+                    .expect("Getter failed on parent json. Shouldn't happen.");
+            }
+
+            let value_json = parent
+                .get(&value_key)
+                .expect("Value not found in parent. Something is wrong.")
+                .clone();
+            // The plusminus key might not exist, so this has to be checked.
+            match parent.get(&(value_key.to_owned() + "_pm")) {
+                Some(v) => (value_json, v.clone()),
+                None => {
+                    return Err(handlebars::RenderError::new::<String>(format!(
+                        "{}_pm key not found",
+                        value_key
+                    )))
+                }
+            }
         }
     };
 
-    // The last key is the value key.
-    let value_key = keys[(keys.len() - 1)].to_owned();
-    // The first keys are the parent keys (may be empty, but that's fine).
-    let parent_keys = &keys[..(keys.len() - 1)];
-
-    // Find the parent json value by iteratively running .get methods.
-    let mut parent: &Json = context.data();
-    // Loop through each parent key (if any).
-    for key in parent_keys {
-        parent = parent
-            .get(key)
-            .expect("Getter failed on parent json. Shouldn't happen.");
-    }
-
     // Parse the value and plusminus keys as f64.
-    let mut value = match parent
-        .get(&value_key)
-        .expect("Value not found in parent. Something is wrong.")
-        .as_f64()
-    {
+    let mut value = match value_json.as_f64() {
         Some(v) => v,
         None => {
             return Err(handlebars::RenderError::new::<String>(format!(
                 "Could not parse value {} as float",
-                parent.get(&value_key).unwrap()
+                value_json
             )))
         }
     };
-    // The plusminus key might not exist, so this has to be checked.
-    let mut pm = match parent.get(&(value_key.to_owned() + "_pm")) {
-        Some(v) => match v.as_f64() {
-            Some(y) => y,
-            None => {
-                return Err(handlebars::RenderError::new::<String>(format!(
-                    "Could not parse pm value {} as float",
-                    v.to_string()
-                )))
-            }
-        },
+    let mut pm = match pm_json.as_f64() {
+        Some(y) => y,
         None => {
             return Err(handlebars::RenderError::new::<String>(format!(
-                "{}_pm key not found",
-                value_key
+                "Could not parse pm value {} as float",
+                pm_json
             )))
         }
     };
@@ -434,6 +459,775 @@ fn exponent_helper(
     Ok(())
 }
 
+/// Parse a helper parameter as f64, or return a render error that names the helper.
+fn param_as_float(value: &Json, helper: &str) -> Result<f64, handlebars::RenderError> {
+    json_as_float(value).map_err(|e| handlebars::RenderError::new(format!("{}: {}", helper, e)))
+}
+
+/// Convert a float to json, as an integer if it has no fractional part.
+///
+/// Only floats below 2^53 are converted, since larger ones are not exact integers anyway and could
+/// overflow an i64.
+pub fn float_to_json(value: f64) -> Json {
+    match (value.fract() == 0.0) & (value.abs() < 2_f64.powi(53)) {
+        true => serde_json::json!(value as i64),
+        false => serde_json::json!(value),
+    }
+}
+
+/// Check if two values are equal; numerically if both are numbers, otherwise as strings.
+fn values_equal(a: &Json, b: &Json) -> bool {
+    match (json_as_float(a), json_as_float(b)) {
+        (Ok(x), Ok(y)) => x == y,
+        _ => match (a.as_str(), b.as_str()) {
+            (Some(x), Some(y)) => x == y,
+            _ => a == b,
+        },
+    }
+}
+
+handlebars_helper!(gt: |a: Json, b: Json| param_as_float(a, "gt")? > param_as_float(b, "gt")?);
+handlebars_helper!(lt: |a: Json, b: Json| param_as_float(a, "lt")? < param_as_float(b, "lt")?);
+handlebars_helper!(eq: |a: Json, b: Json| values_equal(a, b));
+handlebars_helper!(between: |value: Json, low: Json, high: Json| {
+    let value = param_as_float(value, "between")?;
+    (value >= param_as_float(low, "between")?) & (value <= param_as_float(high, "between")?)
+});
+
+handlebars_helper!(add: |a: Json, b: Json| {
+    float_to_json(param_as_float(a, "add")? + param_as_float(b, "add")?)
+});
+handlebars_helper!(sub: |a: Json, b: Json| {
+    float_to_json(param_as_float(a, "sub")? - param_as_float(b, "sub")?)
+});
+handlebars_helper!(mul: |a: Json, b: Json| {
+    float_to_json(param_as_float(a, "mul")? * param_as_float(b, "mul")?)
+});
+handlebars_helper!(div: |a: Json, b: Json| {
+    let divisor = param_as_float(b, "div")?;
+    if divisor == 0.0 {
+        return Err(handlebars::RenderError::new("div: Division by zero."));
+    };
+    float_to_json(param_as_float(a, "div")? / divisor)
+});
+handlebars_helper!(abs: |value: Json| float_to_json(param_as_float(value, "abs")?.abs()));
+
+/// Helper to choose the singular or plural form of a word.
+///
+/// "{{plural n_sites "site"}}" renders "site" if `n_sites` is 1 and "sites" otherwise.
+/// Irregular plurals can be given as a third argument: "{{plural n "glacier tongue" "tongues"}}".
+fn plural_helper(args: &[&Json]) -> Result<Json, handlebars::RenderError> {
+    let count = match args.first() {
+        Some(v) => param_as_float(v, "plural")?,
+        None => {
+            return Err(handlebars::RenderError::new(
+                "plural: No arguments provided. Requires: 'count' 'singular' ['plural']",
+            ))
+        }
+    };
+    let singular = match args.get(1).and_then(|v| v.as_str()) {
+        Some(s) => s.to_owned(),
+        None => {
+            return Err(handlebars::RenderError::new(
+                "plural: The second argument must be the singular form of a word.",
+            ))
+        }
+    };
+    let plural_form = match args.get(2).and_then(|v| v.as_str()) {
+        Some(s) => s.to_owned(),
+        None => singular.to_owned() + "s",
+    };
+
+    Ok(match (count - 1.0).abs() < f64::EPSILON {
+        true => Json::String(singular),
+        false => Json::String(plural_form),
+    })
+}
+handlebars_helper!(plural: |*args| plural_helper(&args)?);
+
+/// Helper to describe the sign of a value in words.
+///
+/// "{{sign change}}" renders "increase" if `change` is positive, "decrease" if it is negative and
+/// "no change" if it is zero. Other words can be given:
+/// "{{sign change "advanced" "retreated" "stood still"}}".
+fn sign_helper(args: &[&Json]) -> Result<Json, handlebars::RenderError> {
+    let value = match args.first() {
+        Some(v) => param_as_float(v, "sign")?,
+        None => {
+            return Err(handlebars::RenderError::new(
+                "sign: No arguments provided. Requires: 'value' ['positive' 'negative' 'zero']",
+            ))
+        }
+    };
+    let word = |index: usize, default: &str| match args.get(index).and_then(|v| v.as_str()) {
+        Some(s) => s.to_owned(),
+        None => default.to_owned(),
+    };
+
+    // Zero has no sign, like in the `change` helper.
+    Ok(Json::String(match value {
+        v if v < 0.0 => word(2, "decrease"),
+        v if v > 0.0 => word(1, "increase"),
+        _ => word(3, "no change"),
+    }))
+}
+handlebars_helper!(sign: |*args| sign_helper(&args)?);
+
+/// Helper to pick one of several options.
+///
+/// The first argument is either an index (starting at 0) or a boolean, where true picks the first
+/// option and false the second:
+/// "{{pick n_campaigns "zero" "one" "two"}}" or "{{pick (gt change 0) "advanced" "retreated"}}".
+fn pick_helper(args: &[&Json]) -> Result<Json, handlebars::RenderError> {
+    let index = match args.first() {
+        Some(Json::Bool(b)) => match b {
+            true => 0,
+            false => 1,
+        },
+        Some(v) => match json_as_integer(v) {
+            Ok(i) if i >= 0 => i as usize,
+            _ => {
+                return Err(handlebars::RenderError::new(format!(
+                    "pick: Could not parse {} as a positive index or a boolean.",
+                    v
+                )))
+            }
+        },
+        None => {
+            return Err(handlebars::RenderError::new(
+                "pick: No arguments provided. Requires: 'index' 'option0' ['option1' ...]",
+            ))
+        }
+    };
+
+    match args.get(index + 1) {
+        Some(v) => Ok((*v).to_owned()),
+        None => Err(handlebars::RenderError::new(format!(
+            "pick: Index {} is out of range for {} options.",
+            index,
+            args.len() - 1
+        ))),
+    }
+}
+handlebars_helper!(pick: |*args| pick_helper(&args)?);
+
+/// Spell out an integer in English words.
+///
+/// # Examples
+/// ```
+/// assert_eq!(number_to_words(3), "three");
+/// assert_eq!(number_to_words(-1042), "minus one thousand forty-two");
+/// ```
+fn number_to_words(number: i64) -> String {
+    const ONES: [&str; 20] = [
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+    const TENS: [&str; 10] = [
+        "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+    const SCALES: [(u64, &str); 6] = [
+        (1_000_000_000_000_000_000, "quintillion"),
+        (1_000_000_000_000_000, "quadrillion"),
+        (1_000_000_000_000, "trillion"),
+        (1_000_000_000, "billion"),
+        (1_000_000, "million"),
+        (1_000, "thousand"),
+    ];
+
+    // Spell out a number below one thousand.
+    let below_thousand = |n: u64| -> String {
+        let mut parts: Vec<String> = Vec::new();
+        if n >= 100 {
+            parts.push(format!("{} hundred", ONES[(n / 100) as usize]));
+        }
+        let rest = n % 100;
+        if rest >= 20 {
+            parts.push(match rest % 10 {
+                0 => TENS[(rest / 10) as usize].to_owned(),
+                i => format!("{}-{}", TENS[(rest / 10) as usize], ONES[i as usize]),
+            });
+        } else if (rest > 0) | parts.is_empty() {
+            parts.push(ONES[rest as usize].to_owned());
+        }
+        parts.join(" ")
+    };
+
+    let mut remainder = number.unsigned_abs();
+    let mut parts: Vec<String> = Vec::new();
+    if number < 0 {
+        parts.push("minus".into());
+    }
+    for (scale, name) in SCALES.iter() {
+        if remainder >= *scale {
+            parts.push(format!("{} {}", below_thousand(remainder / scale), name));
+            remainder %= scale;
+        }
+    }
+    if (remainder > 0) | (number == 0) {
+        parts.push(below_thousand(remainder));
+    }
+
+    parts.join(" ")
+}
+
+/// Convert an integer to an ordinal, either with a suffix ("2nd") or in words ("second").
+///
+/// # Examples
+/// ```
+/// assert_eq!(number_to_ordinal(12, false), "12th");
+/// assert_eq!(number_to_ordinal(21, true), "twenty-first");
+/// ```
+fn number_to_ordinal(number: i64, in_words: bool) -> String {
+    if !in_words {
+        let suffix = match (number.unsigned_abs() % 10, number.unsigned_abs() % 100) {
+            (_, 11..=13) => "th",
+            (1, _) => "st",
+            (2, _) => "nd",
+            (3, _) => "rd",
+            _ => "th",
+        };
+        return format!("{}{}", number, suffix);
+    }
+
+    let words = number_to_words(number);
+
+    // Only the last word (after any hyphen) is changed: "twenty-one" -> "twenty-first".
+    let split_at = words
+        .rfind(|c| (c == ' ') | (c == '-'))
+        .map_or(0, |i| i + 1);
+    let (start, last) = words.split_at(split_at);
+    let last_ordinal = match last {
+        "one" => "first".to_owned(),
+        "two" => "second".to_owned(),
+        "three" => "third".to_owned(),
+        "five" => "fifth".to_owned(),
+        "eight" => "eighth".to_owned(),
+        "nine" => "ninth".to_owned(),
+        "twelve" => "twelfth".to_owned(),
+        w if w.ends_with('y') => w.trim_end_matches('y').to_owned() + "ieth",
+        w => w.to_owned() + "th",
+    };
+
+    start.to_owned() + &last_ordinal
+}
+
+/// Make the first letter of a string uppercase.
+fn capitalize(string: &str) -> String {
+    let mut chars = string.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().collect::<String>() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+/// Parse a helper parameter as an integer, or return a render error that names the helper.
+fn param_as_integer(value: &Json, helper: &str) -> Result<i64, handlebars::RenderError> {
+    match json_as_float(value) {
+        Ok(v) if v.fract() == 0.0 => Ok(v as i64),
+        _ => Err(handlebars::RenderError::new(format!(
+            "{}: Could not parse {} as an integer.",
+            helper, value
+        ))),
+    }
+}
+
+handlebars_helper!(words_helper: |value: Json, {capitalize: bool = false}| {
+    let words = number_to_words(param_as_integer(value, "words")?);
+    match capitalize {
+        true => self::capitalize(&words),
+        false => words,
+    }
+});
+
+handlebars_helper!(ordinal_helper: |value: Json, {words: bool = false, capitalize: bool = false}| {
+    let ordinal = number_to_ordinal(param_as_integer(value, "ordinal")?, words);
+    match capitalize {
+        true => self::capitalize(&ordinal),
+        false => ordinal,
+    }
+});
+
+/// Helper to spell out small numbers and add thousand-separators to larger ones.
+///
+/// Numbers (by magnitude) below the "below" argument (default 10) are written in words, and others
+/// are written like the "sep" helper would:
+/// "{{smallwords n_sites}}" => "three", "{{smallwords n_measurements}}" => "85,819".
+///
+/// Sentence starts can be capitalized with "capitalize=true", and the threshold changed with e.g.
+/// "below=100".
+fn smallwords_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    context: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let value = match h.param(0) {
+        Some(p) => param_as_float(p.value(), "smallwords")?,
+        None => {
+            return Err(handlebars::RenderError::new::<String>(
+                "No arguments provided.".into(),
+            ))
+        }
+    };
+    let below = match h.hash_get("below") {
+        Some(p) => param_as_float(p.value(), "smallwords")?,
+        None => 10.0,
+    };
+    let capitalize = h
+        .hash_get("capitalize")
+        .and_then(|p| p.value().as_bool())
+        .unwrap_or(false);
+
+    // Only integers are spelled out.
+    if (value.abs() < below) & (value.fract() == 0.0) {
+        let words = number_to_words(value as i64);
+        match capitalize {
+            true => out.write(&self::capitalize(&words))?,
+            false => out.write(&words)?,
+        };
+        return Ok(());
+    }
+
+    let separator = match context.data().get("separator") {
+        Some(v) => v.as_str().ok_or_else(|| {
+            handlebars::RenderError::new(format!(
+                "smallwords: The \"separator\" has to be a string, not {}.",
+                v
+            ))
+        })?,
+        None => {
+            return Err(handlebars::RenderError::new::<String>(
+                "Could not find the \"separator\" key in the data file. Please add it.".into(),
+            ))
+        }
+    };
+
+    out.write(&add_separators(value, separator))?;
+
+    Ok(())
+}
+
+/// Read the "decimals" hash argument of a helper.
+fn decimals_argument(h: &handlebars::Helper, default: i64) -> Result<i64, handlebars::RenderError> {
+    match h.hash_get("decimals") {
+        Some(p) => json_as_integer(p.value()).map_err(handlebars::RenderError::new),
+        None => Ok(default),
+    }
+}
+
+/// Read a helper parameter as a float, together with its "_pm" error if one exists in the data.
+///
+/// The error is only looked for if the parameter is a data path, e.g. "results.change" has the
+/// error "results.change_pm".
+fn param_with_pm(
+    param: &handlebars::PathAndJson,
+    context: &handlebars::Context,
+    helper: &str,
+) -> Result<(f64, Option<f64>), handlebars::RenderError> {
+    let value = param_as_float(param.value(), helper)?;
+
+    match find_pm(param, context) {
+        Some(v) => Ok((value, Some(param_as_float(v, helper)?))),
+        None => Ok((value, None)),
+    }
+}
+
+/// Find the "_pm" value that belongs to a parameter, e.g. "volume_pm" for "volume".
+fn find_pm<'a>(
+    param: &handlebars::PathAndJson,
+    context: &'a handlebars::Context,
+) -> Option<&'a Json> {
+    let keys = param.context_path().filter(|k| !k.is_empty())?;
+
+    // Find the parent json value by iteratively running .get methods.
+    let mut parent: &Json = context.data();
+    for key in &keys[..(keys.len() - 1)] {
+        parent = parent.get(key)?;
+    }
+    parent.get(keys[keys.len() - 1].to_owned() + "_pm")
+}
+
+/// Helper to write a fraction as a percentage.
+///
+/// If one argument is given, it is the fraction: "{{percent 0.234}}" => "`23\%`".
+/// If two are given, they are the value and the total: "{{percent 20 80}}" => "`25\%`".
+///
+/// The number of decimals can be set with "decimals=1" (default 0), and the escaping of the
+/// percent sign can be turned off with "escape=false".
+fn percent_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let value = match h.param(0) {
+        Some(p) => param_as_float(p.value(), "percent")?,
+        None => {
+            return Err(handlebars::RenderError::new::<String>(
+                "No arguments provided. Requires: 'fraction' or 'value' 'total'".into(),
+            ))
+        }
+    };
+    let fraction = match h.param(1) {
+        Some(p) => {
+            let total = param_as_float(p.value(), "percent")?;
+            if total == 0.0 {
+                return Err(handlebars::RenderError::new(
+                    "percent: The total cannot be zero.",
+                ));
+            };
+            value / total
+        }
+        None => value,
+    };
+
+    let percent_sign = match h.hash_get("escape").and_then(|p| p.value().as_bool()) {
+        Some(false) => "%",
+        _ => "\\%",
+    };
+
+    out.write(&format!(
+        "{}{}",
+        round_value(fraction * 100.0, decimals_argument(h, 0)?),
+        percent_sign
+    ))?;
+
+    Ok(())
+}
+
+/// Helper to write the ratio between two values.
+///
+/// "{{ratio 1 3}}" => "`0.33`". The number of decimals can be set with "decimals=1" (default 2).
+fn ratio_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let (numerator, denominator) = match (h.param(0), h.param(1)) {
+        (Some(a), Some(b)) => (
+            param_as_float(a.value(), "ratio")?,
+            param_as_float(b.value(), "ratio")?,
+        ),
+        _ => {
+            return Err(handlebars::RenderError::new::<String>(
+                "Two arguments are required: 'numerator' 'denominator'".into(),
+            ))
+        }
+    };
+    if denominator == 0.0 {
+        return Err(handlebars::RenderError::new(
+            "ratio: The denominator cannot be zero.",
+        ));
+    };
+
+    out.write(&format!(
+        "{}",
+        round_value(numerator / denominator, decimals_argument(h, 2)?)
+    ))?;
+
+    Ok(())
+}
+
+/// Helper to write the relative change between an old and a new value, in percent.
+///
+/// Given the data:
+/// ```
+/// {
+///     "old": 80,
+///     "new": 100,
+///     "new_pm": 4
+/// }
+/// ```
+/// the helper "{{change old new}}" will render as "`+25$\pm$5\%`".
+///
+/// The change always has a sign. If either value has a "_pm" key, the error is propagated.
+/// The number of decimals can be set with "decimals=1" (default 0), and the escaping of the
+/// percent sign can be turned off with "escape=false".
+fn change_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    context: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let ((old, old_pm), (new, new_pm)) = match (h.param(0), h.param(1)) {
+        (Some(a), Some(b)) => (
+            param_with_pm(a, context, "change")?,
+            param_with_pm(b, context, "change")?,
+        ),
+        _ => {
+            return Err(handlebars::RenderError::new::<String>(
+                "Two arguments are required: 'old' 'new'".into(),
+            ))
+        }
+    };
+    if old == 0.0 {
+        return Err(handlebars::RenderError::new(
+            "change: The old value cannot be zero.",
+        ));
+    };
+
+    let decimals = decimals_argument(h, 0)?;
+    let percent_sign = match h.hash_get("escape").and_then(|p| p.value().as_bool()) {
+        Some(false) => "%",
+        _ => "\\%",
+    };
+
+    // Adding zero turns a rounded "-0" into "0".
+    let change = round_value(100.0 * (new / old - 1.0), decimals) + 0.0;
+    let plus_sign = match change > 0.0 {
+        true => "+",
+        false => "",
+    };
+
+    // Propagate the errors of the old and new values (assuming they are independent).
+    let error = match (old_pm, new_pm) {
+        (None, None) => None,
+        (o, n) => {
+            let (o, n) = (o.unwrap_or(0.0), n.unwrap_or(0.0));
+            Some(100.0 * ((n / old).powi(2) + (new * o / old.powi(2)).powi(2)).sqrt())
+        }
+    };
+
+    match error {
+        Some(e) => out.write(&format!(
+            "{}{}$\\pm${}{}",
+            plus_sign,
+            change,
+            round_value(e, decimals),
+            percent_sign
+        ))?,
+        None => out.write(&format!("{}{}{}", plus_sign, change, percent_sign))?,
+    };
+
+    Ok(())
+}
+
+/// Helper to write a value and its unit with the siunitx package.
+///
+/// Given the data:
+/// ```
+/// {
+///     "volume": {"value": 2.5e8, "unit": "m^3"},
+///     "volume_pm": 1e7
+/// }
+/// ```
+/// the helper "{{si volume "km^3" decimals=2}}" will render as "`\SI{0.25 +- 0.01}{km^{3}}`".
+///
+/// The unit to convert to is optional. Values without units are written with "\num{}", and the
+/// "_pm" error is assumed to be in the same unit as the value.
+fn si_helper(
+    h: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    context: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let param = h
+        .param(0)
+        .ok_or_else(|| handlebars::RenderError::new("si: A value is required."))?;
+    let (mut value, mut unit) = match Quantity::from_json(param.value()) {
+        Some(q) => (q.value, Some(q.unit)),
+        None => (param_as_float(param.value(), "si")?, None),
+    };
+
+    // An error with a unit is converted to the unit of the value.
+    let mut pm = match find_pm(param, context) {
+        Some(v) => Some(match (Quantity::from_json(v), &unit) {
+            (Some(q), Some(u)) => {
+                q.convert(u)
+                    .map_err(|e| handlebars::RenderError::new(format!("si: {}", e)))?
+                    .value
+            }
+            (Some(_), None) => {
+                return Err(handlebars::RenderError::new(format!(
+                    "si: The error {} has a unit, but {} has none.",
+                    v,
+                    param.value()
+                )))
+            }
+            (None, _) => param_as_float(v, "si")?,
+        }),
+        None => None,
+    };
+
+    // Convert the value (and its error) to another unit if one was given.
+    if let Some(target) = h.param(1) {
+        let target = target
+            .value()
+            .as_str()
+            .ok_or_else(|| handlebars::RenderError::new("si: The unit has to be a string."))?;
+        let quantity = Quantity {
+            value,
+            unit: unit.ok_or_else(|| {
+                handlebars::RenderError::new(format!("si: {} has no unit.", param.value()))
+            })?,
+        };
+        let converted = quantity
+            .convert(target)
+            .map_err(|e| handlebars::RenderError::new(format!("si: {}", e)))?;
+        let factor = units::parse_unit(&quantity.unit).unwrap().factor
+            / units::parse_unit(target).unwrap().factor;
+
+        value = converted.value;
+        pm = pm.map(|e| e * factor);
+        unit = Some(converted.unit);
+    }
+
+    let format_number = |number: f64| match h.hash_get("decimals") {
+        Some(_) => decimals_argument(h, 0)
+            .map(|d| format!("{:.*}", d.max(0) as usize, round_value(number, d))),
+        None => Ok(float_to_json(number).to_string()),
+    };
+    let number = match pm {
+        Some(e) => format!("{} +- {}", format_number(value)?, format_number(e)?),
+        None => format_number(value)?,
+    };
+
+    match unit {
+        Some(u) => out.write(&format!("\\SI{{{}}}{{{}}}", number, units::to_siunitx(&u)))?,
+        None => out.write(&format!("\\num{{{}}}", number))?,
+    };
+
+    Ok(())
+}
+
+/// Parse a date or datetime string, like TOML datetimes are written in the data.
+///
+/// Dates ("2020-06-01"), local datetimes ("2020-06-01T12:00:00") and datetimes with an offset
+/// ("2020-06-01T12:00:00+02:00") are accepted. Offsets are ignored, i.e. the local time is used.
+///
+/// # Examples
+/// ```
+/// let date = parse_date(&json!["1979-05-27T07:32:00Z"], "date").unwrap();
+///
+/// assert_eq!(date.year(), 1979);
+/// ```
+fn parse_date(
+    value: &Json,
+    helper: &str,
+) -> Result<chrono::NaiveDateTime, handlebars::RenderError> {
+    let error = || {
+        handlebars::RenderError::new(format!("{}: Could not parse {} as a date.", helper, value))
+    };
+
+    let string = value.as_str().ok_or_else(error)?.trim();
+
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(string) {
+        return Ok(datetime.naive_local());
+    }
+    for format in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(string, format) {
+            return Ok(datetime);
+        }
+    }
+    match chrono::NaiveDate::parse_from_str(string, "%Y-%m-%d") {
+        Ok(day) => Ok(day.and_hms_opt(0, 0, 0).unwrap()),
+        Err(_) => Err(error()),
+    }
+}
+
+/// Count the whole months between two dates. The count is negative if the end is before the start.
+fn months_between(start: &chrono::NaiveDateTime, end: &chrono::NaiveDateTime) -> i64 {
+    use chrono::Datelike;
+
+    if end < start {
+        return -months_between(end, start);
+    }
+    let months =
+        (end.year() - start.year()) as i64 * 12 + end.month() as i64 - start.month() as i64;
+
+    // The last month only counts if it is complete.
+    match (end.day(), end.time()) < (start.day(), start.time()) {
+        true => months - 1,
+        false => months,
+    }
+}
+
+/// Format a date with a strftime-like format, e.g. "{{date survey.start "%B %Y"}}" => "June 2020".
+///
+/// The format defaults to "%Y-%m-%d".
+fn date_helper(args: &[&Json]) -> Result<Json, handlebars::RenderError> {
+    let value = args
+        .first()
+        .ok_or_else(|| handlebars::RenderError::new("date: A date is required."))?;
+    let format = match args.get(1) {
+        Some(f) => f
+            .as_str()
+            .ok_or_else(|| handlebars::RenderError::new("date: The format has to be a string."))?,
+        None => "%Y-%m-%d",
+    };
+
+    // Invalid formats make chrono panic when written, so they are checked first.
+    if chrono::format::StrftimeItems::new(format).any(|item| item == chrono::format::Item::Error) {
+        return Err(handlebars::RenderError::new(format!(
+            "date: Invalid format: {}",
+            format
+        )));
+    }
+
+    Ok(Json::String(
+        parse_date(value, "date")?.format(format).to_string(),
+    ))
+}
+
+handlebars_helper!(date: |*args| date_helper(&args)?);
+
+/// Count the whole days, months or years between two dates.
+///
+/// The unit defaults to "days", e.g. "{{duration survey.start survey.end "months"}}" => 3.
+fn duration_helper(args: &[&Json]) -> Result<Json, handlebars::RenderError> {
+    let (start, end) = match (args.first(), args.get(1)) {
+        (Some(a), Some(b)) => (parse_date(a, "duration")?, parse_date(b, "duration")?),
+        _ => {
+            return Err(handlebars::RenderError::new(
+                "duration: Two dates are required.",
+            ))
+        }
+    };
+
+    let count = match args.get(2).map(|u| u.as_str()) {
+        None | Some(Some("days")) => (end - start).num_days(),
+        Some(Some("months")) => months_between(&start, &end),
+        Some(Some("years")) => months_between(&start, &end) / 12,
+        _ => {
+            return Err(handlebars::RenderError::new(
+                "duration: The unit has to be \"days\", \"months\" or \"years\".",
+            ))
+        }
+    };
+
+    Ok(Json::from(count))
+}
+
+handlebars_helper!(duration: |*args| duration_helper(&args)?);
+
+handlebars_helper!(year: |value: Json| chrono::Datelike::year(&parse_date(value, "year")?));
+handlebars_helper!(month: |value: Json| chrono::Datelike::month(&parse_date(value, "month")?));
+
 /// Try to parse a JSON value as i32.
 ///
 ///
@@ -469,10 +1263,7 @@ fn json_as_integer(value: &Json) -> Result<i64, String> {
 fn json_as_float(value: &Json) -> Result<f64, String> {
     let parsed: Option<f64> = match value {
         Json::Number(n) => n.as_f64(),
-        Json::String(s) => match s.to_string().parse::<f64>() {
-            Ok(x) => Some(x),
-            Err(_) => None,
-        },
+        Json::String(s) => s.to_string().parse::<f64>().ok(),
         _ => None,
     };
     match parsed {
@@ -507,28 +1298,242 @@ fn json_as_float(value: &Json) -> Result<f64, String> {
 /// ```
 /// assert_eq!(round_value(8999.0, -3), 9000.0);
 /// ```
-fn round_value(value: f64, decimals: i64) -> f64 {
+pub fn round_value(value: f64, decimals: i64) -> f64 {
     (value * 10_f64.powi(decimals as i32)).round() / 10_f64.powi(decimals as i32)
 }
 
+/// Helper to evaluate an expression written directly in the tex, e.g. "{{expr "a - b"}}".
+///
+/// The expression is evaluated against the data (with expressions already evaluated). Inside
+/// blocks like "{{#each samples}}", the keys of the current element can be used directly or with
+/// "this", e.g. "{{expr "this.dh * 2"}}".
+struct InlineExpression;
+
+impl handlebars::HelperDef for InlineExpression {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        _: &'reg handlebars::Handlebars<'reg>,
+        context: &'rc handlebars::Context,
+        rc: &mut handlebars::RenderContext<'reg, 'rc>,
+    ) -> Result<Option<handlebars::ScopedJson<'reg, 'rc>>, handlebars::RenderError> {
+        let source = h.param(0).and_then(|p| p.value().as_str()).ok_or_else(|| {
+            handlebars::RenderError::new("expr: An expression string is required.")
+        })?;
+
+        let root = rc.context();
+        let data = match &root {
+            Some(c) => c.data(),
+            None => context.data(),
+        };
+
+        // The current block element is available both as "this" and by its keys.
+        let current = rc.block().and_then(|block| match block.base_value() {
+            Some(v) => Some(v),
+            None if block.base_path().is_empty() => None,
+            None => expressions::get_by_path(data, &block.base_path().join(".")),
+        });
+        let mut lookup = |key: &str| -> Result<Json, String> {
+            let value = match (current, key.strip_prefix("this")) {
+                (Some(c), Some("")) => Some(c),
+                (Some(c), Some(rest)) if rest.starts_with('.') => {
+                    expressions::get_by_path(c, &rest[1..])
+                }
+                (Some(c @ Json::Object(_)), _) => {
+                    expressions::get_by_path(c, key).or_else(|| expressions::get_by_path(data, key))
+                }
+                _ => expressions::get_by_path(data, key),
+            };
+            match value {
+                Some(v) => Ok(v.clone()),
+                None => find_constant(key),
+            }
+        };
+
+        // The data are already evaluated, so the keys do not contain expressions themselves.
+        let result = expressions::parse(source)
+            .map_err(|e| e.to_string())
+            .and_then(|parsed| parsed.evaluate(&mut lookup))
+            .and_then(|value| match value {
+                Json::Null => Err("The expression returned Null value".into()),
+                v => Ok(v),
+            })
+            .map_err(|e| format!("Error in expression: '{}': {}", source, e));
+
+        match result {
+            // Quantities are written like in the data files ("200 ha"), which "si" also reads.
+            Ok(value) => Ok(Some(handlebars::ScopedJson::Derived(
+                match Quantity::from_json(&value) {
+                    Some(q) => Json::String(q.to_text()),
+                    None => value,
+                },
+            ))),
+            Err(e) => Err(handlebars::RenderError::new(format!("expr: {}", e))),
+        }
+    }
+}
+
+/// Find the "}}" that ends an inline expression, skipping over quoted strings like "a}}b".
+fn closing_braces(expression: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in expression.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '}') if expression[i..].starts_with("}}") => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Rewrite the inline expression shorthand "{{= a - b}}" to "{{expr "a - b"}}".
+///
+/// # Examples
+/// ```
+/// assert_eq!(
+///     expand_inline_expressions("{{= n_total - n_eaten }} pies"),
+///     "{{expr \"n_total - n_eaten\"}} pies"
+/// );
+/// ```
+pub fn expand_inline_expressions(line: &str) -> String {
+    let mut output = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("{{=") {
+        let end = match closing_braces(&rest[start + 3..]) {
+            Some(i) => start + 3 + i,
+            None => break,
+        };
+        let expression = rest[start + 3..end].trim();
+
+        // Handlebars strings are JSON strings, so quotes inside the expression are escaped.
+        output += &rest[..start];
+        output += &format!("{{{{expr {}}}}}", Json::String(expression.to_owned()));
+        rest = &rest[end + 2..];
+    }
+    output + rest
+}
+
+/// A helper that renders the data key with the same name if it is called without arguments.
+///
+/// Handlebars prefers helpers over keys with the same name, so without this, a "{{change}}" in a
+/// manuscript would suddenly call the "change" helper instead of writing the "change" key.
+struct KeyOrHelper(Box<dyn handlebars::HelperDef + Send + Sync>);
+
+impl handlebars::HelperDef for KeyOrHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        r: &'reg handlebars::Handlebars<'reg>,
+        context: &'rc handlebars::Context,
+        rc: &mut handlebars::RenderContext<'reg, 'rc>,
+    ) -> Result<Option<handlebars::ScopedJson<'reg, 'rc>>, handlebars::RenderError> {
+        self.0.call_inner(h, r, context, rc)
+    }
+
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &handlebars::Helper<'reg, 'rc>,
+        r: &'reg handlebars::Handlebars<'reg>,
+        context: &'rc handlebars::Context,
+        rc: &mut handlebars::RenderContext<'reg, 'rc>,
+        out: &mut dyn handlebars::Output,
+    ) -> handlebars::HelperResult {
+        if h.params().is_empty() & h.hash().is_empty() & !h.is_block() {
+            let root = rc.context();
+            let data = match &root {
+                Some(c) => c.data(),
+                None => context.data(),
+            };
+            // Like other keys, it is looked up in the current block (e.g. the element of an
+            // "{{#each}}") first, and then at the top level of the data.
+            let in_block = rc.block().and_then(|block| match block.base_value() {
+                Some(v) => v.get(h.name()).cloned(),
+                None => expressions::get_by_path(data, &block.base_path().join("."))
+                    .and_then(|v| v.get(h.name()))
+                    .cloned(),
+            });
+            if let Some(value) = in_block.or_else(|| data.get(h.name()).cloned()) {
+                use handlebars::JsonRender;
+                out.write(&r.get_escape_fn()(&value.render()))?;
+                return Ok(());
+            }
+        }
+        self.0.call(h, r, context, rc, out)
+    }
+}
+
+/// Create a handlebars registry with all helpers registered, and track the acronyms it writes.
+///
+/// # Arguments
+/// * `usage`: Where to keep track of which acronyms have been used.
+pub fn create_registry(usage: acronyms::Usage) -> handlebars::Handlebars<'static> {
+    let helpers: Vec<(&str, Box<dyn handlebars::HelperDef + Send + Sync>)> = vec![
+        ("upper", Box::new(upper)),
+        ("lower", Box::new(lower)),
+        ("round", Box::new(round_helper)),
+        ("roundup", Box::new(roundup_helper)),
+        ("pm", Box::new(pm_helper)),
+        ("sep", Box::new(sep_helper)),
+        ("pow", Box::new(exponent_helper)),
+        ("gt", Box::new(gt)),
+        ("lt", Box::new(lt)),
+        ("eq", Box::new(eq)),
+        ("between", Box::new(between)),
+        ("add", Box::new(add)),
+        ("sub", Box::new(sub)),
+        ("mul", Box::new(mul)),
+        ("div", Box::new(div)),
+        ("abs", Box::new(abs)),
+        ("plural", Box::new(plural)),
+        ("sign", Box::new(sign)),
+        ("pick", Box::new(pick)),
+        ("words", Box::new(words_helper)),
+        ("ordinal", Box::new(ordinal_helper)),
+        ("smallwords", Box::new(smallwords_helper)),
+        ("percent", Box::new(percent_helper)),
+        ("ratio", Box::new(ratio_helper)),
+        ("change", Box::new(change_helper)),
+        ("si", Box::new(si_helper)),
+        ("date", Box::new(date)),
+        ("duration", Box::new(duration)),
+        ("year", Box::new(year)),
+        ("month", Box::new(month)),
+        ("expr", Box::new(InlineExpression)),
+        ("figure", Box::new(figures::figure_helper)),
+        ("ref", Box::new(refs::ref_helper)),
+        ("cref", Box::new(refs::cref_helper)),
+        ("acr", Box::new(acronyms::AcronymHelper(usage))),
+        ("acrlist", Box::new(acronyms::acrlist_helper)),
+    ];
+
+    let mut reg = handlebars::Handlebars::new();
+    for (name, helper) in helpers {
+        reg.register_helper(name, Box::new(KeyOrHelper(helper)));
+    }
+    reg.set_strict_mode(true);
+
+    reg
+}
+
 /// Fill a vector of text with data using templating.
 pub fn fill_data(lines: &[String], data: &serde_json::Value) -> Result<Vec<String>, String> {
-    let parsed_data = evaluate_all_expressions(data)?;
+    let parsed_data = units::quantities_as_text(&evaluate_all_expressions(data)?);
 
     let mut new_lines: Vec<String> = Vec::new();
 
-    let mut reg = handlebars::Handlebars::new();
-    reg.register_helper("upper", Box::new(upper));
-    reg.register_helper("lower", Box::new(lower));
-    reg.register_helper("round", Box::new(round_helper));
-    reg.register_helper("roundup", Box::new(roundup_helper));
-    reg.register_helper("pm", Box::new(pm_helper));
-    reg.register_helper("sep", Box::new(sep_helper));
-    reg.register_helper("pow", Box::new(exponent_helper));
-    reg.set_strict_mode(true);
+    // Acronyms are written in full the first time they are used in the whole manuscript.
+    let usage = acronyms::Usage::default();
+    let reg = create_registry(usage.clone());
 
     for (i, line) in lines.iter().enumerate() {
-        match reg.render_template(line, &parsed_data) {
+        let rendered = reg.render_template(&expand_inline_expressions(line), &parsed_data);
+        usage.end_line(rendered.is_ok());
+        match rendered {
             Ok(l) => new_lines.push(l),
             Err(e) => {
                 let re = e.as_render_error();
@@ -557,6 +1562,7 @@ pub fn fill_data(lines: &[String], data: &serde_json::Value) -> Result<Vec<Strin
             }
         */
     }
+    acronyms::insert_lists(&mut new_lines, &parsed_data, &usage)?;
 
     Ok(new_lines)
 }
@@ -570,7 +1576,7 @@ pub fn fill_data(lines: &[String], data: &serde_json::Value) -> Result<Vec<Strin
 /// # Returns
 /// A vector of expressions, where each expression is (vector of keys to find it, expression).
 /// If no expressions are found, this will be empty.
-fn find_expressions(data: &Json, parent: Option<&Vec<String>>) -> Vec<(Vec<String>, String)> {
+pub fn find_expressions(data: &Json, parent: Option<&Vec<String>>) -> Vec<(Vec<String>, String)> {
     // The parent relative to the current tree is empty if parent was None or the given parent.
     let relative_parent: Vec<String> = match parent {
         Some(p) => p.to_owned(),
@@ -583,10 +1589,14 @@ fn find_expressions(data: &Json, parent: Option<&Vec<String>>) -> Vec<(Vec<Strin
     // If the json is an array, parse all expressions in the array.
     if let Json::Array(arr) = data {
         // Loop through the array
-        for val in arr {
+        for (i, val) in arr.iter().enumerate() {
+            // The relative parent of this value is the upper relative parent plus the index.
+            let mut relative_parent2 = relative_parent.to_owned();
+            relative_parent2.push(i.to_string());
+
             // Recursively find all expressions in the json value.
             // The parent argument helps retaining the right tree structure.
-            let expressions = find_expressions(val, Some(&relative_parent));
+            let expressions = find_expressions(val, Some(&relative_parent2));
 
             // Push all found expressions into the output.
             for expression in expressions {
@@ -622,129 +1632,90 @@ fn find_expressions(data: &Json, parent: Option<&Vec<String>>) -> Vec<(Vec<Strin
     output
 }
 
-/// Evaluate a mathematical expression return a useful error if it fails.
-///
-/// It is basically just calling the "eval" crate, but handles error messages better than the
-/// crate does per default.
+/// Evaluate an already parsed expression.
 ///
 /// # Arguments
-/// * `expr_string`: The expression to evaluate.
+/// * `expr_string`: The expression as written (for the error messages).
+/// * `expression`: The parsed expression.
 /// * `data`: The data "context" to get variables from.
-///
-/// # Returns
-/// The result of the evaluated expression, or an error detailing why it failed.
-fn run_eval(expr_string: &str, data: &Json) -> Result<Json, String> {
-    // Create an expression object from the string.
-    let mut expr = eval::Expr::new(expr_string);
-
-    // All this is to implement the round function. Oboy!
-    expr = expr.function("round", |args: Vec<Json>| {
-        // Parse the first argument as the value to round.
-        let value = match args.get(0) {
-            Some(Json::Number(x)) => x.as_f64().unwrap(),
-            _ => return Err(eval::Error::ExpectedNumber),
-        };
-        // Parse the second argument as the decimal.
-        let decimals = match args.get(1) {
-            // If it's a number, parse it as f64
-            Some(Json::Number(x)) => x.as_f64().unwrap(),
-            // If it's anything else than a number, return an error
-            Some(_) => return Err(eval::Error::ExpectedNumber),
-            // If the argument was not given, default to 0
-            None => 0.0,
-        };
-
-        // Return an error if the decimal number is not equivalent to an integer.
-        if decimals.fract() > 0.0 {
-            return Err(eval::Error::Custom(format!(
-                "Second rounding argument must be an integer. Given value: {}",
-                decimals
-            )));
-        };
-
-        // Round the number and return it appropriately.
-        let rounded = round_value(value, decimals as i64);
-
-        // If the value is equivalent of an integer, return an integer form of it.
-        match rounded.fract() == 0.0 {
-            true => Ok(serde_json::json!(rounded as i64)),
-            false => Ok(serde_json::json!(rounded)),
-        }
-    });
-
-    // Function to raise a value to the power of an exponent.
-    expr = expr.function("pow", |args: Vec<Json>| {
-        let value = match args.get(0) {
-            Some(Json::Number(x)) => x.as_f64().unwrap(),
-            _ => return Err(eval::Error::ExpectedNumber),
-        };
-
-        let exponent = match args.get(1) {
-            Some(Json::Number(x)) => x.as_f64().unwrap(),
-            _ => return Err(eval::Error::ExpectedNumber),
-        };
-
-        let product = match exponent >= 0.0 {
-            true => value.powf(exponent),
-            false => 1.0 / value.powf(-exponent),
-        };
-
-        match product.fract() == 0.0 {
-            true => Ok(serde_json::json!(product as i64)),
-            false => Ok(serde_json::json!(product)),
-        }
-    });
-
-    // Function to expand powers of ten
-    expr = expr.function("E", |args: Vec<Json>| {
-        if args.len() != 1 {
-            return Err(eval::Error::ArgumentsGreater(1));
-        };
-
-        let exponent = match args.get(0) {
-            Some(x) => match json_as_float(x) {
-                Ok(v) => v,
-                Err(e) => return Err(eval::Error::Custom(e)),
-            },
-            _ => return Err(eval::Error::ExpectedNumber),
-        };
-
-        let product = match exponent >= 0.0 {
-            true => 10_f64.powf(exponent),
-            false => 1.0 / 10_f64.powf(-exponent),
-        };
-
-        match product.fract() == 0.0 {
-            true => Ok(serde_json::json!(product as i64)),
-            false => Ok(serde_json::json!(product)),
-        }
-    });
-
-    // Fill the expression with variables from the data.
-    // TODO: Look into if the "json has to be object" check may have side-effects.
-    if let Json::Object(obj) = data {
-        for (key, val) in obj {
-            expr = expr.value(key, val);
+/// * `parents`: The keys of the expressions that are being evaluated (to find circular ones).
+/// * `record`: Optional. A function that is given each evaluated part of the expression and its
+///   value.
+fn evaluate_parsed(
+    expr_string: &str,
+    expression: &expressions::Expression,
+    data: &Json,
+    parents: &[String],
+    record: Option<&mut expressions::Recorder>,
+) -> Result<Json, String> {
+    let mut lookup = |key: &str| -> Result<Json, String> {
+        match expressions::get_by_path(data, key) {
+            Some(value) => evaluate_nested(value, key, data, parents),
+            None => find_constant(key),
         }
     };
 
-    // Execute the expression.
-    match expr.exec() {
-        Err(err) => {
-            let mut err_str = err.to_string();
+    let value = match record {
+        Some(r) => expression.evaluate_recorded(&mut lookup, r),
+        None => expression.evaluate(&mut lookup),
+    };
+    match value {
+        Err(e) => Err(format!("Error in expression: '{}': {}", expr_string, e)),
+        Ok(Json::Null) => Err(format!("Expression '{}' returned Null value", expr_string)),
+        Ok(v) => Ok(v),
+    }
+}
 
-            // If a null was encountered, it is likely that a conexistent key was indexed.
-            if err_str.contains("Null") {
-                err_str += ". Perhaps a key is misspelled?"
-            }
+/// Find a constant like "pi" for a key that is not in the data.
+fn find_constant(key: &str) -> Result<Json, String> {
+    match crate::functions::CONSTANTS
+        .iter()
+        .find(|(name, _)| *name == key)
+    {
+        Some((_, value)) => Ok(Json::from(*value)),
+        None => Err(format!(
+            "Key '{}' not found. Perhaps a key is misspelled?",
+            key
+        )),
+    }
+}
 
-            Err(format!(
-                "Error in expression: '{}': {}",
-                expr_string, err_str
-            ))
+/// Evaluate all expressions inside a value that an expression refers to.
+///
+/// # Arguments
+/// * `value`: The value to evaluate expressions in.
+/// * `key`: The dotted key path of the value.
+/// * `data`: The data "context" to get variables from.
+/// * `parents`: The keys of the expressions that are being evaluated.
+fn evaluate_nested(
+    value: &Json,
+    key: &str,
+    data: &Json,
+    parents: &[String],
+) -> Result<Json, String> {
+    match value {
+        Json::String(s) if s.trim().starts_with("expr:") => {
+            let mut new_parents = parents.to_vec();
+            new_parents.push(key.to_owned());
+            evaluate_expression(s, data, &new_parents)
         }
-        Ok(Json::Null) => Err(format!("Expression '{}' returned Null value", expr_string)),
-        Ok(v) => Ok(v),
+        Json::Array(arr) => Ok(Json::Array(
+            arr.iter()
+                .enumerate()
+                .map(|(i, v)| evaluate_nested(v, &format!("{}.{}", key, i), data, parents))
+                .collect::<Result<Vec<Json>, String>>()?,
+        )),
+        Json::Object(obj) => Ok(Json::Object(
+            obj.iter()
+                .map(|(k, v)| {
+                    Ok((
+                        k.to_owned(),
+                        evaluate_nested(v, &format!("{}.{}", key, k), data, parents)?,
+                    ))
+                })
+                .collect::<Result<serde_json::Map<String, Json>, String>>()?,
+        )),
+        v => Ok(v.clone()),
     }
 }
 
@@ -753,39 +1724,75 @@ fn run_eval(expr_string: &str, data: &Json) -> Result<Json, String> {
 /// # Arguments
 /// * `expression`: The expression to evaluate.
 /// * `data`: The "context" data to parse variables from.
-/// * `recursion_depth`: The current recursion depth (only needed internally).
-fn evaluate_expression(
-    expression: &str,
-    data: &Json,
-    recursion_depth: usize,
-) -> Result<Json, String> {
-    // Avoid circular expressions by setting a max recursion depth.
-    if recursion_depth > 1000 {
-        return Err(format!(
-            "Max recursion depth reached for expression: '{}'. Maybe due to a circular expression?",
-            expression
-        ));
-    };
+/// * `parents`: The keys of the expressions that are being evaluated (only needed internally).
+fn evaluate_expression(expression: &str, data: &Json, parents: &[String]) -> Result<Json, String> {
+    // Avoid circular expressions by checking that no expression depends on itself.
+    if let Some((key, others)) = parents.split_last() {
+        if others.contains(key) {
+            return Err(format!(
+                "Infinite recursion for expression in '{}': {}. Is it a circular expression?",
+                key,
+                parents.join(" -> ")
+            ));
+        }
+    }
 
     // Format the expression string and remove the "expr:" part.
-    let mut expr_string = expression.replacen("expr:", "", 1).trim().to_owned();
-
-    // Find any expressions in the data and check if an associated key is referred to in the
-    // expression.
-    let expressions = find_expressions(data, None);
-    for (keys, expression_str) in &expressions {
-        // If the key exists in the current expression, evaluate the referred expression first.
-        // TODO: Maybe make data mutable so all expressions only have to be evaluated once?
-        if expr_string.contains(&keys.join(".")) {
-            // Evaluate the referred expression.
-            let value = evaluate_expression(&expression_str, &data, recursion_depth + 1)?;
-            // Replace its key in the current expression with the evaluated value.
-            expr_string = expr_string.replace(&keys.join("."), &value.to_string());
+    let expr_string = expression.trim().trim_start_matches("expr:").trim();
+
+    let parsed = expressions::parse(expr_string)
+        .map_err(|e| format!("Error in expression: '{}': {}", expr_string, e))?;
+
+    evaluate_parsed(expr_string, &parsed, data, parents, None)
+}
+
+/// Sort expressions so that each one comes after the expressions that it refers to.
+///
+/// Circular expressions are left in any order, and are caught when they are evaluated.
+///
+/// # Arguments
+/// * `keys`: The dotted key paths of the expressions.
+/// * `dependencies`: The key paths that each expression refers to.
+///
+/// # Returns
+/// The indices of the expressions in the order they should be evaluated.
+fn dependency_order(keys: &[String], dependencies: &[Vec<String>]) -> Vec<usize> {
+    fn visit(
+        i: usize,
+        keys: &[String],
+        dependencies: &[Vec<String>],
+        visited: &mut Vec<bool>,
+        order: &mut Vec<usize>,
+    ) {
+        if visited[i] {
+            return;
+        }
+        visited[i] = true;
+        for (j, key) in keys.iter().enumerate() {
+            if dependencies[i]
+                .iter()
+                .any(|path| expressions::refers_to(path, key))
+            {
+                visit(j, keys, dependencies, visited, order);
+            }
         }
+        order.push(i);
     }
 
-    // Now that all potential referred expressions have been evaluated, evaluate the current one.
-    run_eval(&expr_string, &data)
+    let mut visited = vec![false; keys.len()];
+    let mut order: Vec<usize> = Vec::new();
+    for i in 0..keys.len() {
+        visit(i, keys, dependencies, &mut visited, &mut order);
+    }
+    order
+}
+
+/// Get a mutable child of a json, by its key in an object or its index in an array.
+fn get_child_mut<'a>(data: &'a mut Json, key: &str) -> Option<&'a mut Json> {
+    match data {
+        Json::Array(arr) => arr.get_mut(key.parse::<usize>().ok()?),
+        d => d.get_mut(key),
+    }
 }
 
 /// Set data in a json at an arbitrary tree depth.
@@ -806,7 +1813,7 @@ fn replace_value_in_data(data: &mut Json, keys: &[String], value: Json) -> Resul
         let first_key = &keys[0];
 
         // Try to get the value of the first key.
-        let mut subset = match data.get_mut(first_key) {
+        let mut subset = match get_child_mut(data, first_key) {
             Some(s) => s,
             None => return Err("Key not found".into()),
         };
@@ -816,7 +1823,7 @@ fn replace_value_in_data(data: &mut Json, keys: &[String], value: Json) -> Resul
         // If the keys is a single key (it will be reached using recursion if not)...
         // ... try to set the value.
 
-        match data.get_mut(&keys[0]) {
+        match get_child_mut(data, &keys[0]) {
             Some(v) => (*v = value),
             None => return Err("Key not found".into()),
         };
@@ -825,6 +1832,19 @@ fn replace_value_in_data(data: &mut Json, keys: &[String], value: Json) -> Resul
     Ok(())
 }
 
+/// The evaluation of one expression, step by step.
+pub struct Explanation {
+    /// The dotted key path of the expression.
+    pub key: String,
+    /// The expression as written.
+    pub expression: String,
+    /// The key paths that the expression refers to.
+    pub dependencies: Vec<String>,
+    /// Each evaluated part of the expression (except literals) and its value, with the innermost
+    /// parts first and the whole expression last.
+    pub steps: Vec<(String, Json)>,
+}
+
 /// Try to evaluate all expressions in a data file.
 ///
 /// # Arguments
@@ -832,27 +1852,93 @@ fn replace_value_in_data(data: &mut Json, keys: &[String], value: Json) -> Resul
 ///
 /// # Returns
 /// A copy of the data file with expressions filled, or an error detailing why it failed.
-fn evaluate_all_expressions(data: &Json) -> Result<Json, String> {
+pub fn evaluate_all_expressions(data: &Json) -> Result<Json, String> {
+    evaluate_expressions_in_order(data, None)
+}
+
+/// Evaluate all expressions in a data file and explain how each value was reached.
+///
+/// # Arguments
+/// `data`: The data file to evaluate expressions inside.
+///
+/// # Returns
+/// A copy of the data file with expressions filled and an explanation of each expression, in the
+/// order they were evaluated, or an error detailing why it failed.
+pub fn explain_expressions(data: &Json) -> Result<(Json, Vec<Explanation>), String> {
+    let mut explanations: Vec<Explanation> = Vec::new();
+    let new_data = evaluate_expressions_in_order(data, Some(&mut explanations))?;
+
+    Ok((new_data, explanations))
+}
+
+/// Evaluate all expressions in a data file, after the expressions that they depend on.
+///
+/// # Arguments
+/// `data`: The data file to evaluate expressions inside.
+/// `explanations`: Optional. A vector to push the explanation of each expression to.
+fn evaluate_expressions_in_order(
+    data: &Json,
+    mut explanations: Option<&mut Vec<Explanation>>,
+) -> Result<Json, String> {
     let mut new_data = data.clone();
 
-    // Find all expressions and evaluate them (recursively if needed)
-    for (keys, expr_string) in find_expressions(data, None) {
-        let new_value = match evaluate_expression(&expr_string, &new_data, 0) {
-            Ok(v) => v,
+    // Parse all expressions once, to find syntax errors and what they depend on.
+    let mut key_vecs: Vec<Vec<String>> = Vec::new();
+    let mut parsed: Vec<(String, expressions::Expression)> = Vec::new();
+    for (key_vec, expr_string) in find_expressions(data, None) {
+        let expr_string = expr_string.trim().trim_start_matches("expr:").trim();
+        match expressions::parse(expr_string) {
+            Ok(expression) => parsed.push((expr_string.to_owned(), expression)),
             Err(e) => {
                 return Err(format!(
-                    "Error for expression in '{}' ('{}'): {:?}",
-                    keys.join("."),
+                    "Error for expression in '{}' ('{}'): {}",
+                    key_vec.join("."),
                     expr_string,
                     e
                 ))
             }
         };
+        key_vecs.push(key_vec);
+    }
+    let keys: Vec<String> = key_vecs.iter().map(|k| k.join(".")).collect();
+    let dependencies: Vec<Vec<String>> = parsed.iter().map(|(_, e)| e.dependencies()).collect();
+
+    // Evaluate the expressions after the ones they depend on, so that each is only evaluated once.
+    for i in dependency_order(&keys, &dependencies) {
+        let (expr_string, expression) = &parsed[i];
+        let mut steps: Vec<(String, Json)> = Vec::new();
+        let mut record = |part: &expressions::Expression, value: &Json| {
+            if !matches!(part, expressions::Expression::Literal(_)) {
+                steps.push((part.to_string(), value.clone()));
+            }
+        };
+        let record: Option<&mut expressions::Recorder> = match explanations {
+            Some(_) => Some(&mut record),
+            None => None,
+        };
+        let new_value =
+            match evaluate_parsed(expr_string, expression, &new_data, &keys[i..=i], record) {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(format!(
+                        "Error for expression in '{}' ('{}'): {:?}",
+                        keys[i], expr_string, e
+                    ))
+                }
+            };
         // Replace the expression with the evaluated value.
-        match replace_value_in_data(&mut new_data, &keys, new_value) {
+        match replace_value_in_data(&mut new_data, &key_vecs[i], new_value) {
             Ok(_) => (),
-            Err(e) => return Err(format!("Error setting key '{}': {}", keys.join("."), e)),
+            Err(e) => return Err(format!("Error setting key '{}': {}", keys[i], e)),
         };
+        if let Some(explanations) = explanations.as_mut() {
+            explanations.push(Explanation {
+                key: keys[i].to_owned(),
+                expression: expr_string.to_owned(),
+                dependencies: dependencies[i].to_owned(),
+                steps,
+            });
+        }
     }
     Ok(new_data)
 }
@@ -861,6 +1947,7 @@ fn evaluate_all_expressions(data: &Json) -> Result<Json, String> {
 mod tests {
 
     use super::*;
+    use assert_approx_eq::assert_approx_eq;
     use std::path::PathBuf;
 
     #[test]
@@ -967,6 +2054,259 @@ mod tests {
         assert_eq!(new_lines[3], "-123,456,789$\\pm$12,456");
     }
 
+    #[test]
+    fn test_logic_helpers() {
+        let lines: Vec<String> = vec![
+            "The glacier {{#if (gt change 0)}}advanced{{else}}retreated{{/if}}.".into(),
+            "It {{sign change \"advanced\" \"retreated\"}} {{abs change}} m, a {{sign change}}.".into(),
+            "{{#if (between change -10 10)}}A small change{{/if}} of {{round 1 (div (abs change) years)}} m/yr.".into(),
+            "{{sep (mul (sub n_total 1) 1000)}} {{#if (eq region \"north\")}}northern{{/if}}".into(),
+            "{{n_sites}} {{plural n_sites \"site\"}}, 1 {{plural 1 \"site\"}}, 2 {{plural 2 \"ox\" \"oxen\"}}".into(),
+            "{{pick 1 \"zero\" \"one\"}} {{pick (lt change 0) \"negative\" \"positive\"}} {{add (round change) 1}}".into(),
+            "{{div 1 0}}".into(),
+            "{{sign zero}}, {{sign zero \"advanced\" \"retreated\" \"stood still\"}}".into(),
+        ];
+
+        let data = serde_json::json!({
+            "change": -7.5,
+            "zero": 0,
+            "years": 3,
+            "n_total": 2001,
+            "region": "north",
+            "n_sites": 3,
+            "separator": ","
+        });
+
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "The glacier retreated.");
+        assert_eq!(new_lines[1], "It retreated 7.5 m, a decrease.");
+        assert_eq!(new_lines[2], "A small change of 2.5 m/yr.");
+        assert_eq!(new_lines[3], "2,000,000 northern");
+        assert_eq!(new_lines[4], "3 sites, 1 site, 2 oxen");
+        assert_eq!(new_lines[5], "one negative -7");
+        // Division by zero fails, so the line is left as it was.
+        assert_eq!(new_lines[6], "{{div 1 0}}");
+        assert_eq!(new_lines[7], "no change, stood still");
+
+        assert_eq!(float_to_json(-3.0), serde_json::json!(-3));
+        assert_eq!(float_to_json(1e20), serde_json::json!(1e20));
+    }
+
+    #[test]
+    fn test_word_helpers() {
+        assert_eq!(number_to_words(0), "zero");
+        assert_eq!(number_to_words(13), "thirteen");
+        assert_eq!(number_to_words(40), "forty");
+        assert_eq!(number_to_words(-1042), "minus one thousand forty-two");
+        assert_eq!(
+            number_to_words(2_000_315),
+            "two million three hundred fifteen"
+        );
+        assert_eq!(number_to_ordinal(1, false), "1st");
+        assert_eq!(number_to_ordinal(12, false), "12th");
+        assert_eq!(number_to_ordinal(23, false), "23rd");
+        assert_eq!(number_to_ordinal(111, false), "111th");
+        assert_eq!(number_to_ordinal(2, true), "second");
+        assert_eq!(number_to_ordinal(20, true), "twentieth");
+        assert_eq!(number_to_ordinal(21, true), "twenty-first");
+        assert_eq!(number_to_ordinal(112, true), "one hundred twelfth");
+        assert_eq!(number_to_ordinal(i64::MIN, false), "-9223372036854775808th");
+        assert!(number_to_ordinal(i64::MIN, true).ends_with(" eight hundred eighth"));
+
+        let lines: Vec<String> = vec![
+            "{{words n_sites capitalize=true}} sites were visited.".into(),
+            "It was the {{ordinal campaign}} ({{ordinal campaign words=true}}) campaign.".into(),
+            "{{smallwords n_sites}} sites, {{smallwords n_measurements}} measurements.".into(),
+            "{{smallwords n_measurements below=100000 capitalize=true}}".into(),
+            "{{smallwords 12.5}}".into(),
+        ];
+
+        let data = serde_json::json!({
+            "n_sites": 3,
+            "campaign": 2,
+            "n_measurements": 85819,
+            "separator": "\\,"
+        });
+
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "Three sites were visited.");
+        assert_eq!(new_lines[1], "It was the 2nd (second) campaign.");
+        assert_eq!(new_lines[2], "three sites, 85\\,819 measurements.");
+        assert_eq!(new_lines[3], "Eighty-five thousand eight hundred nineteen");
+        assert_eq!(new_lines[4], "12.5");
+
+        // A separator that is not a string is an error, not a panic.
+        let data = serde_json::json!({"n_measurements": 85819, "separator": 1});
+        let lines: Vec<String> = vec!["{{smallwords n_measurements}}".into()];
+        assert_eq!(fill_data(&lines, &data).unwrap(), lines);
+    }
+
+    #[test]
+    fn test_relative_helpers() {
+        let lines: Vec<String> = vec![
+            "A {{percent fraction}} or {{percent 1 decimals=1 escape=false}} increase.".into(),
+            "{{percent n_eaten n_total}} of the snacks were eaten.".into(),
+            "The ratio is {{ratio n_eaten n_total}}, or {{ratio n_eaten n_total decimals=1}}."
+                .into(),
+            "The area changed by {{change area.old area.new}}.".into(),
+            "The volume changed by {{change volume_old volume_new decimals=1}}.".into(),
+            "{{percent 1 0}}".into(),
+        ];
+
+        let data = serde_json::json!({
+            "fraction": 0.2345,
+            "n_eaten": 1567,
+            "n_total": 2042,
+            "area": {"old": 80, "new": 60},
+            "volume_old": 80,
+            "volume_old_pm": 8,
+            "volume_new": 100,
+            "volume_new_pm": 5,
+        });
+
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "A 23\\% or 100% increase.");
+        assert_eq!(new_lines[1], "77\\% of the snacks were eaten.");
+        assert_eq!(new_lines[2], "The ratio is 0.77, or 0.8.");
+        assert_eq!(new_lines[3], "The area changed by -25\\%.");
+        // sqrt((5 / 80)^2 + (100 * 8 / 80^2)^2) = 0.1398
+        assert_eq!(new_lines[4], "The volume changed by +25$\\pm$14\\%.");
+        assert_eq!(new_lines[5], "{{percent 1 0}}");
+
+        // Keys that share a name with a helper are still written as before.
+        let lines: Vec<String> = vec!["The {{change}} was a {{sign}}.".into()];
+        let data = serde_json::json!({"change": 1.4, "sign": "surprise"});
+
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "The 1.4 was a surprise.");
+
+        // Also inside blocks, where the keys of the current element are used.
+        let lines: Vec<String> = vec![
+            "{{#each glaciers}}{{change}} in {{year}};{{/each}}".into(),
+            "{{#with results}}{{ratio}}{{/with}} {{change 1000 999.9}}".into(),
+        ];
+        let data = serde_json::json!({
+            "glaciers": [{"change": -2, "year": 2001}, {"change": 3, "year": 2002}],
+            "results": {"ratio": 0.5},
+        });
+
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "-2 in 2001;3 in 2002;");
+        assert_eq!(new_lines[1], "0.5 0\\%");
+    }
+
+    #[test]
+    fn test_date_helpers() {
+        let lines: Vec<String> = vec![
+            "Surveyed between {{date survey.start \"%B %Y\"}} and {{date survey.end}}.".into(),
+            "It took {{duration survey.start survey.end}} days, or {{duration survey.start survey.end \"months\"}} months.".into(),
+            "The {{year launch}} launch in month {{month launch}} was {{duration launch survey.end \"years\"}} years before.".into(),
+            "{{date survey.start \"%Q\"}}".into(),
+        ];
+
+        let data = serde_json::json!({
+            "survey": {"start": "2020-06-01", "end": "2020-09-15T12:00:00Z"},
+            "launch": "2017-10-02T08:00:00",
+        });
+
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "Surveyed between June 2020 and 2020-09-15.");
+        assert_eq!(new_lines[1], "It took 106 days, or 3 months.");
+        assert_eq!(
+            new_lines[2],
+            "The 2017 launch in month 10 was 2 years before."
+        );
+        assert_eq!(new_lines[3], "{{date survey.start \"%Q\"}}");
+    }
+
+    #[test]
+    fn test_inline_expressions() {
+        let data = serde_json::json!({
+            "n_total": 12,
+            "n_eaten": "expr: n_total / 3",
+            "big_number": 12345.678,
+            "separator": "\\,",
+            "value_pm": 0.5,
+            "area": "2 km^2",
+            "samples": [{"dh": 1.5}, {"dh": 2}],
+        });
+        let lines: Vec<String> = vec![
+            "{{= n_total - n_eaten }} pies".into(),
+            "{{expr \"n_total - n_eaten\"}} pies".into(),
+            "{{round 1 (expr \"n_eaten / 3\")}}".into(),
+            "{{sep (expr \"big_number * 10\")}}".into(),
+            "{{pm 1 (expr \"n_eaten + 0.5\") pm=value_pm}}".into(),
+            "{{= to(area, \"ha\") }} or {{expr \"to(area, 'km^2')\"}}".into(),
+            "{{#each samples}}{{= this.dh * 2}},{{= dh + n_total}};{{/each}}".into(),
+            "{{= \"a}}b\" + 'c}}' }} and {{= n_total}}".into(),
+        ];
+        let new_lines = fill_data(&lines, &data).unwrap();
+
+        assert_eq!(new_lines[0], "8 pies");
+        assert_eq!(new_lines[1], "8 pies");
+        assert_eq!(new_lines[2], "1.3");
+        assert_eq!(new_lines[3], "123\\,456.78");
+        assert_eq!(new_lines[4], "4.5$\\pm$0.5");
+        assert_eq!(new_lines[5], "200 ha or 2 km^2");
+        assert_eq!(new_lines[6], "3,13.5;4,14;");
+        assert_eq!(new_lines[7], "a}}bc}} and 12");
+
+        // Errors leave the line unchanged.
+        let lines: Vec<String> = vec!["{{= missing + 1}}".into()];
+        assert_eq!(fill_data(&lines, &data).unwrap()[0], lines[0]);
+    }
+
+    #[test]
+    fn test_units() {
+        let lines: Vec<String> = vec![
+            "A volume of {{si volume \"km^3\" decimals=2}} ({{si volume}}).".into(),
+            "The mass loss was {{si rate decimals=1}} over {{si area}}.".into(),
+            "{{si 3}}, {{area}} and {{volume}}".into(),
+        ];
+
+        let data = serde_json::json!({
+            "area": "12.5 km^2",
+            "height": {"value": -20, "unit": "m"},
+            "volume": "expr: area * -height",
+            "volume_pm": 1e7,
+            "rate": "expr: to(volume * \"917 kg/m^3\" / \"2 yr\", \"Gt/yr\")",
+        });
+
+        let parsed_data = evaluate_all_expressions(&data).unwrap();
+        let new_lines = fill_data(&lines, &parsed_data).unwrap();
+
+        assert_eq!(
+            new_lines[0],
+            "A volume of \\SI{0.25 +- 0.01}{km^{3}} (\\SI{250000000 +- 10000000}{m^{3}})."
+        );
+        assert_eq!(
+            new_lines[1],
+            "The mass loss was \\SI{0.1}{Gt/yr} over \\SI{12.5}{km^{2}}."
+        );
+        assert_eq!(new_lines[2], "\\num{3}, 12.5 km^2 and 250000000 m^3");
+
+        // The error is converted to the unit of the value.
+        let data = serde_json::json!({"depth": "1.5 km", "depth_pm": "200 m", "label": "3 h"});
+        assert_eq!(
+            fill_data(&["{{si depth decimals=1}}".to_owned()], &data).unwrap(),
+            vec!["\\SI{1.5 +- 0.2}{km}"]
+        );
+        // Other helpers do not read strings as quantities.
+        let lines = vec!["{{round label}}".to_owned()];
+        assert_eq!(fill_data(&lines, &data).unwrap(), lines);
+
+        // Adding values with different dimensions is an error.
+        let data = serde_json::json!({"area": "12.5 km^2", "sum": "expr: area + \"1 s\""});
+        let error = evaluate_all_expressions(&data).unwrap_err();
+        assert!(error.contains("dimensions differ"), "{}", error);
+    }
+
     #[test]
     fn test_expressions() {
         let lines: Vec<String> = vec![
@@ -988,30 +2328,51 @@ mod tests {
             }
         });
 
-        assert_eq!(run_eval(&"100 * 3", &data), Ok(serde_json::json!(300)));
         assert_eq!(
-            run_eval("round(1.23, 1)", &data),
+            evaluate_expression("100 * 3", &data, &[]),
+            Ok(serde_json::json!(300))
+        );
+        assert_eq!(
+            evaluate_expression("round(1.23, 1)", &data, &[]),
             Ok(serde_json::json!(1.2))
         );
-        assert_eq!(run_eval("round(1.23)", &data), Ok(serde_json::json!(1)));
+        assert_eq!(
+            evaluate_expression("round(1.23)", &data, &[]),
+            Ok(serde_json::json!(1))
+        );
         // Check that the second argument has an integer-check
-        match run_eval("round(1.23, 1.2)", &data) {
+        match evaluate_expression("round(1.23, 1.2)", &data, &[]) {
             Ok(v) => panic!("This should have failed!: {:?}", v),
             Err(e) => assert!(e.contains("must be an integer")),
         }
 
-        assert_eq!(run_eval("E(3)", &data), Ok(serde_json::json!(1000)));
-        assert_eq!(run_eval("E(0-1)", &data), Ok(serde_json::json!(0.1)));
-        assert_eq!(run_eval("3 * E(0-2)", &data), Ok(serde_json::json!(0.03)));
-        assert_eq!(run_eval("pow(10, 3)", &data), Ok(serde_json::json!(1000)));
-        assert_eq!(run_eval("pow(10, 0-1)", &data), Ok(serde_json::json!(0.1)));
         assert_eq!(
-            run_eval("pow(92809.984, 0)", &data),
+            evaluate_expression("E(3)", &data, &[]),
+            Ok(serde_json::json!(1000))
+        );
+        assert_eq!(
+            evaluate_expression("E(0-1)", &data, &[]),
+            Ok(serde_json::json!(0.1))
+        );
+        assert_eq!(
+            evaluate_expression("3 * E(0-2)", &data, &[]),
+            Ok(serde_json::json!(0.03))
+        );
+        assert_eq!(
+            evaluate_expression("pow(10, 3)", &data, &[]),
+            Ok(serde_json::json!(1000))
+        );
+        assert_eq!(
+            evaluate_expression("pow(10, 0-1)", &data, &[]),
+            Ok(serde_json::json!(0.1))
+        );
+        assert_eq!(
+            evaluate_expression("pow(92809.984, 0)", &data, &[]),
             Ok(serde_json::json!(1))
         );
 
         // This will fail because of a misspelled key.
-        match run_eval(&"largee + small", &data) {
+        match evaluate_expression("largee + small", &data, &[]) {
             Ok(v) => panic!("This should have failed!: {:?}", v),
             Err(e) => assert!(e.contains("Perhaps a key is misspelled?")),
         };
@@ -1040,9 +2401,101 @@ mod tests {
 
         assert!(data.is_object());
 
-        match evaluate_expression("ex1 + ex2", &data, 0) {
+        match evaluate_expression("ex1 + ex2", &data, &[]) {
             Ok(v) => panic!("This should have failed!: {:?}", v),
             Err(s) => assert!(s.contains("recursion"), "{}", s),
         };
     }
+
+    #[test]
+    fn test_include_expressions() {
+        // Array elements can be indexed and sliced, and can contain expressions themselves.
+        let array_data = serde_json::json!({
+            "samples": [{"dh": 1}, {"dh": "expr: 2 * samples[0].dh"}, {"dh": 4}],
+            "last": "expr: samples[-1].dh",
+            "rest_mean": "expr: mean(samples[1:].dh)",
+        });
+        let parsed_data = evaluate_all_expressions(&array_data).unwrap();
+
+        assert_eq!(parsed_data["samples"][1]["dh"], serde_json::json!(2));
+        assert_eq!(parsed_data["last"], serde_json::json!(4));
+        assert_eq!(parsed_data["rest_mean"], serde_json::json!(3));
+
+        // Expressions can use values from included files.
+        let data = crate::io::read_data_with_sources(&PathBuf::from("tests/data/case5/data.toml"))
+            .unwrap();
+        let parsed_data = evaluate_all_expressions(&data).unwrap();
+
+        assert_eq!(parsed_data["dh_last"], serde_json::json!(3.4));
+        assert_eq!(parsed_data["dh_mean"], serde_json::json!(3.13));
+        assert_approx_eq!(parsed_data["corrected"].as_f64().unwrap(), 2.9);
+    }
+
+    #[test]
+    fn test_expression_syntax() {
+        let data = serde_json::json!({"dh_mean": 4, "samples": [1, 2, 3]});
+
+        // Negative numbers no longer have to be written as "0-1".
+        assert_eq!(
+            evaluate_expression("E(-1) * -dh_mean", &data, &[]),
+            Ok(serde_json::json!(-0.4))
+        );
+        let error =
+            evaluate_all_expressions(&serde_json::json!({"a": "expr: 1 +* 2"})).unwrap_err();
+        assert!(
+            error.contains("'a'") & error.contains("column 4"),
+            "{}",
+            error
+        );
+
+        // The builtins of the previous expression engine still work.
+        assert_eq!(
+            evaluate_expression("len(samples) + len(array(1, 2))", &data, &[]),
+            Ok(serde_json::json!(5))
+        );
+        assert_eq!(
+            evaluate_expression("is_empty(samples)", &data, &[]),
+            Ok(serde_json::json!(false))
+        );
+    }
+
+    #[test]
+    fn test_math_functions() {
+        let data = serde_json::json!({});
+
+        assert_eq!(
+            evaluate_expression("round(sqrt(2) * sind(45), 3)", &data, &[]),
+            Ok(serde_json::json!(1))
+        );
+        assert_eq!(
+            evaluate_expression("log(e, e)", &data, &[]),
+            Ok(serde_json::json!(1))
+        );
+        assert_eq!(
+            evaluate_expression("round(pi, 1)", &data, &[]),
+            Ok(serde_json::json!(3.1))
+        );
+        match evaluate_expression("sqrt(0-4)", &data, &[]) {
+            Ok(v) => panic!("This should have failed!: {:?}", v),
+            Err(e) => assert!(e.contains("Error in expression: 'sqrt(0-4)'"), "{}", e),
+        };
+    }
+
+    #[test]
+    fn test_statistics() {
+        // Statistics functions work on (nested) arrays reached by dotted paths.
+        let data = serde_json::json!({
+            "samples": {"dh": [[1, 2], [3, 4, 10]], "area": [1, 1, 1, 1, 6]},
+            "dh_mean": "expr: mean(samples.dh)",
+            "dh_p90": "expr: round(percentile(samples.dh, 90), 1)",
+            "dh_weighted": "expr: weighted_mean(samples.dh, samples.area) - dh_mean",
+        });
+
+        let parsed_data = evaluate_all_expressions(&data).unwrap();
+
+        assert_eq!(parsed_data["dh_mean"], serde_json::json!(4));
+        assert_eq!(parsed_data["dh_p90"], serde_json::json!(7.6));
+        assert_eq!(parsed_data["dh_weighted"], serde_json::json!(3));
+        assert!(evaluate_expression("mean(samples.nothing)", &data, &[]).is_err());
+    }
 }
@@ -0,0 +1,450 @@
+//! Physical units and quantities, e.g. "12.5 km^2" or `{value = 12.5, unit = "km^2"}`.
+use crate::templates::float_to_json;
+use serde_json::Value as Json;
+
+/// The SI base dimensions, in the order that they are stored in a `Unit`.
+const BASE_UNITS: [&str; 7] = ["m", "kg", "s", "K", "A", "mol", "cd"];
+
+/// Units, their factor to SI and their dimension (exponents of the base units).
+const UNITS: [(&str, f64, [i32; 7]); 21] = [
+    ("m", 1.0, [1, 0, 0, 0, 0, 0, 0]),
+    ("g", 1e-3, [0, 1, 0, 0, 0, 0, 0]),
+    ("t", 1e3, [0, 1, 0, 0, 0, 0, 0]),
+    ("s", 1.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("min", 60.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("h", 3600.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("d", 86400.0, [0, 0, 1, 0, 0, 0, 0]),
+    // Julian years.
+    ("yr", 31_557_600.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("a", 31_557_600.0, [0, 0, 1, 0, 0, 0, 0]),
+    ("K", 1.0, [0, 0, 0, 1, 0, 0, 0]),
+    ("A", 1.0, [0, 0, 0, 0, 1, 0, 0]),
+    ("mol", 1.0, [0, 0, 0, 0, 0, 1, 0]),
+    ("cd", 1.0, [0, 0, 0, 0, 0, 0, 1]),
+    ("L", 1e-3, [3, 0, 0, 0, 0, 0, 0]),
+    ("ha", 1e4, [2, 0, 0, 0, 0, 0, 0]),
+    ("Hz", 1.0, [0, 0, -1, 0, 0, 0, 0]),
+    ("N", 1.0, [1, 1, -2, 0, 0, 0, 0]),
+    ("Pa", 1.0, [-1, 1, -2, 0, 0, 0, 0]),
+    ("bar", 1e5, [-1, 1, -2, 0, 0, 0, 0]),
+    ("J", 1.0, [2, 1, -2, 0, 0, 0, 0]),
+    ("W", 1.0, [2, 1, -3, 0, 0, 0, 0]),
+];
+
+/// Prefixes and their factors.
+const PREFIXES: [(&str, f64); 11] = [
+    ("T", 1e12),
+    ("G", 1e9),
+    ("M", 1e6),
+    ("k", 1e3),
+    ("h", 1e2),
+    ("d", 1e-1),
+    ("c", 1e-2),
+    ("m", 1e-3),
+    ("u", 1e-6),
+    ("µ", 1e-6),
+    ("n", 1e-9),
+];
+
+/// A unit, as its factor to SI and its dimension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unit {
+    pub factor: f64,
+    pub dimension: [i32; 7],
+}
+
+/// A value with a unit, e.g. 12.5 "km^2".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+    pub value: f64,
+    pub unit: String,
+}
+
+/// Find a unit symbol, with or without a prefix, e.g. "km" or "Gt".
+fn parse_symbol(symbol: &str) -> Option<Unit> {
+    let find = |s: &str| UNITS.iter().find(|(name, _, _)| *name == s);
+
+    // Whole units go first, so that e.g. "min" is not read as milli-inches.
+    if let Some((_, factor, dimension)) = find(symbol) {
+        return Some(Unit {
+            factor: *factor,
+            dimension: *dimension,
+        });
+    }
+    for (prefix, prefix_factor) in PREFIXES.iter() {
+        if let Some((_, factor, dimension)) = symbol.strip_prefix(prefix).and_then(find) {
+            return Some(Unit {
+                factor: prefix_factor * factor,
+                dimension: *dimension,
+            });
+        }
+    }
+    None
+}
+
+/// Parse a unit string, e.g. "km^2", "Gt/yr", "kg m^-3" or "m s-1".
+///
+/// Terms are separated by spaces, "*" or "." and a "/" divides by the next term.
+/// Exponents can be written with or without a "^", or as "²" and "³".
+///
+/// # Examples
+/// ```
+/// let unit = parse_unit("km^2").unwrap();
+///
+/// assert_eq!(unit.factor, 1e6);
+/// ```
+pub fn parse_unit(unit_str: &str) -> Result<Unit, String> {
+    let mut unit = Unit {
+        factor: 1.0,
+        dimension: [0; 7],
+    };
+    let spaced = unit_str
+        .replace('/', " / ")
+        .replace(['*', '.'], " ")
+        .replace('²', "^2")
+        .replace('³', "^3");
+
+    let mut divide = false;
+    let mut n_terms = 0;
+    for term in spaced.split_whitespace() {
+        if term == "/" {
+            divide = true;
+            continue;
+        }
+        let split = term
+            .find(|c: char| c == '^' || c == '-' || c.is_ascii_digit())
+            .unwrap_or(term.len());
+        let (symbol, exponent) = term.split_at(split);
+
+        let exponent: i32 = match exponent.trim_start_matches('^') {
+            "" => 1,
+            e => e
+                .parse()
+                .map_err(|_| format!("Invalid exponent in unit '{}'", unit_str))?,
+        };
+        let exponent = if divide { -exponent } else { exponent };
+        divide = false;
+
+        let term_unit = parse_symbol(symbol)
+            .ok_or_else(|| format!("Unknown unit '{}' in '{}'", symbol, unit_str))?;
+        unit.factor *= term_unit.factor.powi(exponent);
+        for (d, term_d) in unit.dimension.iter_mut().zip(term_unit.dimension.iter()) {
+            *d += term_d * exponent;
+        }
+        n_terms += 1;
+    }
+    if n_terms == 0 || divide {
+        return Err(format!("Invalid unit '{}'", unit_str));
+    }
+
+    Ok(unit)
+}
+
+/// Write a dimension in SI base units, e.g. [1, 0, -1, ...] => "m s^-1".
+fn si_unit_string(dimension: &[i32; 7]) -> String {
+    BASE_UNITS
+        .iter()
+        .zip(dimension.iter())
+        .filter(|(_, exponent)| **exponent != 0)
+        .map(|(base, exponent)| match exponent {
+            1 => base.to_string(),
+            e => format!("{}^{}", base, e),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl Quantity {
+    /// Read a quantity from a string ("12.5 km^2") or an object (`{"value": 12.5, "unit": "km^2"}`).
+    ///
+    /// Only values that are used in expressions, by the "si" helper or by a schema with a unit
+    /// are read as quantities. Elsewhere, a string like "3 h" is just text.
+    ///
+    /// Returns None if the value is not a quantity with a valid unit.
+    pub fn from_json(value: &Json) -> Option<Quantity> {
+        let (number, unit) = match value {
+            Json::String(s) => {
+                let s = s.trim();
+                let split = s.find(char::is_whitespace)?;
+                (
+                    s[..split].parse::<f64>().ok()?,
+                    s[split..].trim().to_owned(),
+                )
+            }
+            Json::Object(obj) if obj.len() == 2 => (
+                obj.get("value")?.as_f64()?,
+                obj.get("unit")?.as_str()?.to_owned(),
+            ),
+            _ => return None,
+        };
+        parse_unit(&unit).ok()?;
+
+        Some(Quantity {
+            value: number,
+            unit,
+        })
+    }
+
+    /// Write the quantity as `{"value": 12.5, "unit": "km^2"}`.
+    pub fn to_json(&self) -> Json {
+        serde_json::json!({"value": self.value, "unit": self.unit})
+    }
+
+    /// Write the quantity like it is written in the data files, e.g. "12.5 km^2".
+    pub fn to_text(&self) -> String {
+        format!("{} {}", float_to_json(self.value), self.unit)
+    }
+
+    /// Convert the quantity to SI base units.
+    fn to_si(&self) -> (f64, [i32; 7]) {
+        let unit = parse_unit(&self.unit).unwrap();
+        (self.value * unit.factor, unit.dimension)
+    }
+
+    /// Create a quantity from SI base units, or a plain number if it has no dimension.
+    fn from_si(value: f64, dimension: [i32; 7]) -> Json {
+        match dimension == [0; 7] {
+            true => Json::from(value),
+            false => Quantity {
+                value,
+                unit: si_unit_string(&dimension),
+            }
+            .to_json(),
+        }
+    }
+
+    /// Convert the quantity to another unit with the same dimension.
+    ///
+    /// # Examples
+    /// ```
+    /// let volume = Quantity { value: 2e9, unit: "m^3".into() };
+    ///
+    /// assert_eq!(volume.convert("km^3").unwrap().value, 2.0);
+    /// ```
+    pub fn convert(&self, unit_str: &str) -> Result<Quantity, String> {
+        let (value, dimension) = self.to_si();
+        let unit = parse_unit(unit_str)?;
+
+        if unit.dimension != dimension {
+            return Err(format!(
+                "Cannot convert {} to {}: The dimensions differ ({} and {}).",
+                self.unit,
+                unit_str,
+                si_unit_string(&dimension),
+                si_unit_string(&unit.dimension)
+            ));
+        }
+        Ok(Quantity {
+            value: value / unit.factor,
+            unit: unit_str.to_owned(),
+        })
+    }
+}
+
+/// Apply a binary operator if one of the values is a quantity.
+///
+/// Sums and comparisons are made in the unit of the left value, and fail if the dimensions
+/// differ. Products, ratios and powers of two quantities are given in SI base units.
+///
+/// # Returns
+/// None if neither value is a quantity, or else the result.
+pub fn apply_binary(op: &str, left: &Json, right: &Json) -> Option<Result<Json, String>> {
+    let (left_q, right_q) = (Quantity::from_json(left), Quantity::from_json(right));
+    if left_q.is_none() && right_q.is_none() {
+        return None;
+    }
+    let number = |value: &Json| {
+        value
+            .as_f64()
+            .ok_or_else(|| format!("'{}' expected a number or a quantity. Got: {}", op, value))
+    };
+
+    Some(match (op, left_q, right_q) {
+        ("*", Some(q), None) | ("*", None, Some(q)) => {
+            let factor = if left.is_number() { left } else { right };
+            number(factor).map(|f| {
+                Quantity {
+                    value: q.value * f,
+                    unit: q.unit,
+                }
+                .to_json()
+            })
+        }
+        ("/", Some(q), None) => number(right).map(|f| {
+            Quantity {
+                value: q.value / f,
+                unit: q.unit,
+            }
+            .to_json()
+        }),
+        ("/", None, Some(q)) => number(left).map(|f| {
+            let (value, dimension) = q.to_si();
+            Quantity::from_si(f / value, dimension.map(|d| -d))
+        }),
+        ("*", Some(a), Some(b)) | ("/", Some(a), Some(b)) => {
+            let ((a_value, a_dim), (b_value, b_dim)) = (a.to_si(), b.to_si());
+            let mut dimension = a_dim;
+            for (d, b_d) in dimension.iter_mut().zip(b_dim.iter()) {
+                *d += if op == "*" { *b_d } else { -b_d };
+            }
+            let value = if op == "*" {
+                a_value * b_value
+            } else {
+                a_value / b_value
+            };
+            Ok(Quantity::from_si(value, dimension))
+        }
+        ("^", Some(q), None) => match right.as_i64() {
+            Some(exponent) => {
+                let (value, dimension) = q.to_si();
+                Ok(Quantity::from_si(
+                    value.powi(exponent as i32),
+                    dimension.map(|d| d * exponent as i32),
+                ))
+            }
+            None => Err(format!(
+                "Quantities can only be raised to integer powers. Got: {}",
+                right
+            )),
+        },
+        ("+", Some(a), Some(b))
+        | ("-", Some(a), Some(b))
+        | ("<", Some(a), Some(b))
+        | ("<=", Some(a), Some(b))
+        | (">", Some(a), Some(b))
+        | (">=", Some(a), Some(b))
+        | ("==", Some(a), Some(b))
+        | ("!=", Some(a), Some(b)) => b.convert(&a.unit).map(|b| match op {
+            "+" => Quantity {
+                value: a.value + b.value,
+                unit: a.unit,
+            }
+            .to_json(),
+            "-" => Quantity {
+                value: a.value - b.value,
+                unit: a.unit,
+            }
+            .to_json(),
+            "<" => Json::Bool(a.value < b.value),
+            "<=" => Json::Bool(a.value <= b.value),
+            ">" => Json::Bool(a.value > b.value),
+            ">=" => Json::Bool(a.value >= b.value),
+            "==" => Json::Bool(a.value == b.value),
+            _ => Json::Bool(a.value != b.value),
+        }),
+        (_, Some(q), None) | (_, None, Some(q)) if left.is_number() || right.is_number() => {
+            Err(format!(
+                "'{}' cannot combine a number without a unit and a quantity in {}.",
+                op, q.unit
+            ))
+        }
+        _ => Err(format!("'{}' cannot be used on {} and {}", op, left, right)),
+    })
+}
+
+/// Write a unit in the siunitx syntax, e.g. "kg m^-3" => "kg.m^{-3}".
+pub fn to_siunitx(unit_str: &str) -> String {
+    unit_str
+        .replace('/', " / ")
+        .replace(['*', '.'], " ")
+        .split_whitespace()
+        .map(|term| match term.split_once('^') {
+            Some((symbol, exponent)) => format!("{}^{{{}}}", symbol, exponent),
+            None => term.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+        .replace("./.", "/")
+}
+
+/// Write the quantity tables (`{"value": 12.5, "unit": "km^2"}`) in data as text ("12.5 km^2").
+///
+/// Templates write tables as "[object]", so this is done before the data are rendered.
+///
+/// # Arguments
+/// * `data`: The evaluated data.
+///
+/// # Returns
+/// The data, with each quantity table replaced by a string.
+pub fn quantities_as_text(data: &Json) -> Json {
+    match data {
+        Json::Object(obj) => match Quantity::from_json(data) {
+            Some(q) => Json::String(q.to_text()),
+            None => Json::Object(
+                obj.iter()
+                    .map(|(key, value)| (key.to_owned(), quantities_as_text(value)))
+                    .collect(),
+            ),
+        },
+        Json::Array(arr) => Json::Array(arr.iter().map(quantities_as_text).collect()),
+        v => v.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(parse_unit("km^2").unwrap().factor, 1e6);
+        assert_eq!(parse_unit("km²").unwrap(), parse_unit("km2").unwrap());
+        assert_eq!(
+            parse_unit("Gt/yr").unwrap().dimension,
+            [0, 1, -1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            parse_unit("kg m^-3").unwrap(),
+            parse_unit("kg/m^3").unwrap()
+        );
+        assert_eq!(parse_unit("min").unwrap().factor, 60.0);
+        parse_unit("sites").expect_err("Not a unit");
+        parse_unit("m/").expect_err("Nothing to divide by");
+
+        let area = serde_json::json!("12.5 km^2");
+        let height = serde_json::json!({"value": 20, "unit": "m"});
+        assert_eq!(Quantity::from_json(&serde_json::json!("3 sites")), None);
+
+        let volume = apply_binary("*", &area, &height).unwrap().unwrap();
+        assert_eq!(volume["unit"], "m^3");
+        assert_approx_eq!(volume["value"].as_f64().unwrap(), 2.5e8);
+
+        let km3 = Quantity::from_json(&volume)
+            .unwrap()
+            .convert("km^3")
+            .unwrap();
+        assert_approx_eq!(km3.value, 0.25);
+
+        let sum = apply_binary("+", &height, &serde_json::json!("1 km"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sum, serde_json::json!({"value": 1020.0, "unit": "m"}));
+        assert_eq!(
+            apply_binary("/", &height, &serde_json::json!("10 m")).unwrap(),
+            Ok(serde_json::json!(2.0))
+        );
+
+        let error = apply_binary("+", &height, &serde_json::json!("1 s"))
+            .unwrap()
+            .unwrap_err();
+        assert!(error.contains("dimensions differ"), "{}", error);
+        apply_binary("+", &height, &serde_json::json!(1))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            apply_binary("+", &serde_json::json!(1), &serde_json::json!(2)),
+            None
+        );
+
+        assert_eq!(to_siunitx("kg m^-3"), "kg.m^{-3}");
+        assert_eq!(to_siunitx("Gt/yr"), "Gt/yr");
+
+        let data = serde_json::json!({"volume": volume, "samples": [height], "label": "3 h"});
+        assert_eq!(
+            quantities_as_text(&data),
+            serde_json::json!({"volume": "250000000 m^3", "samples": ["20 m"], "label": "3 h"})
+        );
+    }
+}
@@ -50,6 +50,133 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_provenance() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("provenance")
+            .arg("--format=csv")
+            .arg("--data=tests/data/case3/data.json")
+            .arg("tests/data/case3/main.tex");
+
+        // Check that the source line, the expression and the rendered value are reported.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "tests/data/case3/main.tex,9,{{pm 1 results.change}}",
+            ))
+            .stdout(predicate::str::contains(
+                "results.change_vol = results.change * results.area",
+            ))
+            .stdout(predicate::str::contains("12\\,805.9$\\pm$4\\,956.7"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_data() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("diff-data")
+            .arg("tests/data/case3/data.json")
+            .arg("tests/data/case3/data_revised.json")
+            .arg("tests/data/case3/main.tex");
+
+        // Only the lines that depend on results.change should be shown.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("tests/data/case3/main.tex:9"))
+            .stdout(predicate::str::contains(
+                "# results.change: 1.2805909 -> 1.4805909",
+            ))
+            .stdout(predicate::str::contains(
+                "+ They showed a mean change of 1.5$\\pm$0.5 m.",
+            ))
+            .stdout(predicate::str::contains("ArcticDEMs").not());
+
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("diff-data")
+            .arg("--format=tex")
+            .arg("tests/data/case3/data.json")
+            .arg("tests/data/case3/data_revised.json")
+            .arg("tests/data/case3/main.tex");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "mean change of \\DIFdel{1.3$\\pm$0.5}\\DIFadd{1.5$\\pm$0.5} m.",
+            ))
+            .stdout(predicate::str::contains("\\providecommand{\\DIFadd}"));
+
+        // Overrides are applied to both data files, so the change disappears.
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("diff-data")
+            .arg("--set=results.change=2")
+            .arg("tests/data/case3/data.json")
+            .arg("tests/data/case3/data_revised.json")
+            .arg("tests/data/case3/main.tex");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("tests/data/case3/main.tex:9").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revdiff() -> Result<(), Box<dyn std::error::Error>> {
+        let temp_dir = tempfile::tempdir()?;
+
+        // Run a git command in the temporary directory.
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(temp_dir.path())
+                .args([
+                    "-c",
+                    "user.name=manus",
+                    "-c",
+                    "user.email=manus@example.com",
+                ])
+                .args(args)
+                .output()
+                .expect("Could not run git")
+        };
+        // Write a file in the temporary directory and commit it.
+        let commit = |filename: &str, contents: &str| {
+            std::fs::write(temp_dir.path().join(filename), contents).unwrap();
+            git(&["add", filename]);
+            git(&["commit", "-m", filename]);
+        };
+
+        git(&["init"]);
+        commit(
+            "main.tex",
+            "\\documentclass{article}\n\\begin{document}\n\\input{results}\n\\end{document}",
+        );
+        commit("results.tex", "The glacier retreated {{change}} m.");
+        commit("data.toml", "change = 120");
+        commit("data.toml", "change = 135");
+
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("revdiff")
+            .arg("--tex")
+            .arg("--data")
+            .arg(temp_dir.path().join("data.toml"))
+            .arg("HEAD~1")
+            .arg("HEAD")
+            .arg(temp_dir.path().join("main.tex"));
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "The glacier retreated \\DIFdel{120}\\DIFadd{135} m.",
+        ));
+
+        Ok(())
+    }
+
     #[test]
     fn test_verbosity() -> Result<(), Box<dyn std::error::Error>> {
         let mut cmd = Command::cargo_bin("manus")?;
@@ -156,4 +283,302 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_included_data() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("convert")
+            .arg("--data=tests/data/case5/data.toml")
+            .arg("--format=tex")
+            .arg("tests/data/case5/main.tex");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "The Revised measurements by AB ended at 3.4, with a mean of 3.13.",
+            ))
+            .stdout(predicate::str::contains("corrected for the offset is 2.9."));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_overrides() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        // The command line has the last say over the environment.
+        cmd.arg("convert")
+            .arg("--data=tests/data/case5/data.toml")
+            .arg("--format=tex")
+            .arg("--set=dh=[3.1, 2.9, 3.6]")
+            .arg("--set")
+            .arg("label=Draft")
+            .env("MANUS_DATA_label", "Environment")
+            .env("MANUS_DATA_results__operator", "CD")
+            .arg("tests/data/case5/main.tex");
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "The Draft measurements by CD ended at 3.6, with a mean of 3.2.",
+        ));
+
+        let mut cmd2 = Command::cargo_bin("manus")?;
+        cmd2.arg("eval")
+            .arg("--set=dh.x=1")
+            .arg("tests/data/case5/data.toml");
+
+        cmd2.assert().failure();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("check")
+            .arg("--schema=tests/data/case5/schema.toml")
+            .arg("tests/data/case5/data.toml");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("The data matches the schema."));
+
+        // Every violation is listed with its key.
+        let mut cmd2 = Command::cargo_bin("manus")?;
+        cmd2.arg("convert")
+            .arg("--data=tests/data/case5/data.toml")
+            .arg("--schema=tests/data/case5/schema.toml")
+            .arg("--set=label=Other")
+            .arg("--set=calibration.offset=1.5")
+            .arg("tests/data/case5/main.tex");
+
+        cmd2.assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "calibration.offset: 1.5 is larger",
+            ))
+            .stderr(predicate::str::contains("label: \"Other\" is not one of"));
+
+        // A schema without data is not silently skipped.
+        let mut cmd3 = Command::cargo_bin("manus")?;
+        cmd3.arg("convert")
+            .arg("--schema=tests/data/case5/schema.toml")
+            .arg("tests/data/case5/main.tex");
+
+        cmd3.assert()
+            .failure()
+            .stderr(predicate::str::contains("no data to validate"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_figures() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("convert")
+            .arg("--data=tests/data/case6/data.toml")
+            .arg("tests/data/case6/main.tex");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "\\includegraphics[width=\\linewidth]{tests/data/case6/map.png}\n\\caption{The 4 measurement sites.}\n\\label{fig:sites}",
+            ));
+
+        let mut cmd2 = Command::cargo_bin("manus")?;
+        cmd2.arg("merge")
+            .arg("--figures")
+            .arg("--data=tests/data/case6/data.toml")
+            .arg("tests/data/case6/main.tex");
+
+        cmd2.assert().success().stdout(predicate::eq(
+            "tests/data/case6/map.png\ntests/data/case6/logo.png\n",
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refs() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("convert")
+            .arg("--data=tests/data/case6/data.toml")
+            .arg("tests/data/case6/main.tex");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "The sites are shown in Figure~\\ref{fig:sites}.",
+            ))
+            .stderr(predicate::str::contains("Undefined references").not());
+
+        // Broken references are listed before tectonic runs.
+        let mut cmd2 = Command::cargo_bin("manus")?
+            .arg("build")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        {
+            let stdin = cmd2.stdin.as_mut().expect("failed to get stdin");
+            stdin.write_all(b"See Table~\\ref{tab:results}.\n")?;
+        }
+        let output = cmd2.wait_with_output()?;
+        assert!(String::from_utf8(output.stderr)?
+            .contains("WARNING: Undefined references:\n  -:1: \\ref{tab:results}"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_acronyms() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("convert")
+            .arg("--data=tests/data/case6/data.toml")
+            .arg("tests/data/case6/main.tex");
+
+        // The first use is in main.tex and the second in the merged methods.tex.
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains(
+                "\\begin{description}\n\\item[DEM] Digital Elevation Model\n\\end{description}",
+            ))
+            .stdout(predicate::str::contains(
+                "A Digital Elevation Model (DEM) was made of each site.\nThe DEM has a",
+            ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), Box<dyn std::error::Error>> {
+        // The limits are read from the manus.toml next to main.tex.
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("stats")
+            .arg("--set=adjective=short")
+            .arg("tests/data/case7/main.tex");
+
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("Introduction       7"))
+            .stderr(predicate::str::contains("abstract: 6 words (limit: 5)"));
+
+        let mut cmd2 = Command::cargo_bin("manus")?;
+        cmd2.arg("stats")
+            .arg("--set=adjective=short")
+            .arg("--config=tests/data/case7/journal.toml")
+            .arg("tests/data/case7/main.tex");
+
+        cmd2.assert()
+            .success()
+            .stdout(predicate::str::contains("Abstract           6"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("merge")
+            .arg("--strip-comments")
+            .arg("--strip-todos")
+            .arg("tests/data/case8/main.tex");
+
+        cmd.assert().success().stdout(predicate::str::contains(
+            "\\begin{document}\n\nThe glaciers lost 5\\% of their volume.\nThe end.\n\n\\end{document}",
+        ));
+
+        // A submission may not have todos left.
+        let mut cmd2 = Command::cargo_bin("manus")?;
+        cmd2.arg("build")
+            .arg("--profile=submission")
+            .arg("tests/data/case8/main.tex");
+
+        cmd2.assert().failure().stderr(predicate::str::contains(
+            "The 'submission' profile does not allow todos, but some remain:\n  tests/data/case8/main.tex:8\n  tests/data/case8/main.tex:9\n",
+        ));
+
+        let mut cmd3 = Command::cargo_bin("manus")?;
+        cmd3.arg("convert")
+            .arg("--profile=submission")
+            .arg("--strip-todos")
+            .arg("tests/data/case8/main.tex");
+
+        cmd3.assert()
+            .success()
+            .stdout(predicate::str::contains("Or was it").not())
+            .stdout(predicate::str::contains("\\todo{").not());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("manus")?;
+
+        cmd.arg("eval")
+            .arg("--key=dh_mean")
+            .arg("--explain")
+            .arg("tests/data/case5/data.toml");
+
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("dh_mean = round(mean(dh), 2)"))
+            .stdout(predicate::str::contains("=> 3.13"));
+
+        let mut cmd2 = Command::cargo_bin("manus")?;
+        cmd2.arg("eval")
+            .arg("--key=missing")
+            .arg("tests/data/case5/data.toml");
+
+        cmd2.assert().failure();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_from_stdin() -> Result<(), Box<dyn std::error::Error>> {
+        // TOML from a pipe, with the format guessed from the content.
+        let mut cmd = Command::cargo_bin("manus")?
+            .arg("convert")
+            .arg("--format=tex")
+            .arg("--data=-")
+            .arg("tests/data/case4/main.tex")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        {
+            let stdin = cmd.stdin.as_mut().expect("failed to get stdin");
+            stdin.write_all(std::fs::read_to_string("tests/data/case4/data.toml")?.as_bytes())?;
+        }
+        let output = String::from_utf8(cmd.wait_with_output()?.stdout)?;
+        assert!(output.contains("The first numbers from one are: 1 and 2."));
+
+        // YAML with an explicit format.
+        let mut cmd2 = Command::cargo_bin("manus")?
+            .arg("eval")
+            .arg("--data-format=yaml")
+            .arg("--key=data.number2")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        {
+            let stdin = cmd2.stdin.as_mut().expect("failed to get stdin");
+            stdin.write_all(b"data:\n  number2: 'expr: 1 + 1'\n")?;
+        }
+        let output = String::from_utf8(cmd2.wait_with_output()?.stdout)?;
+        assert_eq!(output.trim(), "2");
+
+        Ok(())
+    }
 }